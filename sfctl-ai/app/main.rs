@@ -1,40 +1,337 @@
-use sfctl_ai::app_loop;
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use sfctl_ai::{
+    ChatConfig,
+    ack::ApprovalMode,
+    ai::LoopConfig,
+    app_loop,
+    audit::{AuditCommand, AuditConfig, Outcome},
+    backend::BackendConfig,
+    context::ContextConfig,
+    create_chat,
+    policy::PolicyConfig,
+    provider::AiConfig,
+    retry::RetryConfig,
+    session::{SessionCommand, SessionConfig, SessionStore},
+};
 use tokio::signal;
 use tracing_appender::rolling;
 use tracing_subscriber::fmt;
 
+#[derive(Parser)]
+#[command(version, about = "AI assistant for Service Fabric clusters")]
+struct Args {
+    #[command(flatten)]
+    ai: AiConfig,
+
+    #[command(flatten)]
+    backend: BackendConfig,
+
+    #[command(flatten)]
+    policy: PolicyConfig,
+
+    #[command(flatten)]
+    audit: AuditConfig,
+
+    #[command(flatten)]
+    session: SessionConfig,
+
+    #[command(flatten)]
+    context: ContextConfig,
+
+    #[command(flatten)]
+    limits: LoopConfig,
+
+    #[command(flatten)]
+    retry: RetryConfig,
+
+    /// Cluster connection endpoint to connect to on start, e.g. mycluster:19000
+    #[arg(long, env = "SFCTL_AI_ENDPOINT", global = true)]
+    endpoint: Option<String>,
+
+    /// How commands that need approval are handled. Defaults to prompt, or read-only for ask
+    #[arg(long, env = "SFCTL_AI_APPROVAL", value_enum, global = true)]
+    approval: Option<ApprovalMode>,
+
+    /// Output format of the ask, run and config commands
+    #[arg(
+        long,
+        env = "SFCTL_AI_OUTPUT",
+        value_enum,
+        default_value_t,
+        global = true
+    )]
+    output: OutputFormat,
+
+    /// Directory of the application logs
+    #[arg(long, env = "SFCTL_AI_LOG_DIR", default_value = "logs", global = true)]
+    log_dir: PathBuf,
+
+    /// Application log level: error, warn, info, debug or trace
+    #[arg(long, env = "SFCTL_AI_LOG_LEVEL", default_value_t = tracing::Level::INFO, global = true)]
+    log_level: tracing::Level,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Chat with the assistant (default)
+    Chat,
+    /// Answer a single question and exit. The answer goes to stdout, notices to stderr.
+    /// Exits with 0 when answered, 1 on errors, 2 when some commands were not run or the
+    /// step limit was reached so the answer may be incomplete, and 130 when interrupted
+    Ask {
+        /// The question, e.g. "are all nodes up?"
+        question: String,
+    },
+    /// Run a single PowerShell command with the policy and audit log, without the model
+    Run {
+        /// The command, e.g. Get-ServiceFabricClusterHealth
+        command: String,
+    },
+    /// Inspect the audit log of executed commands
+    #[command(subcommand)]
+    Audit(AuditCommand),
+    /// List and show saved conversations, resume them with --resume
+    #[command(subcommand)]
+    Session(SessionCommand),
+    /// Show the effective configuration
+    Config,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
 fn main() {
-    // Set up file appender (logs/ directory, file per day)
-    let file_appender = rolling::daily("logs", "sfctl-ai.log");
+    let args = Args::parse();
+    match &args.command {
+        Some(Command::Audit(cmd)) => {
+            if let Err(e) = cmd.run(&args.audit.audit_log) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Session(cmd)) => {
+            let store = SessionStore::new(&args.session.session_dir);
+            if let Err(e) = cmd.run(&store, args.output == OutputFormat::Json) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Config) => {
+            print_config(&args);
+            return;
+        }
+        _ => {}
+    }
+    let policy = match args.policy.load() {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("Failed to load policy: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    // Set up file appender (file per day)
+    let file_appender = rolling::daily(&args.log_dir, "sfctl-ai.log");
     fmt()
         .with_writer(file_appender)
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(args.log_level)
         .with_ansi(false)
         .init();
 
+    let config = ChatConfig {
+        ai: args.ai,
+        backend: args.backend,
+        policy,
+        audit: args.audit,
+        approval: args.approval.unwrap_or(match args.command {
+            // nobody is there to approve the commands
+            Some(Command::Ask { .. }) => ApprovalMode::ReadOnly,
+            _ => ApprovalMode::Prompt,
+        }),
+        context: args.context,
+        limits: args.limits,
+        retry: args.retry,
+        endpoint: args.endpoint,
+        // single commands are not conversations
+        session: match args.command {
+            Some(Command::Run { .. }) => None,
+            _ => Some(args.session),
+        },
+    };
+
     let h = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
 
-    h.block_on(async {
-        let token = tokio_util::sync::CancellationToken::new();
-        let app_handle = tokio::spawn({
-            let token = token.clone();
-            async move {
-                app_loop(token).await;
+    let status = h.block_on(async {
+        match args.command {
+            None | Some(Command::Chat) => chat(config).await,
+            Some(Command::Ask { question }) => {
+                tokio::select! {
+                    status = ask(&config, &question, args.output) => status,
+                    _ = signal::ctrl_c() => 130,
+                }
             }
-        });
-
-        signal::ctrl_c()
-            .await
-            .expect("Failed to install Ctrl-C handler");
-        tracing::info!("Ctrl-C received, shutting down.");
-        token.cancel();
-        app_handle.await.expect("App loop failed");
-        tracing::info!("Application block_on done.");
+            Some(Command::Run { command }) => {
+                tokio::select! {
+                    status = run(&config, &command, args.output) => status,
+                    _ = signal::ctrl_c() => 130,
+                }
+            }
+            Some(Command::Audit(_) | Command::Session(_) | Command::Config) => {
+                unreachable!("handled before")
+            }
+        }
     });
     // shutdown manually due to windows io.
     h.shutdown_background();
     tracing::info!("Application has exited.");
+    if status != 0 {
+        std::process::exit(status);
+    }
+}
+
+async fn chat(config: ChatConfig) -> i32 {
+    let token = tokio_util::sync::CancellationToken::new();
+    let mut app_handle = tokio::spawn({
+        let token = token.clone();
+        async move { app_loop(token, config).await }
+    });
+
+    // the loop ends by itself when the chat cannot be created
+    let result = tokio::select! {
+        result = &mut app_handle => result,
+        ctrl_c = signal::ctrl_c() => {
+            ctrl_c.expect("Failed to install Ctrl-C handler");
+            tracing::info!("Ctrl-C received, shutting down.");
+            token.cancel();
+            app_handle.await
+        }
+    };
+    tracing::info!("Application block_on done.");
+    match result.expect("App loop failed") {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{e}");
+            1
+        }
+    }
+}
+
+async fn ask(config: &ChatConfig, question: &str, output: OutputFormat) -> i32 {
+    let mut chat = match create_chat(config).await {
+        Ok(chat) => chat,
+        Err(e) => {
+            eprintln!("{e}");
+            return 1;
+        }
+    };
+    chat.set_interactive(false);
+    if let Some(id) = chat.session_id() {
+        eprintln!("Session {id}");
+    }
+    let result = match chat.ask(question).await {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("{e}");
+            return 1;
+        }
+    };
+    let status = if result.is_incomplete() { 2 } else { 0 };
+    match output {
+        OutputFormat::Json => {
+            let result = serde_json::json!({
+                "session": chat.session_id(),
+                "question": question,
+                "answer": result.answer,
+                "commands": result.commands,
+                "stopped": result.stopped.as_ref().map(|r| r.to_string()),
+                "status": status,
+            });
+            println!("{result:#}");
+        }
+        OutputFormat::Text => println!("{}", result.answer),
+    }
+    status
+}
+
+async fn run(config: &ChatConfig, command: &str, output: OutputFormat) -> i32 {
+    let mut chat = match create_chat(config).await {
+        Ok(chat) => chat,
+        Err(e) => {
+            eprintln!("{e}");
+            return 1;
+        }
+    };
+    let json = output == OutputFormat::Json;
+    let (outcome, content) = chat.run_command(command, json).await;
+    if json {
+        let output = serde_json::from_str(&content).unwrap_or(serde_json::Value::String(content));
+        let result = serde_json::json!({
+            "command": command,
+            "outcome": outcome,
+            "output": output,
+        });
+        println!("{result:#}");
+    } else {
+        println!("{content}");
+    }
+    if outcome == Outcome::Succeeded { 0 } else { 1 }
+}
+
+fn print_config(args: &Args) {
+    let value_name = |v: Option<clap::builder::PossibleValue>| {
+        v.map(|v| v.get_name().to_string()).unwrap_or_default()
+    };
+    let api_key_env = args.ai.api_key_env();
+    let config = serde_json::json!({
+        "provider": value_name(args.ai.provider.to_possible_value()),
+        "model": args.ai.model(),
+        "base_url": args.ai.base_url,
+        "api_key_env": api_key_env,
+        "api_key_set": api_key_env.is_some_and(|env| std::env::var_os(env).is_some()),
+        "backend": value_name(args.backend.backend.to_possible_value()),
+        "gateway_url": args.backend.gateway_url,
+        "command_timeout": args.backend.command_timeout,
+        "endpoint": args.endpoint,
+        "policy": args.policy.policy_file,
+        "approval": args.approval.map(|a| a.to_string()),
+        "audit_log": args.audit.audit_log,
+        "session_dir": args.session.session_dir,
+        "context_budget": args.context.context_budget,
+        "max_output_tokens": args.context.max_output_tokens,
+        "max_steps": args.limits.max_steps,
+        "max_repeats": args.limits.max_repeats,
+        "max_retries": args.retry.max_retries,
+        "max_retry_wait": args.retry.max_retry_wait,
+        "log_dir": args.log_dir,
+        "log_level": args.log_level.to_string().to_lowercase(),
+    });
+    match args.output {
+        OutputFormat::Json => println!("{config:#}"),
+        OutputFormat::Text => {
+            if let serde_json::Value::Object(map) = config {
+                for (key, value) in map {
+                    let value = match value {
+                        serde_json::Value::String(s) => s,
+                        serde_json::Value::Null => "-".to_string(),
+                        v => v.to_string(),
+                    };
+                    println!("{key}: {value}");
+                }
+            }
+        }
+    }
 }
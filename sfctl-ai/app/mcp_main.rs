@@ -1,12 +1,390 @@
 mod mcp_server;
 
-use mcp_server::ServiceFabricServer;
-use rmcp::{ServiceExt, transport::stdio};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use clap::{Parser, ValueEnum};
+use http_body_util::{BodyExt, Full, combinators::BoxBody};
+use hyper::{
+    Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header,
+    http::{Uri, uri::Authority},
+    service::{Service, service_fn},
+};
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use mcp_server::{ClientUser, ServiceFabricServer};
+use rmcp::{
+    ServiceExt,
+    transport::{
+        StreamableHttpService, stdio, streamable_http_server::session::local::LocalSessionManager,
+    },
+};
+use sfctl_ai::{
+    audit::{AuditConfig, AuditLog},
+    backend::BackendConfig,
+    policy::PolicyConfig,
+    pool::PoolConfig,
+};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+
+#[derive(Parser)]
+#[command(version, about = "Service Fabric MCP server")]
+struct Args {
+    #[command(flatten)]
+    backend: BackendConfig,
+
+    #[command(flatten)]
+    policy: PolicyConfig,
+
+    #[command(flatten)]
+    audit: AuditConfig,
+
+    #[command(flatten)]
+    pool: PoolConfig,
+
+    /// How clients connect to the server
+    #[arg(long, env = "SFCTL_AI_MCP_TRANSPORT", value_enum, default_value_t)]
+    transport: Transport,
+
+    /// Address the http transport listens on. Addresses other than loopback need --auth-token
+    #[arg(long, env = "SFCTL_AI_MCP_BIND", default_value = "127.0.0.1:8000")]
+    bind: SocketAddr,
+
+    /// Bearer token of the http transport as USER=TOKEN, repeat for each user. The user is
+    /// recorded in the audit log, a token without user is recorded as `mcp-client`
+    #[arg(
+        long,
+        env = "SFCTL_AI_MCP_TOKEN",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    auth_token: Vec<String>,
+
+    /// Run writes for clients that cannot ask the user to confirm them, trusting the client to
+    /// confirm sf_write_command from its annotations. Destructive commands and `ask` rules are
+    /// still refused
+    #[arg(long, env = "SFCTL_AI_MCP_ALLOW_UNCONFIRMED_WRITES")]
+    allow_unconfirmed_writes: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum Transport {
+    /// A single client that started the server, over stdin and stdout
+    #[default]
+    Stdio,
+    /// Any number of clients over streamable HTTP, with server-sent events
+    Http,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let auth = Auth::new(&args.auth_token);
+    if args.transport == Transport::Http && auth.is_open() && !args.bind.ip().is_loopback() {
+        return Err(format!(
+            "--auth-token is required to serve other machines at {}",
+            args.bind
+        )
+        .into());
+    }
+
     // Create an instance of our Service Fabric service
-    let service = ServiceFabricServer::new().await?.serve(stdio()).await?;
-    service.waiting().await?;
+    let server = ServiceFabricServer::new(&args.backend, &args.pool)
+        .await?
+        .with_policy(args.policy.load()?)
+        .with_audit_log(AuditLog::open(&args.audit.audit_log)?)
+        .with_unconfirmed_writes(args.allow_unconfirmed_writes);
+    match args.transport {
+        Transport::Stdio => {
+            let service = server.serve(stdio()).await?;
+            service.waiting().await?;
+        }
+        Transport::Http => {
+            let listener = TcpListener::bind(args.bind).await?;
+            eprintln!(
+                "Serving MCP over HTTP at http://{}/mcp",
+                listener.local_addr()?
+            );
+            tokio::select! {
+                _ = serve_http(server, listener, auth) => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+    }
     Ok(())
 }
+
+/// Users of the http transport by the hash of their bearer token.
+/// Without tokens every request is let in.
+struct Auth {
+    users: HashMap<[u8; 32], String>,
+}
+
+impl Auth {
+    fn new(tokens: &[String]) -> Self {
+        let users = tokens
+            .iter()
+            .map(|token| match token.split_once('=') {
+                Some((user, token)) => (Self::hash(token), user.to_string()),
+                None => (Self::hash(token), "mcp-client".to_string()),
+            })
+            .collect();
+        Auth { users }
+    }
+
+    /// Hash compared instead of the token, so the lookup time does not reveal the token.
+    fn hash(token: &str) -> [u8; 32] {
+        Sha256::digest(token.as_bytes()).into()
+    }
+
+    fn is_open(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// User of the request's bearer token, None if it has no valid one.
+    fn user<B>(&self, request: &Request<B>) -> Option<ClientUser> {
+        let token = request
+            .headers()
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+        self.users
+            .get(&Self::hash(token.trim()))
+            .map(|user| ClientUser(user.clone()))
+    }
+}
+
+fn unauthorized() -> Response<BoxBody<Bytes, Infallible>> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::WWW_AUTHENTICATE, "Bearer")
+        .body(Full::new(Bytes::from("Missing or invalid bearer token")).boxed())
+        .expect("valid response")
+}
+
+fn forbidden() -> Response<BoxBody<Bytes, Infallible>> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Full::new(Bytes::from("Host or origin not allowed")).boxed())
+        .expect("valid response")
+}
+
+/// Whether the host names this server: loopback or the address it listens on. A web page
+/// cannot reach the server through a DNS name rebound to it, as such names are refused.
+fn is_server_host(host: &str, bind: IpAddr) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback() || ip == bind || bind.is_unspecified(),
+        Err(_) => false,
+    }
+}
+
+/// Whether the Host and, if a browser sent one, the Origin of the request name this server.
+fn is_server_request<B>(request: &Request<B>, bind: IpAddr) -> bool {
+    let headers = request.headers();
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok()?.parse::<Authority>().ok())
+        .is_some_and(|host| is_server_host(host.host(), bind));
+    let origin = match headers.get(header::ORIGIN) {
+        Some(origin) => origin
+            .to_str()
+            .ok()
+            .and_then(|origin| origin.parse::<Uri>().ok())
+            .is_some_and(|origin| origin.host().is_some_and(|h| is_server_host(h, bind))),
+        None => true,
+    };
+    host && origin
+}
+
+/// Serve the MCP clients connecting to the listener, each with its own MCP and command session.
+/// Requests without a valid bearer token are refused, unless `auth` has no tokens, and so are
+/// requests for another host or from another origin.
+async fn serve_http(server: ServiceFabricServer, listener: TcpListener, auth: Auth) {
+    let bind = listener
+        .local_addr()
+        .map_or(IpAddr::from([127, 0, 0, 1]), |addr| addr.ip());
+    let mcp = TowerToHyperService::new(StreamableHttpService::new(
+        move || Ok(server.new_client()),
+        LocalSessionManager::default().into(),
+        Default::default(),
+    ));
+    let auth = Arc::new(auth);
+    let service = service_fn(move |mut request: Request<Incoming>| {
+        let mcp = mcp.clone();
+        let auth = auth.clone();
+        async move {
+            if !is_server_request(&request, bind) {
+                return Ok(forbidden());
+            }
+            if !auth.is_open() {
+                match auth.user(&request) {
+                    // the tools read the user from the http parts of the request
+                    Some(user) => {
+                        request.extensions_mut().insert(user);
+                    }
+                    None => return Ok(unauthorized()),
+                }
+            }
+            mcp.call(request).await
+        }
+    });
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // e.g. out of file handles, the next connection may work
+                eprintln!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let service = service.clone();
+        tokio::spawn(async move {
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sfctl_ai::backend::ScriptedBackend;
+
+    /// Post a json-rpc message. Returns the status, the MCP session id and the body.
+    async fn post(
+        url: &str,
+        token: Option<&str>,
+        session: Option<&str>,
+        message: serde_json::Value,
+    ) -> (reqwest::StatusCode, Option<String>, String) {
+        let mut request = reqwest::Client::new()
+            .post(url)
+            .header("Accept", "application/json, text/event-stream")
+            .header("Content-Type", "application/json")
+            .body(message.to_string());
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(session) = session {
+            request = request.header("mcp-session-id", session);
+        }
+        let response = request.send().await.unwrap();
+        let status = response.status();
+        let session = response
+            .headers()
+            .get("mcp-session-id")
+            .map(|id| id.to_str().unwrap().to_string());
+        (status, session, response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_serve_http() {
+        let path = std::env::temp_dir().join(format!(
+            "sfctl-ai-mcp-http-audit-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = format!("http://127.0.0.1:{port}/mcp");
+        let server = ServiceFabricServer::with_backend(
+            ScriptedBackend::new().with_text("Get-ServiceFabricNode", "NodeName : n1"),
+        )
+        .with_audit_log(AuditLog::open(&path).unwrap());
+        let auth = Auth::new(&["alice=secret".to_string(), "shared".to_string()]);
+        tokio::spawn(serve_http(server, listener, auth));
+
+        let initialize = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {
+                "protocolVersion": "2025-06-18",
+                "capabilities": { "elicitation": {} },
+                "clientInfo": { "name": "test", "version": "1.0" },
+            },
+        });
+
+        // requests need a valid token
+        for token in [None, Some("wrong"), Some("alice=secret")] {
+            let (status, _, _) = post(&url, token, None, initialize.clone()).await;
+            assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED, "{token:?}");
+        }
+        let (status, _, _) = post(&url, Some("shared"), None, initialize.clone()).await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+
+        // pages of other sites cannot reach it, e.g. through a DNS name rebound to loopback
+        for (name, value, expected) in [
+            (
+                "Host",
+                format!("attacker.example:{port}"),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "Origin",
+                "http://attacker.example".to_string(),
+                StatusCode::FORBIDDEN,
+            ),
+            ("Origin", "null".to_string(), StatusCode::FORBIDDEN),
+            ("Origin", format!("http://localhost:{port}"), StatusCode::OK),
+        ] {
+            let status = reqwest::Client::new()
+                .post(&url)
+                .bearer_auth("shared")
+                .header("Accept", "application/json, text/event-stream")
+                .header("Content-Type", "application/json")
+                .header(name, &value)
+                .body(initialize.to_string())
+                .send()
+                .await
+                .unwrap()
+                .status();
+            assert_eq!(status.as_u16(), expected.as_u16(), "{name}: {value}");
+        }
+        assert!(is_server_host("[::1]", IpAddr::from([10, 0, 0, 4])));
+        assert!(is_server_host("10.0.0.4", IpAddr::from([10, 0, 0, 4])));
+        assert!(!is_server_host("10.0.0.5", IpAddr::from([10, 0, 0, 4])));
+
+        let (status, session, body) = post(&url, Some("secret"), None, initialize).await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        assert!(
+            body.contains("\"protocolVersion\":\"2025-06-18\""),
+            "{body}"
+        );
+        assert!(body.contains("sf_write_command"), "{body}");
+        let session = session.expect("mcp-session-id header");
+
+        // the commands are audited under the user of the token
+        let initialized = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/initialized",
+        });
+        post(&url, Some("secret"), Some(&session), initialized).await;
+        let call = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "tools/call",
+            "params": {
+                "name": "sf_command",
+                "arguments": { "command": "Get-ServiceFabricNode" },
+            },
+        });
+        let (status, _, body) = post(&url, Some("secret"), Some(&session), call).await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        assert!(body.contains("NodeName : n1"), "{body}");
+        let records = sfctl_ai::audit::query(&path, &Default::default()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].entry.user, "alice");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,11 @@
+use futures::future::BoxFuture;
 use rmcp::{
-    ServerHandler,
+    Peer, RoleServer, ServerHandler,
     handler::server::{router::tool::ToolRouter, wrapper::Parameters},
     model::{ErrorData as McpError, *},
-    schemars, tool, tool_handler, tool_router,
+    schemars,
+    service::{ElicitationError, RequestContext},
+    tool, tool_handler, tool_router,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -10,10 +13,17 @@ use std::borrow::Cow;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
 
 // Import the pwsh module from the parent crate
-use sfctl_ai::pwsh::PwshSession;
+use sfctl_ai::Error;
+use sfctl_ai::audit::{Approval, AuditEntry, AuditLog, Outcome};
+use sfctl_ai::backend::{BackendConfig, json_error};
+use sfctl_ai::catalog::{self, RiskLevel};
+use sfctl_ai::policy::{Policy, PolicyAction};
+use sfctl_ai::pool::{BackendPool, PoolClient, PoolConfig, SharedBackend};
+use sfctl_ai::pwsh::{DEFAULT_COMMAND_TIMEOUT, DEFAULT_JSON_DEPTH, is_endpoint, quote};
+use tokio_util::sync::CancellationToken;
 
 // Define a wrapper for tracing that writes to a file instead
 fn log_to_file(message: &str) {
@@ -27,26 +37,223 @@ fn log_to_file(message: &str) {
     }
 }
 
+/// User a request of the HTTP transport authenticated as, in the extensions of its http parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientUser(pub String);
+
 #[derive(Clone)]
 pub struct ServiceFabricServer {
     tool_router: ToolRouter<ServiceFabricServer>,
-    pwsh_session: Arc<Mutex<PwshSession>>,
+    /// The client's command session, each MCP client has its own.
+    client: Arc<PoolClient>,
+    policy: Arc<Policy>,
+    audit: Option<Arc<AuditLog>>,
+    /// Run writes without confirmation for clients that cannot ask the user.
+    unconfirmed_writes: bool,
+    /// Max time of a command that does not set its own timeout, None waits forever.
+    command_timeout: Option<Duration>,
+    /// User the request authenticated as, None records the user running the server.
+    user: Option<String>,
 }
 
 impl ServiceFabricServer {
-    pub async fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let pwsh_session = PwshSession::new()?;
-        Ok(Self {
+    pub async fn new(config: &BackendConfig, pool: &PoolConfig) -> sfctl_ai::Result<Self> {
+        let command_timeout = config.command_timeout();
+        let config = config.clone();
+        let mut server = Self::with_pool(BackendPool::new(pool, move || config.create_backend()));
+        server.command_timeout = command_timeout;
+        // Start the first session now, so a missing pwsh is reported on start
+        server.client.backend().await?;
+        Ok(server)
+    }
+
+    /// Server with sessions cloned from the backend, e.g. a scripted one.
+    #[cfg(test)]
+    pub fn with_backend(
+        backend: impl sfctl_ai::backend::CommandBackend + Clone + Sync + 'static,
+    ) -> Self {
+        Self::with_pool(BackendPool::new(&PoolConfig::default(), move || {
+            Ok(Box::new(backend.clone()))
+        }))
+    }
+
+    pub fn with_pool(pool: Arc<BackendPool>) -> Self {
+        Self {
             tool_router: Self::tool_router(),
-            pwsh_session: Arc::new(Mutex::new(pwsh_session)),
+            client: Arc::new(pool.client()),
+            policy: Arc::new(Policy::default()),
+            audit: None,
+            unconfirmed_writes: false,
+            command_timeout: Some(DEFAULT_COMMAND_TIMEOUT),
+            user: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+
+    /// Let clients without elicitation run writes that are neither destructive nor matched by
+    /// an `ask` rule, trusting them to confirm sf_write_command from its annotations.
+    pub fn with_unconfirmed_writes(mut self, unconfirmed_writes: bool) -> Self {
+        self.unconfirmed_writes = unconfirmed_writes;
+        self
+    }
+
+    /// The same tools, policy and audit log for another MCP client, with its own session.
+    pub fn new_client(&self) -> Self {
+        Self {
+            client: Arc::new(self.client.pool().client()),
+            ..self.clone()
+        }
+    }
+
+    /// The client's session, started if it was closed or not used yet.
+    async fn session(&self) -> Result<SharedBackend, McpError> {
+        self.client
+            .backend()
+            .await
+            .map_err(|e| tool_error("Failed to start the command session", e))
+    }
+
+    /// The server acting for the user the request authenticated as, if any.
+    fn for_request(&self, ctx: &RequestContext<RoleServer>) -> Self {
+        let mut server = self.clone();
+        server.user = ctx
+            .extensions
+            .get::<hyper::http::request::Parts>()
+            .and_then(|parts| parts.extensions.get::<ClientUser>())
+            .map(|user| user.0.clone());
+        server
+    }
+
+    fn record_audit(&self, mut entry: AuditEntry) {
+        if let Some(audit) = &self.audit {
+            if let Some(user) = &self.user {
+                entry.user = user.clone();
+            }
+            audit.record(entry);
+        }
+    }
+}
+
+/// Internal error for a failed tool call, e.g. the backend failed to run the command.
+fn tool_error(context: &str, e: Error) -> McpError {
+    McpError {
+        code: ErrorCode(-32603),
+        message: Cow::from(format!("{context}: {e}")),
+        data: None,
+    }
+}
+
+/// Audit entry for a command run by the MCP server.
+fn audit_entry(
+    command: &str,
+    cluster: Option<String>,
+    policy: String,
+    approval: Approval,
+    outcome: Outcome,
+) -> AuditEntry {
+    AuditEntry {
+        cluster,
+        risk: catalog::assess(command).level.to_string(),
+        policy,
+        approval,
+        outcome,
+        ..AuditEntry::new("mcp", command)
+    }
+}
+
+/// Which commands a tool may run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Only commands that the catalog classifies as reads.
+    ReadOnly,
+    /// Commands changing the cluster, checked by the classifier and policy.
+    Write,
+}
+
+/// How long the user has to confirm a command.
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Answer of the user to a command confirmation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Confirmation {
+    Approved,
+    /// Declined, cancelled or not answered in time.
+    Declined,
+    /// The client cannot ask the user.
+    Unavailable,
+}
+
+/// Asks the user to confirm a command before it runs.
+trait Approver: Send + Sync {
+    fn confirm<'a>(&'a self, message: &'a str) -> BoxFuture<'a, Confirmation>;
+}
+
+/// Form shown by the client for a command confirmation.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct CommandConfirmation {
+    /// Run the command
+    confirm: bool,
+}
+
+rmcp::elicit_safe!(CommandConfirmation);
+
+/// Confirmation through an MCP elicitation request, shown by the client.
+impl Approver for Peer<RoleServer> {
+    fn confirm<'a>(&'a self, message: &'a str) -> BoxFuture<'a, Confirmation> {
+        Box::pin(async move {
+            if !self.supports_elicitation() {
+                return Confirmation::Unavailable;
+            }
+            match self
+                .elicit_with_timeout::<CommandConfirmation>(message, Some(CONFIRMATION_TIMEOUT))
+                .await
+            {
+                Ok(Some(CommandConfirmation { confirm: true })) => Confirmation::Approved,
+                Ok(_) => Confirmation::Declined,
+                Err(ElicitationError::CapabilityNotSupported) => Confirmation::Unavailable,
+                Err(e) => {
+                    log_to_file(&format!("Command confirmation failed: {}", e));
+                    Confirmation::Declined
+                }
+            }
         })
     }
 }
 
+/// Question shown to the user, with the exact command and the cluster it runs on.
+fn confirmation_message(
+    command: &str,
+    cluster: Option<&str>,
+    level: RiskLevel,
+    policy: &str,
+) -> String {
+    format!(
+        "Run this command on cluster {}?\n\n{command}\n\nRisk: {level}, {policy}",
+        cluster.unwrap_or("(not connected)")
+    )
+}
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ServiceFabricCommandParams {
     /// PowerShell command to execute, e.g. "Get-ServiceFabricClusterHealth"
     pub command: String,
+    /// Return the output objects as JSON instead of formatted text
+    #[serde(default)]
+    pub json: bool,
+    /// Depth for the JSON conversion, defaults to 4
+    pub json_depth: Option<u32>,
+    /// Max seconds the command may run, defaults to the server's command timeout, 0 for none.
+    /// On timeout the PowerShell session is restarted.
+    pub timeout_secs: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
@@ -55,84 +262,611 @@ pub struct ServiceFabricConnectParams {
     pub endpoint: Option<String>,
 }
 
+/// Health states to include in a health report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum HealthStateFilter {
+    Default,
+    None,
+    Ok,
+    Warning,
+    Error,
+    All,
+}
+
+/// Node statuses to list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum NodeStatusFilter {
+    Default,
+    All,
+    Up,
+    Down,
+    Enabling,
+    Disabling,
+    Disabled,
+    Unknown,
+    Removed,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ClusterHealthParams {
+    /// Health states of the nodes to include, e.g. Error for the unhealthy nodes only
+    pub nodes_filter: Option<HealthStateFilter>,
+    /// Health states of the applications to include
+    pub applications_filter: Option<HealthStateFilter>,
+    /// Health states of the cluster health events to include
+    pub events_filter: Option<HealthStateFilter>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ApplicationsParams {
+    /// Application name, e.g. "fabric:/MyApp". All applications if not set
+    pub application_name: Option<String>,
+    /// Only applications of this type, e.g. "MyAppType"
+    pub application_type_name: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ServicesParams {
+    /// Application of the services, e.g. "fabric:/MyApp"
+    pub application_name: String,
+    /// Service name, e.g. "fabric:/MyApp/Web". All services of the application if not set
+    pub service_name: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct NodesParams {
+    /// Node name, e.g. "_Node_0". All nodes if not set
+    pub node_name: Option<String>,
+    /// Only nodes with this status, e.g. Down
+    pub status_filter: Option<NodeStatusFilter>,
+}
+
+/// Cmdlet with the parameters that are set, in order.
+fn cmdlet(name: &str, params: &[(&str, Option<String>)]) -> String {
+    let mut command = name.to_string();
+    for (param, value) in params {
+        if let Some(value) = value {
+            command.push_str(&format!(" -{param} {value}"));
+        }
+    }
+    command
+}
+
+/// Name of a filter value, e.g. `Warning`.
+fn filter_name<T: Serialize>(filter: &Option<T>) -> Option<String> {
+    filter
+        .as_ref()
+        .and_then(|f| serde_json::to_value(f).ok())
+        .and_then(|v| v.as_str().map(str::to_string))
+}
+
+impl ClusterHealthParams {
+    fn to_command(&self) -> String {
+        cmdlet(
+            "Get-ServiceFabricClusterHealth",
+            &[
+                ("NodesFilter", filter_name(&self.nodes_filter)),
+                ("ApplicationsFilter", filter_name(&self.applications_filter)),
+                ("EventsFilter", filter_name(&self.events_filter)),
+            ],
+        )
+    }
+}
+
+impl ApplicationsParams {
+    fn to_command(&self) -> String {
+        cmdlet(
+            "Get-ServiceFabricApplication",
+            &[
+                (
+                    "ApplicationName",
+                    self.application_name.as_deref().map(quote),
+                ),
+                (
+                    "ApplicationTypeName",
+                    self.application_type_name.as_deref().map(quote),
+                ),
+            ],
+        )
+    }
+}
+
+impl ServicesParams {
+    fn to_command(&self) -> String {
+        cmdlet(
+            "Get-ServiceFabricService",
+            &[
+                ("ApplicationName", Some(quote(&self.application_name))),
+                ("ServiceName", self.service_name.as_deref().map(quote)),
+            ],
+        )
+    }
+}
+
+impl NodesParams {
+    fn to_command(&self) -> String {
+        cmdlet(
+            "Get-ServiceFabricNode",
+            &[
+                ("NodeName", self.node_name.as_deref().map(quote)),
+                ("StatusFilter", filter_name(&self.status_filter)),
+            ],
+        )
+    }
+}
+
 #[tool_router]
 impl ServiceFabricServer {
-    #[tool(description = "Connect to a Service Fabric cluster")]
+    #[tool(
+        description = "Connect to a Service Fabric cluster",
+        annotations(
+            read_only_hint = false,
+            destructive_hint = false,
+            idempotent_hint = true,
+            open_world_hint = false
+        )
+    )]
     async fn sf_connect(
         &self,
-        Parameters(ServiceFabricConnectParams { endpoint }): Parameters<ServiceFabricConnectParams>,
+        Parameters(params): Parameters<ServiceFabricConnectParams>,
+        ctx: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        // Stop the command if the client cancels the request
+        self.for_request(&ctx)
+            .connect(params, Some(&ctx.peer), ctx.ct)
+            .await
+    }
+
+    #[tool(
+        description = "Show whether the server is connected to a cluster and its endpoint",
+        annotations(
+            read_only_hint = true,
+            destructive_hint = false,
+            idempotent_hint = true,
+            open_world_hint = false
+        )
+    )]
+    async fn sf_connection_status(&self) -> Result<CallToolResult, McpError> {
+        self.connection_status().await
+    }
+
+    #[tool(
+        description = "Get the aggregated health of the cluster with the health of its nodes and applications",
+        annotations(
+            read_only_hint = true,
+            destructive_hint = false,
+            idempotent_hint = true,
+            open_world_hint = false
+        )
+    )]
+    async fn sf_cluster_health(
+        &self,
+        Parameters(params): Parameters<ClusterHealthParams>,
+        ctx: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        self.for_request(&ctx)
+            .query(params.to_command(), Some(&ctx.peer), ctx.ct)
+            .await
+    }
+
+    #[tool(
+        description = "List the applications in the cluster with their type, version and health",
+        annotations(
+            read_only_hint = true,
+            destructive_hint = false,
+            idempotent_hint = true,
+            open_world_hint = false
+        )
+    )]
+    async fn sf_applications(
+        &self,
+        Parameters(params): Parameters<ApplicationsParams>,
+        ctx: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        self.for_request(&ctx)
+            .query(params.to_command(), Some(&ctx.peer), ctx.ct)
+            .await
+    }
+
+    #[tool(
+        description = "List the services of an application with their kind, status and health",
+        annotations(
+            read_only_hint = true,
+            destructive_hint = false,
+            idempotent_hint = true,
+            open_world_hint = false
+        )
+    )]
+    async fn sf_services(
+        &self,
+        Parameters(params): Parameters<ServicesParams>,
+        ctx: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        self.for_request(&ctx)
+            .query(params.to_command(), Some(&ctx.peer), ctx.ct)
+            .await
+    }
+
+    #[tool(
+        description = "List the cluster nodes with their status and health",
+        annotations(
+            read_only_hint = true,
+            destructive_hint = false,
+            idempotent_hint = true,
+            open_world_hint = false
+        )
+    )]
+    async fn sf_nodes(
+        &self,
+        Parameters(params): Parameters<NodesParams>,
+        ctx: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        self.for_request(&ctx)
+            .query(params.to_command(), Some(&ctx.peer), ctx.ct)
+            .await
+    }
+
+    #[tool(
+        description = "Execute a read-only Service Fabric PowerShell command, e.g. Get-ServiceFabricApplicationHealth. Commands that change the cluster are refused, use sf_write_command for them",
+        annotations(
+            read_only_hint = true,
+            destructive_hint = false,
+            idempotent_hint = true,
+            open_world_hint = false
+        )
+    )]
+    async fn sf_command(
+        &self,
+        Parameters(params): Parameters<ServiceFabricCommandParams>,
+        ctx: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        self.for_request(&ctx)
+            .command(params, Access::ReadOnly, Some(&ctx.peer), ctx.ct)
+            .await
+    }
+
+    #[tool(
+        description = "Execute a Service Fabric PowerShell command that changes the cluster, e.g. Restart-ServiceFabricNode. The user is asked to confirm the command, it is refused if the client cannot ask",
+        annotations(
+            read_only_hint = false,
+            destructive_hint = true,
+            idempotent_hint = false,
+            open_world_hint = false
+        )
+    )]
+    async fn sf_write_command(
+        &self,
+        Parameters(params): Parameters<ServiceFabricCommandParams>,
+        ctx: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        self.for_request(&ctx)
+            .command(params, Access::Write, Some(&ctx.peer), ctx.ct)
+            .await
+    }
+}
+
+impl ServiceFabricServer {
+    async fn connection_status(&self) -> Result<CallToolResult, McpError> {
+        let endpoint = self.session().await?.lock().await.endpoint();
+        log_to_file(&format!("sf_connection_status: {:?}", endpoint));
+        Ok(CallToolResult::structured(serde_json::json!({
+            "connected": endpoint.is_some(),
+            "endpoint": endpoint,
+        })))
+    }
+
+    /// Run a read command of a typed tool, with the policy and audit of sf_command.
+    async fn query(
+        &self,
+        command: String,
+        approver: Option<&dyn Approver>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        let mut result = self
+            .command(
+                ServiceFabricCommandParams {
+                    command: command.clone(),
+                    json: true,
+                    json_depth: None,
+                    timeout_secs: None,
+                },
+                Access::ReadOnly,
+                approver,
+                ct,
+            )
+            .await?;
+        // show which command gave the result
+        if let Some(structured) = &mut result.structured_content {
+            structured["command"] = command.into();
+        }
+        Ok(result)
+    }
+
+    async fn connect(
+        &self,
+        ServiceFabricConnectParams { endpoint }: ServiceFabricConnectParams,
+        approver: Option<&dyn Approver>,
+        ct: CancellationToken,
     ) -> Result<CallToolResult, McpError> {
         let endpoint = endpoint.unwrap_or_else(|| "localhost:19000".to_string());
         log_to_file(&format!("sf_connect called with endpoint: {}", endpoint));
+        if !is_endpoint(&endpoint) {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Invalid endpoint, expected host:port: {endpoint}"
+            ))]));
+        }
+        let connect_command = format!(
+            "Connect-ServiceFabricCluster -ConnectionEndpoint {}",
+            quote(&endpoint)
+        );
 
-        let mut session = self.pwsh_session.lock().await;
+        let backend = self.session().await?;
+        let mut session = backend.lock().await;
+        let decision = self.policy.evaluate(&connect_command);
+        let approval = match self
+            .authorize(
+                &connect_command,
+                Access::Write,
+                approver,
+                session.endpoint(),
+            )
+            .await
+        {
+            Ok(approval) => approval,
+            Err(refusal) => return Ok(CallToolResult::error(vec![Content::text(refusal)])),
+        };
+        session.set_cancellation_token(ct);
+        // an earlier sf_command may have left its own timeout
+        session.set_timeout(self.command_timeout);
 
         // First import the Service Fabric module
         match session.run_command("Import-Module ServiceFabric").await {
             Ok(_) => log_to_file("ServiceFabric module imported successfully"),
             Err(e) => {
                 log_to_file(&format!("Failed to import ServiceFabric module: {}", e));
-                return Err(McpError {
-                    code: ErrorCode(-32603),
-                    message: Cow::from(format!("Failed to import ServiceFabric module: {}", e)),
-                    data: None,
-                });
+                return Err(tool_error(
+                    "Failed to import ServiceFabric module",
+                    Error::Backend(e),
+                ));
             }
         }
 
-        // Connect to the cluster
-        let connect_command = format!(
-            "Connect-ServiceFabricCluster -ConnectionEndpoint {}",
-            endpoint
+        // Connect to the cluster, a failed connection is kept out of the bootstrap
+        let started = Instant::now();
+        let result = session.run_command_outcome(&connect_command).await;
+        self.record_audit(
+            audit_entry(
+                &connect_command,
+                Some(endpoint.clone()),
+                decision.describe(),
+                approval,
+                match &result {
+                    Ok(outcome) if outcome.is_success() => Outcome::Succeeded,
+                    Ok(_) => Outcome::Failed,
+                    Err(_) => Outcome::Error,
+                },
+            )
+            .with_duration(started.elapsed()),
         );
-        match session.run_command(&connect_command).await {
-            Ok(output) => {
-                log_to_file(&format!("Connected to SF cluster: {}", output));
+        match result {
+            Ok(outcome) if outcome.is_success() => {
+                log_to_file(&format!("Connected to SF cluster: {}", outcome.stdout));
                 Ok(CallToolResult::success(vec![Content::text(format!(
                     "Connected to Service Fabric cluster at {}\n{}",
-                    endpoint, output
+                    endpoint,
+                    outcome.to_report()
+                ))]))
+            }
+            Ok(outcome) => {
+                log_to_file(&format!(
+                    "Failed to connect to SF cluster: {}",
+                    outcome.to_report()
+                ));
+                Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to connect to Service Fabric cluster at {}\n{}",
+                    endpoint,
+                    outcome.to_report()
                 ))]))
             }
             Err(e) => {
                 log_to_file(&format!("Failed to connect to SF cluster: {}", e));
-                Err(McpError {
-                    code: ErrorCode(-32603),
-                    message: Cow::from(format!(
-                        "Failed to connect to Service Fabric cluster: {}",
-                        e
-                    )),
-                    data: None,
-                })
+                Err(tool_error(
+                    "Failed to connect to Service Fabric cluster",
+                    Error::Backend(e),
+                ))
             }
         }
     }
 
-    #[tool(description = "Execute a Service Fabric PowerShell command")]
-    async fn sf_command(
+    /// Check the command against the policy, the access of the tool and its risk, and ask the
+    /// user to confirm it if needed. Returns how it was approved, or why it must not run.
+    /// Refusals are recorded in the audit log.
+    async fn authorize(
+        &self,
+        command: &str,
+        access: Access,
+        approver: Option<&dyn Approver>,
+        cluster: Option<String>,
+    ) -> Result<Approval, String> {
+        let level = catalog::assess(command).level;
+        let decision = self.policy.evaluate(command);
+        let ask_rule = decision.action == PolicyAction::Ask && decision.rule.is_some();
+        let refuse = |refusal: String, approval: Approval| {
+            log_to_file(&format!("SF command rejected: {}", refusal));
+            self.record_audit(audit_entry(
+                command,
+                cluster.clone(),
+                decision.describe(),
+                approval,
+                Outcome::NotRun,
+            ));
+            Err(refusal)
+        };
+        if decision.action == PolicyAction::Deny {
+            return refuse(
+                format!("Command denied, {}: {command}", decision.describe()),
+                Approval::Denied,
+            );
+        }
+        if access == Access::ReadOnly && level.needs_ack() {
+            return refuse(
+                format!(
+                    "Command refused, sf_command only runs read commands and this one is {level} risk: {command}. Use sf_write_command to change the cluster"
+                ),
+                Approval::Denied,
+            );
+        }
+        let needs_approval = ask_rule || (access == Access::Write && level.needs_ack());
+        if !needs_approval {
+            return Ok(Approval::Auto);
+        }
+
+        let confirmation = match approver {
+            Some(approver) => {
+                let message =
+                    confirmation_message(command, cluster.as_deref(), level, &decision.describe());
+                approver.confirm(&message).await
+            }
+            None => Confirmation::Unavailable,
+        };
+        match confirmation {
+            Confirmation::Approved => Ok(Approval::User),
+            Confirmation::Declined => refuse(
+                format!("Command declined by the user: {command}"),
+                Approval::Declined,
+            ),
+            // Without elicitation nobody confirms the command, unless the server trusts the
+            // client to confirm sf_write_command from its annotations
+            Confirmation::Unavailable if ask_rule => refuse(
+                format!(
+                    "Command requires user approval, which the client does not support, {}: {command}",
+                    decision.describe()
+                ),
+                Approval::Denied,
+            ),
+            Confirmation::Unavailable
+                if self.unconfirmed_writes && level != RiskLevel::Destructive =>
+            {
+                Ok(Approval::Auto)
+            }
+            Confirmation::Unavailable => refuse(
+                format!(
+                    "Command requires user approval, which the client does not support, {level} risk: {command}"
+                ),
+                Approval::Denied,
+            ),
+        }
+    }
+
+    async fn command(
         &self,
-        Parameters(ServiceFabricCommandParams { command }): Parameters<ServiceFabricCommandParams>,
+        ServiceFabricCommandParams {
+            command,
+            json,
+            json_depth,
+            timeout_secs,
+        }: ServiceFabricCommandParams,
+        access: Access,
+        approver: Option<&dyn Approver>,
+        ct: CancellationToken,
     ) -> Result<CallToolResult, McpError> {
-        log_to_file(&format!("sf_command called with: {}", command));
+        log_to_file(&format!("{:?} command called with: {}", access, command));
+
+        // Hold the session while asking, so the command runs on the cluster that was confirmed
+        let backend = self.session().await?;
+        let mut session = backend.lock().await;
+        let decision = self.policy.evaluate(&command);
+        let policy_note = decision.rule.as_ref().map(|_| decision.describe());
+        let approval = match self
+            .authorize(&command, access, approver, session.endpoint())
+            .await
+        {
+            Ok(approval) => approval,
+            Err(refusal) => return Ok(CallToolResult::error(vec![Content::text(refusal)])),
+        };
+
+        session.set_cancellation_token(ct);
+        // 0 disables the timeout, like the server's command timeout
+        session.set_timeout(match timeout_secs {
+            None => self.command_timeout,
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+        });
+        let started = Instant::now();
+        let audit = |outcome: Outcome, cluster: Option<String>| {
+            self.record_audit(
+                audit_entry(&command, cluster, decision.describe(), approval, outcome)
+                    .with_duration(started.elapsed()),
+            )
+        };
 
-        let mut session = self.pwsh_session.lock().await;
+        if json {
+            let depth = json_depth.unwrap_or(DEFAULT_JSON_DEPTH);
+            let result = session.run_command_json(&command, depth).await;
+            audit(
+                match &result {
+                    Ok(output) if json_error(output).is_some() => Outcome::Failed,
+                    Ok(_) => Outcome::Succeeded,
+                    Err(_) => Outcome::Error,
+                },
+                session.endpoint(),
+            );
+            return match result {
+                Ok(output) if json_error(&output).is_some() => {
+                    let message = json_error(&output).unwrap_or_default();
+                    log_to_file(&format!("SF command failed: {}", message));
+                    Ok(CallToolResult::error(vec![Content::text(message)]))
+                }
+                Ok(output) => {
+                    log_to_file(&format!("SF command executed successfully: {}", command));
+                    // Structured content must be an object
+                    let mut result = serde_json::json!({ "output": output });
+                    if let Some(note) = policy_note {
+                        result["policy"] = note.into();
+                    }
+                    Ok(CallToolResult::structured(result))
+                }
+                Err(e) => {
+                    log_to_file(&format!("SF command failed: {}", e));
+                    Err(tool_error("PowerShell command failed", Error::Backend(e)))
+                }
+            };
+        }
 
-        match session.run_command(&command).await {
-            Ok(output) => {
+        let result = session.run_command_outcome(&command).await;
+        audit(
+            match &result {
+                Ok(outcome) if outcome.is_success() => Outcome::Succeeded,
+                Ok(_) => Outcome::Failed,
+                Err(_) => Outcome::Error,
+            },
+            session.endpoint(),
+        );
+        match result {
+            Ok(outcome) if !outcome.is_success() => {
+                log_to_file(&format!("SF command failed: {}", outcome.to_report()));
+                Ok(CallToolResult::error(vec![Content::text(
+                    outcome.to_report(),
+                )]))
+            }
+            Ok(outcome) => {
                 log_to_file(&format!("SF command executed successfully: {}", command));
-                let result = if output.is_empty() {
+                let result = if !outcome.warnings.is_empty() || !outcome.stderr.is_empty() {
+                    outcome.to_report()
+                } else if outcome.stdout.is_empty() {
                     format!("Command '{}' executed successfully (no output)", command)
                 } else {
-                    output
+                    outcome.stdout
+                };
+                let result = match policy_note {
+                    Some(note) => format!("Policy: {note}\n{result}"),
+                    None => result,
                 };
                 Ok(CallToolResult::success(vec![Content::text(result)]))
             }
             Err(e) => {
                 log_to_file(&format!("SF command failed: {}", e));
-                Err(McpError {
-                    code: ErrorCode(-32603),
-                    message: Cow::from(format!("PowerShell command failed: {}", e)),
-                    data: None,
-                })
+                Err(tool_error("PowerShell command failed", Error::Backend(e)))
             }
         }
     }
@@ -142,10 +876,726 @@ impl ServiceFabricServer {
 impl ServerHandler for ServiceFabricServer {
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
-            protocol_version: ProtocolVersion::V_2024_11_05,
+            protocol_version: ProtocolVersion::V_2025_06_18,
             capabilities: ServerCapabilities::builder().enable_tools().build(),
             server_info: Implementation::from_build_env(),
-            instructions: Some("Service Fabric AI Assistant. Use sf_connect to connect to a cluster, then sf_command to execute Service Fabric PowerShell commands like Get-ServiceFabricClusterHealth, Get-ServiceFabricApplication, etc.".to_string()),
+            instructions: Some("Service Fabric AI Assistant. Use sf_connect to connect to a cluster and sf_connection_status to check the connection. Use sf_cluster_health, sf_applications, sf_services and sf_nodes for the common queries, sf_command for other read-only Service Fabric PowerShell commands and sf_write_command for commands that change the cluster, the user is asked to confirm them.".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sfctl_ai::backend::ScriptedBackend;
+
+    fn command_params(command: &str, json: bool) -> ServiceFabricCommandParams {
+        ServiceFabricCommandParams {
+            command: command.to_string(),
+            json,
+            json_depth: None,
+            timeout_secs: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sf_connect() {
+        let backend = ScriptedBackend::new()
+            .with_text("Import-Module ServiceFabric", "")
+            .with_text("Connect-ServiceFabricCluster", "True");
+        let commands = backend.commands();
+        let server = ServiceFabricServer::with_backend(backend);
+
+        let res = server
+            .connect(
+                ServiceFabricConnectParams { endpoint: None },
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(false));
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                "Import-Module ServiceFabric",
+                "Connect-ServiceFabricCluster -ConnectionEndpoint 'localhost:19000'",
+            ]
+        );
+
+        // the endpoint cannot add commands
+        for endpoint in [
+            "x; Remove-ServiceFabricApplication fabric:/App -ForceRemove",
+            "x:19000'; Remove-ServiceFabricApplication 'fabric:/App",
+            "$(Remove-ServiceFabricApplication fabric:/App):19000",
+            "localhost",
+        ] {
+            let res = server
+                .connect(
+                    ServiceFabricConnectParams {
+                        endpoint: Some(endpoint.to_string()),
+                    },
+                    None,
+                    CancellationToken::new(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(res.is_error, Some(true), "{endpoint}");
         }
+        assert_eq!(commands.lock().unwrap().len(), 2);
+
+        // connecting goes through the policy
+        let policy = Policy::from_toml(
+            "[[rule]]\nname = \"no-connect\"\naction = \"deny\"\ncmdlet = \"Connect-*\"",
+        )
+        .unwrap();
+        let res = server
+            .with_policy(policy)
+            .connect(
+                ServiceFabricConnectParams { endpoint: None },
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(true));
+        assert_eq!(commands.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sf_connect_failure() {
+        let path =
+            std::env::temp_dir().join(format!("sfctl-ai-mcp-connect-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // the cluster cannot be reached, the cmdlet reports an error
+        let backend = ScriptedBackend::new().with_text("Import-Module ServiceFabric", "");
+        let server = ServiceFabricServer::with_backend(backend)
+            .with_audit_log(AuditLog::open(&path).unwrap());
+
+        let res = server
+            .connect(
+                ServiceFabricConnectParams { endpoint: None },
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(true));
+        let status = server.connection_status().await.unwrap().structured_content;
+        assert_eq!(status.unwrap()["connected"], false);
+        let records = sfctl_ai::audit::query(&path, &Default::default()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].entry.outcome, Outcome::Failed);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sf_clients() {
+        let backend = ScriptedBackend::new()
+            .with_text("Import-Module ServiceFabric", "")
+            .with_text("Connect-ServiceFabricCluster", "True");
+        let first = ServiceFabricServer::with_backend(backend);
+        let second = first.new_client();
+
+        // each client connects its own session
+        first
+            .connect(
+                ServiceFabricConnectParams {
+                    endpoint: Some("first:19000".to_string()),
+                },
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        let status = |server: ServiceFabricServer| async move {
+            server.connection_status().await.unwrap().structured_content
+        };
+        assert_eq!(
+            status(first.clone()).await.unwrap()["endpoint"],
+            "first:19000"
+        );
+        assert_eq!(status(second.clone()).await.unwrap()["connected"], false);
+        assert_eq!(first.client.pool().len(), 2);
+
+        // a disconnected client releases its session
+        drop(second);
+        assert_eq!(first.client.pool().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sf_command() {
+        let backend = ScriptedBackend::new()
+            .with_text(
+                "Get-ServiceFabricClusterHealth",
+                "AggregatedHealthState : Ok",
+            )
+            .with_json(
+                "Get-ServiceFabricNode",
+                serde_json::json!([{ "NodeName": "_Node_0" }]),
+            );
+        let server = ServiceFabricServer::with_backend(backend);
+
+        let res = server
+            .command(
+                command_params("Get-ServiceFabricClusterHealth", false),
+                Access::ReadOnly,
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(false));
+        assert_eq!(
+            res.content[0].as_text().unwrap().text,
+            "AggregatedHealthState : Ok"
+        );
+
+        let res = server
+            .command(
+                command_params("Get-ServiceFabricNode", true),
+                Access::ReadOnly,
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            res.structured_content.unwrap()["output"][0]["NodeName"],
+            "_Node_0"
+        );
+
+        // Failures are reported as tool errors
+        let res = server
+            .command(
+                command_params("Get-ServiceFabricMissing", false),
+                Access::ReadOnly,
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(true));
+        assert!(
+            res.content[0]
+                .as_text()
+                .unwrap()
+                .text
+                .contains("CommandNotFoundException")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sf_timeouts() {
+        let backend = ScriptedBackend::new()
+            .with_text("Import-Module ServiceFabric", "")
+            .with_text("Connect-ServiceFabricCluster", "True")
+            .with_json("Get-ServiceFabricNode", serde_json::json!([]));
+        let timeout = backend.timeout();
+        let server = ServiceFabricServer::with_backend(backend);
+        let run = |timeout_secs| {
+            server.command(
+                ServiceFabricCommandParams {
+                    timeout_secs,
+                    ..command_params("Get-ServiceFabricNode", true)
+                },
+                Access::ReadOnly,
+                None,
+                CancellationToken::new(),
+            )
+        };
+
+        run(Some(5)).await.unwrap();
+        assert_eq!(*timeout.lock().unwrap(), Some(Duration::from_secs(5)));
+        // 0 disables it
+        run(Some(0)).await.unwrap();
+        assert_eq!(*timeout.lock().unwrap(), None);
+        run(None).await.unwrap();
+        assert_eq!(*timeout.lock().unwrap(), Some(DEFAULT_COMMAND_TIMEOUT));
+
+        // the other tools do not keep the timeout of an earlier command
+        run(Some(5)).await.unwrap();
+        server
+            .connect(
+                ServiceFabricConnectParams { endpoint: None },
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(*timeout.lock().unwrap(), Some(DEFAULT_COMMAND_TIMEOUT));
+        run(Some(5)).await.unwrap();
+        server
+            .query(
+                NodesParams::default().to_command(),
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(*timeout.lock().unwrap(), Some(DEFAULT_COMMAND_TIMEOUT));
+    }
+
+    #[tokio::test]
+    async fn test_sf_typed_tools() {
+        let backend = ScriptedBackend::new()
+            .with_text("Import-Module ServiceFabric", "")
+            .with_text("Connect-ServiceFabricCluster", "True")
+            .with_json(
+                "Get-ServiceFabricClusterHealth",
+                serde_json::json!({ "AggregatedHealthState": "Warning" }),
+            )
+            .with_json(
+                "Get-ServiceFabricNode",
+                serde_json::json!([{ "NodeName": "_Node_2", "NodeStatus": "Down" }]),
+            )
+            .with_json(
+                "Get-ServiceFabricService -ApplicationName 'fabric:/Missing'",
+                serde_json::json!({ "Error": "Application not found" }),
+            )
+            .with_json("Get-ServiceFabric", serde_json::json!([]));
+        let commands = backend.commands();
+        let server = ServiceFabricServer::with_backend(backend);
+        let ct = CancellationToken::new;
+
+        let res = server.connection_status().await.unwrap();
+        assert_eq!(
+            res.structured_content.unwrap(),
+            serde_json::json!({ "connected": false, "endpoint": null })
+        );
+
+        let res = server
+            .query(
+                ClusterHealthParams {
+                    nodes_filter: Some(HealthStateFilter::Error),
+                    ..Default::default()
+                }
+                .to_command(),
+                None,
+                ct(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(false));
+        let result = res.structured_content.unwrap();
+        assert_eq!(result["output"]["AggregatedHealthState"], "Warning");
+        assert_eq!(
+            result["command"],
+            "Get-ServiceFabricClusterHealth -NodesFilter Error"
+        );
+
+        let res = server
+            .query(
+                NodesParams {
+                    status_filter: Some(NodeStatusFilter::Down),
+                    ..Default::default()
+                }
+                .to_command(),
+                None,
+                ct(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            res.structured_content.unwrap()["output"][0]["NodeName"],
+            "_Node_2"
+        );
+
+        // values are quoted so they cannot add commands
+        let params = ApplicationsParams {
+            application_name: Some("fabric:/App'; Remove-ServiceFabricApplication 'x".to_string()),
+            application_type_name: None,
+        };
+        assert_eq!(
+            params.to_command(),
+            "Get-ServiceFabricApplication -ApplicationName 'fabric:/App''; Remove-ServiceFabricApplication ''x'"
+        );
+        server.query(params.to_command(), None, ct()).await.unwrap();
+
+        // errors in the json output are tool errors
+        let res = server
+            .query(
+                ServicesParams {
+                    application_name: "fabric:/Missing".to_string(),
+                    service_name: None,
+                }
+                .to_command(),
+                None,
+                ct(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(true));
+        assert_eq!(
+            res.content[0].as_text().unwrap().text,
+            "Application not found"
+        );
+
+        assert_eq!(
+            commands.lock().unwrap().len(),
+            4,
+            "{:?}",
+            commands.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sf_command_policy() {
+        let backend = ScriptedBackend::new().with_text("Get-ServiceFabricNode", "NodeName : n1");
+        let commands = backend.commands();
+        let policy = Policy::from_toml(
+            r#"
+[[rule]]
+name = "protect-system"
+action = "deny"
+target = "fabric:/System*"
+
+[[rule]]
+name = "reads"
+action = "allow"
+cmdlet = "Get-*"
+
+[[rule]]
+name = "node-operations"
+action = "ask"
+cmdlet = "*-ServiceFabricNode"
+"#,
+        )
+        .unwrap();
+        let server = ServiceFabricServer::with_backend(backend).with_policy(policy);
+
+        let res = server
+            .command(
+                command_params("Get-ServiceFabricService fabric:/System", false),
+                Access::ReadOnly,
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(true));
+        assert_eq!(
+            res.content[0].as_text().unwrap().text,
+            "Command denied, deny by policy rule 'protect-system': Get-ServiceFabricService fabric:/System"
+        );
+
+        let res = server
+            .command(
+                command_params("Restart-ServiceFabricNode -NodeName n1", false),
+                Access::Write,
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(true));
+
+        let res = server
+            .command(
+                command_params("Get-ServiceFabricNode", false),
+                Access::ReadOnly,
+                None,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.is_error, Some(false));
+        assert_eq!(
+            res.content[0].as_text().unwrap().text,
+            "Policy: allow by policy rule 'reads'\nNodeName : n1"
+        );
+        assert_eq!(*commands.lock().unwrap(), vec!["Get-ServiceFabricNode"]);
+    }
+
+    #[tokio::test]
+    async fn test_sf_command_access() {
+        let backend = ScriptedBackend::new()
+            .with_text("Restart-ServiceFabricNode", "")
+            .with_text("Get-ServiceFabricNode", "NodeName : n1");
+        let commands = backend.commands();
+        let server = ServiceFabricServer::with_backend(backend).with_unconfirmed_writes(true);
+        let run = |command: &'static str, access: Access| {
+            let server = server.clone();
+            async move {
+                let res = server
+                    .command(
+                        command_params(command, false),
+                        access,
+                        None,
+                        CancellationToken::new(),
+                    )
+                    .await
+                    .unwrap();
+                (res.is_error, res.content[0].as_text().unwrap().text.clone())
+            }
+        };
+
+        // sf_command runs reads only
+        let (is_error, text) =
+            run("Restart-ServiceFabricNode -NodeName n1", Access::ReadOnly).await;
+        assert_eq!(is_error, Some(true));
+        assert!(text.contains("disruptive risk"), "{text}");
+        let (is_error, _) = run(
+            "Get-ServiceFabricNode; Restart-ServiceFabricNode n1",
+            Access::ReadOnly,
+        )
+        .await;
+        assert_eq!(is_error, Some(true));
+        assert_eq!(
+            run("Get-ServiceFabricNode", Access::ReadOnly).await.0,
+            Some(false)
+        );
+
+        // sf_write_command runs changes but not destructive ones
+        assert_eq!(
+            run("Restart-ServiceFabricNode -NodeName n1", Access::Write)
+                .await
+                .0,
+            Some(false)
+        );
+        let (is_error, text) =
+            run("Remove-ServiceFabricApplication fabric:/App", Access::Write).await;
+        assert_eq!(is_error, Some(true));
+        assert!(text.contains("destructive risk"), "{text}");
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                "Get-ServiceFabricNode",
+                "Restart-ServiceFabricNode -NodeName n1"
+            ]
+        );
+
+        let tools = ServiceFabricServer::tool_router().list_all();
+        let hints = |name: &str| {
+            let tool = tools.iter().find(|t| t.name == name).unwrap();
+            let annotations = tool.annotations.as_ref().unwrap();
+            (annotations.read_only_hint, annotations.destructive_hint)
+        };
+        for name in [
+            "sf_connection_status",
+            "sf_cluster_health",
+            "sf_applications",
+            "sf_services",
+            "sf_nodes",
+            "sf_command",
+        ] {
+            assert_eq!(hints(name), (Some(true), Some(false)), "{name}");
+        }
+        assert_eq!(hints("sf_write_command"), (Some(false), Some(true)));
+        assert_eq!(hints("sf_connect"), (Some(false), Some(false)));
+    }
+
+    /// Gives the same answer to every confirmation and keeps the questions.
+    struct ScriptedApprover {
+        answer: Confirmation,
+        messages: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl Approver for ScriptedApprover {
+        fn confirm<'a>(&'a self, message: &'a str) -> BoxFuture<'a, Confirmation> {
+            self.messages.lock().unwrap().push(message.to_string());
+            Box::pin(async move { self.answer })
+        }
+    }
+
+    async fn confirmed(
+        server: &ServiceFabricServer,
+        command: &str,
+        access: Access,
+        approver: &ScriptedApprover,
+    ) -> (Option<bool>, String) {
+        let res = server
+            .command(
+                command_params(command, false),
+                access,
+                Some(approver),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        (res.is_error, res.content[0].as_text().unwrap().text.clone())
+    }
+
+    #[tokio::test]
+    async fn test_sf_command_confirmation() {
+        let path =
+            std::env::temp_dir().join(format!("sfctl-ai-mcp-confirm-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let backend = ScriptedBackend::new()
+            .with_text("Restart-ServiceFabricNode", "")
+            .with_text("Remove-ServiceFabricApplication", "")
+            .with_text("Get-ServiceFabricNode", "NodeName : n1")
+            .with_text(
+                "Get-ServiceFabricClusterHealth",
+                "AggregatedHealthState : Ok",
+            );
+        let commands = backend.commands();
+        let policy = Policy::from_toml(
+            "[[rule]]\nname = \"node-reads\"\naction = \"ask\"\ncmdlet = \"Get-ServiceFabricNode\"",
+        )
+        .unwrap();
+        let server = ServiceFabricServer::with_backend(backend)
+            .with_policy(policy)
+            .with_audit_log(AuditLog::open(&path).unwrap());
+        let approver = |answer| ScriptedApprover {
+            answer,
+            messages: Default::default(),
+        };
+        let run = |command, access, approver| confirmed(&server, command, access, approver);
+
+        // the user sees the exact command and cluster before it runs
+        let approved = approver(Confirmation::Approved);
+        let command = "Restart-ServiceFabricNode -NodeName n1";
+        assert_eq!(run(command, Access::Write, &approved).await.0, Some(false));
+        assert_eq!(
+            *approved.messages.lock().unwrap(),
+            vec![confirmation_message(
+                command,
+                None,
+                RiskLevel::Disruptive,
+                "ask by default policy"
+            )]
+        );
+
+        // nothing runs when declined
+        let declined = approver(Confirmation::Declined);
+        let (is_error, text) = run(command, Access::Write, &declined).await;
+        assert_eq!(is_error, Some(true));
+        assert_eq!(text, format!("Command declined by the user: {command}"));
+
+        // ask rules are confirmed for reads too, reads without a rule are not
+        assert_eq!(
+            run("Get-ServiceFabricNode", Access::ReadOnly, &declined)
+                .await
+                .0,
+            Some(true)
+        );
+        assert_eq!(
+            run(
+                "Get-ServiceFabricClusterHealth",
+                Access::ReadOnly,
+                &declined
+            )
+            .await
+            .0,
+            Some(false)
+        );
+        assert_eq!(declined.messages.lock().unwrap().len(), 2);
+
+        // confirmed destructive commands run, sf_command still refuses writes
+        let command = "Remove-ServiceFabricApplication fabric:/App";
+        assert_eq!(run(command, Access::Write, &approved).await.0, Some(false));
+        assert_eq!(
+            run(command, Access::ReadOnly, &approved).await.0,
+            Some(true)
+        );
+        assert_eq!(approved.messages.lock().unwrap().len(), 2);
+
+        // without elicitation nothing that needs a confirmation runs
+        let unavailable = approver(Confirmation::Unavailable);
+        for (command, level) in [
+            ("Remove-ServiceFabricApplication fabric:/App", "destructive"),
+            ("Restart-ServiceFabricNode -NodeName n1", "disruptive"),
+            ("Invoke-ServiceFabricThing", "unknown"),
+            (
+                "Start-ServiceFabricNodeTransition -Start -NodeName n1",
+                "safe-write",
+            ),
+        ] {
+            let (is_error, text) = run(command, Access::Write, &unavailable).await;
+            assert_eq!(is_error, Some(true), "{command}");
+            assert!(text.contains(&format!("{level} risk")), "{text}");
+        }
+
+        // unless the server trusts the client to confirm from the annotations
+        let trusting = server.clone().with_unconfirmed_writes(true);
+        let command = "Restart-ServiceFabricNode -NodeName n1";
+        assert_eq!(
+            confirmed(&trusting, command, Access::Write, &unavailable)
+                .await
+                .0,
+            Some(false)
+        );
+        let command = "Remove-ServiceFabricApplication fabric:/App";
+        assert_eq!(
+            confirmed(&trusting, command, Access::Write, &unavailable)
+                .await
+                .0,
+            Some(true)
+        );
+
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                "Restart-ServiceFabricNode -NodeName n1",
+                "Get-ServiceFabricClusterHealth",
+                "Remove-ServiceFabricApplication fabric:/App",
+                "Restart-ServiceFabricNode -NodeName n1",
+            ]
+        );
+        let approvals = sfctl_ai::audit::query(&path, &Default::default())
+            .unwrap()
+            .iter()
+            .map(|r| (r.entry.approval, r.entry.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            approvals[..3],
+            [
+                (Approval::User, Outcome::Succeeded),
+                (Approval::Declined, Outcome::NotRun),
+                (Approval::Declined, Outcome::NotRun),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sf_command_audit() {
+        let path =
+            std::env::temp_dir().join(format!("sfctl-ai-mcp-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let backend = ScriptedBackend::new().with_text("Get-ServiceFabricNode", "NodeName : n1");
+        let policy = Policy::from_toml(
+            "[[rule]]\nname = \"no-removal\"\naction = \"deny\"\ncmdlet = \"Remove-*\"",
+        )
+        .unwrap();
+        let server = ServiceFabricServer::with_backend(backend)
+            .with_policy(policy)
+            .with_audit_log(AuditLog::open(&path).unwrap())
+            .with_unconfirmed_writes(true);
+
+        for command in [
+            "Get-ServiceFabricNode",
+            "Remove-ServiceFabricApplication fabric:/App",
+            "Restart-ServiceFabricNode n1",
+        ] {
+            server
+                .command(
+                    command_params(command, false),
+                    Access::Write,
+                    None,
+                    CancellationToken::new(),
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(sfctl_ai::audit::verify(&path).unwrap(), 3);
+        let records = sfctl_ai::audit::query(&path, &Default::default()).unwrap();
+        let summary = records
+            .iter()
+            .map(|r| (r.entry.risk.as_str(), r.entry.approval, r.entry.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("read", Approval::Auto, Outcome::Succeeded),
+                ("destructive", Approval::Denied, Outcome::NotRun),
+                ("disruptive", Approval::Auto, Outcome::Failed),
+            ]
+        );
+        assert_eq!(records[0].entry.source, "mcp");
+        assert!(records[0].entry.duration_ms.is_some());
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -1,22 +1,105 @@
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 
-// prompt user to ack an command
-pub async fn ack_command(command: &str) -> bool {
-    println!("You are about to run the command: {}", command);
-    println!("Do you want to proceed? (yes/no)");
+use crate::catalog::Assessment;
+
+/// User answer to a command approval prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AckChoice {
+    /// Run the command this time.
+    Once,
+    /// Run the command and do not ask again for its cmdlets in this session.
+    Session,
+    /// Run this edited command instead.
+    Edit(String),
+    /// Do not run the command.
+    Decline,
+    /// Do not run the command nor any other pending command.
+    SkipAll,
+}
+
+/// How commands that need approval are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ApprovalMode {
+    /// Ask the user before running them
+    #[default]
+    Prompt,
+    /// Run read commands only and deny the others, even if the policy allows them
+    ReadOnly,
+    /// Run them without asking. Explicit `ask` rules of the policy deny the command, and so
+    /// do destructive commands unless an `allow` rule matches them
+    Auto,
+}
+
+impl std::fmt::Display for ApprovalMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ApprovalMode::Prompt => "prompt",
+            ApprovalMode::ReadOnly => "read-only",
+            ApprovalMode::Auto => "auto",
+        };
+        f.write_str(s)
+    }
+}
 
-    let input = get_user_input().await;
+/// Parse the answer to the approval prompt. Anything unrecognized declines.
+pub fn parse_ack_choice(input: &str) -> AckChoice {
+    match input.trim().to_lowercase().as_str() {
+        "yes" | "y" => AckChoice::Once,
+        "always" | "a" => AckChoice::Session,
+        // The edited command is read separately
+        "edit" | "e" => AckChoice::Edit(String::new()),
+        "skip" | "s" => AckChoice::SkipAll,
+        _ => AckChoice::Decline,
+    }
+}
 
-    matches!(input.trim().to_lowercase().as_str(), "yes" | "y")
+// prompt user to ack an command
+pub async fn ack_command(command: &str, assessment: &Assessment) -> AckChoice {
+    println!("You are about to run the command: {}", command);
+    match &assessment.command {
+        Some(cmd) => println!("Risk level: {} ({})", assessment.level, cmd),
+        None => println!("Risk level: {}", assessment.level),
+    }
+    loop {
+        println!(
+            "Do you want to proceed? (yes/no, always: allow these cmdlets for this session, edit: change the command, skip: skip all pending commands)"
+        );
+        match parse_ack_choice(&get_user_input().await) {
+            AckChoice::Edit(_) => {
+                println!("Enter the command to run instead (empty to go back):");
+                let edited = get_user_input().await;
+                if !edited.is_empty() {
+                    return AckChoice::Edit(edited);
+                }
+            }
+            choice => return choice,
+        }
+    }
 }
 
 pub async fn get_user_input() -> String {
     let stdin = io::stdin();
     let mut reader = BufReader::new(stdin);
     let mut input = String::new();
-    reader
-        .read_line(&mut input)
-        .await
-        .expect("Failed to read line");
+    // a broken stdin reads as no answer, which declines
+    if let Err(e) = reader.read_line(&mut input).await {
+        tracing::info!("Failed to read user input: {e}");
+        return String::new();
+    }
     input.trim().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ack_choice() {
+        assert_eq!(parse_ack_choice("Y"), AckChoice::Once);
+        assert_eq!(parse_ack_choice(" always "), AckChoice::Session);
+        assert_eq!(parse_ack_choice("e"), AckChoice::Edit(String::new()));
+        assert_eq!(parse_ack_choice("skip"), AckChoice::SkipAll);
+        assert_eq!(parse_ack_choice("no"), AckChoice::Decline);
+        assert_eq!(parse_ack_choice("sure"), AckChoice::Decline);
+    }
+}
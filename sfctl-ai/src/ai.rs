@@ -1,48 +1,100 @@
-use std::{collections::VecDeque, vec};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+    vec,
+};
 
 use futures::StreamExt;
 use genai::{
-    Client, ModelIden,
-    chat::{ChatMessage, ChatRequest, ChatStreamEvent, printer::PrintChatStreamOptions},
-    resolver::{AuthData, AuthResolver},
+    Client, ServiceTarget,
+    chat::{
+        ChatMessage, ChatOptions, ChatRequest, ChatRole, ChatStreamEvent, Tool, ToolCall,
+        ToolResponse, printer::PrintChatStreamOptions,
+    },
+    resolver::ServiceTargetResolver,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::{cmd_parse::CmdKind, model::extract_code_blocks, pwsh::PwshSession};
+use crate::{
+    ack::{AckChoice, ApprovalMode},
+    audit::{Approval, AuditEntry, AuditLog, Outcome},
+    backend::{CommandBackend, json_error},
+    catalog::RiskLevel,
+    cmd_parse::Token,
+    context::{self, ContextConfig, READ_OUTPUT_TOOL_NAME, ReadOutputArgs},
+    error::{Error, Result},
+    model::{extract_code_blocks, get_action_from_tool_call},
+    policy::{Policy, PolicyAction},
+    provider::AiConfig,
+    pwsh::{CommandOutcome, DEFAULT_JSON_DEPTH, PwshSession},
+    retry::{self, RetryConfig},
+    session::{Session, SessionStore},
+};
 
-const MODEL: &str = "gemini-2.0-flash";
 const SYSTEM_PROMPT: &str = include_str!("system_prompt.txt");
+const SUMMARY_PROMPT: &str = "Summarize this conversation between a user and an assistant \
+operating a Service Fabric cluster. Keep the user's goals, the commands that were run with \
+their key results, the findings and the open questions. Be concise.";
+/// Name of the native tool the model calls to run powershell.
+pub const PWSH_TOOL_NAME: &str = "run_pwsh_command";
+
+/// Tool definition for running a Service Fabric powershell command.
+/// The arguments match [`crate::model::Action`].
+pub fn pwsh_tool() -> Tool {
+    Tool::new(PWSH_TOOL_NAME)
+        .with_description(
+            "Run a Service Fabric PowerShell command in the current session and return its output",
+        )
+        .with_schema(json!({
+            "type": "object",
+            "properties": {
+                "reason": {
+                    "type": "string",
+                    "description": "Why this command is needed"
+                },
+                "command": {
+                    "type": "string",
+                    "description": "The PowerShell command to run, e.g. Get-ServiceFabricClusterHealth"
+                },
+                "json": {
+                    "type": "boolean",
+                    "description": "Return the output objects as json instead of formatted text. Use it for structured data like health states or node lists"
+                }
+            },
+            "required": ["reason", "command"]
+        }))
+}
 
 pub struct AiConnection {
     pub client: Client,
+    pub config: AiConfig,
 }
 
 impl AiConnection {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        // -- Build an auth_resolver and the AdapterConfig
-        let auth_resolver = AuthResolver::from_resolver_fn(
-            |model_iden: ModelIden| -> Result<Option<AuthData>, genai::resolver::Error> {
-                let ModelIden {
-                    adapter_kind,
-                    model_name,
-                } = model_iden;
-                tracing::info!(
-                    "\n>> Custom auth provider for {adapter_kind} (model: {model_name}) <<"
-                );
-
-                // This will cause it to fail if any model is not an GEMINI_API_KEY
-                let key = std::env::var("GEMINI_API_KEY").map_err(|_| {
-                    genai::resolver::Error::ApiKeyEnvNotFound {
-                        env_name: "GEMINI_API_KEY".to_string(),
-                    }
-                })?;
-                Ok(Some(AuthData::from_single(key)))
+    pub fn new(config: AiConfig) -> Result<Self> {
+        config.validate().map_err(Error::Config)?;
+        tracing::info!(
+            "Using provider {:?} with model {}",
+            config.provider,
+            config.model()
+        );
+
+        // -- Build a service target resolver for the configured provider
+        let target_config = config.clone();
+        let target_resolver = ServiceTargetResolver::from_resolver_fn(
+            move |target: ServiceTarget| -> genai::resolver::Result<ServiceTarget> {
+                Ok(target_config.resolve_target(target))
             },
         );
 
         // -- Build the new client with this adapter_config
-        let client = Client::builder().with_auth_resolver(auth_resolver).build();
+        let client = Client::builder()
+            .with_service_target_resolver(target_resolver)
+            .build();
 
-        Ok(AiConnection { client })
+        Ok(AiConnection { client, config })
     }
 
     // pub async fn run_user_prompt(&self, command: &str) -> Result<String, Box<dyn std::error::Error>> {
@@ -65,81 +117,620 @@ impl AiConnection {
     //     Ok(())
     // }
 
-    pub fn create_chat(&self) -> AiChat {
-        // let tool = Tool::new("ServiceFabric Powershell")
-        //     .with_description("Run a Service Fabric Powershell command");
-
-        // let tools = vec![tool];
+    /// Create a chat running commands in a new powershell session.
+    pub fn create_chat(&self) -> Result<AiChat> {
+        Ok(self.create_chat_with_backend(Box::new(PwshSession::new()?)))
+    }
 
+    pub fn create_chat_with_backend(&self, backend: Box<dyn CommandBackend>) -> AiChat {
         // Create the chat request with the system prompt and tools
-        let req = ChatRequest::default().with_system(SYSTEM_PROMPT);
-        //.with_tools(tools);
+        let req = ChatRequest::default()
+            .with_system(SYSTEM_PROMPT)
+            .with_tools(vec![pwsh_tool(), context::read_output_tool()]);
         AiChat {
             req,
             client: self.client.clone(),
-            pwsh_session: PwshSession::new().expect("cannot open powershell session"),
+            model: self.config.model().to_string(),
+            options: self.config.chat_options(),
+            backend,
             pending_ps_commands: VecDeque::new(),
             pending_ps_commands_results: VecDeque::new(),
             pending_user_input: VecDeque::new(),
+            policy: Policy::default(),
+            session_approved_cmdlets: HashMap::new(),
+            audit: None,
+            approval_mode: ApprovalMode::Prompt,
+            interactive: true,
+            last_answer: String::new(),
+            commands: Vec::new(),
+            session: None,
+            context: ContextConfig::default(),
+            outputs: Vec::new(),
+            limits: LoopConfig::default(),
+            retry: RetryConfig::default(),
+            turn_steps: 0,
+            turn_results: Vec::new(),
         }
     }
 }
 
+/// A powershell command requested by the model.
+#[derive(Debug, Clone)]
+struct PendingCommand {
+    /// Set when the command came from a native tool call,
+    /// so the result is sent back as a tool response.
+    /// None for commands scraped from `tool_code` blocks.
+    call_id: Option<String>,
+    command: String,
+    /// Run the command in json output mode.
+    json: bool,
+}
+
 pub struct AiChat {
     req: ChatRequest,
     client: Client,
-    pwsh_session: PwshSession,
-    pending_ps_commands: VecDeque<String>,
-    pending_ps_commands_results: VecDeque<(String, String)>,
+    model: String,
+    options: ChatOptions,
+    backend: Box<dyn CommandBackend>,
+    pending_ps_commands: VecDeque<PendingCommand>,
+    pending_ps_commands_results: VecDeque<(PendingCommand, String)>,
     pending_user_input: VecDeque<String>,
+    policy: Policy,
+    /// Lowercase cmdlet names the user approved for the rest of the session, with the highest
+    /// risk approved. Explicit policy `ask` rules still prompt for them.
+    session_approved_cmdlets: HashMap<String, RiskLevel>,
+    audit: Option<Arc<AuditLog>>,
+    approval_mode: ApprovalMode,
+    /// Print the model's answers and notices to stdout as they come.
+    /// Otherwise notices go to stderr and answers are only returned.
+    interactive: bool,
+    /// Text of the model's last response.
+    last_answer: String,
+    /// Every command handled in this chat.
+    commands: Vec<CommandRecord>,
+    /// Where the conversation is saved after each step.
+    session: Option<(SessionStore, Session)>,
+    context: ContextConfig,
+    /// Full command outputs that were truncated, the model reads them by 1-based id.
+    outputs: Vec<String>,
+    limits: LoopConfig,
+    retry: RetryConfig,
+    /// Model responses with commands since the last question or continue.
+    turn_steps: usize,
+    /// Commands and results since the last question or continue, to detect repeats.
+    turn_results: Vec<(String, String)>,
+}
+
+pub const DEFAULT_MAX_STEPS: usize = 20;
+pub const DEFAULT_MAX_REPEATS: usize = 3;
+
+/// Limits on how long the model may run commands before answering.
+#[derive(Debug, Clone, clap::Args)]
+pub struct LoopConfig {
+    /// Max model responses with commands per question before the agent stops and asks to continue
+    #[arg(long, env = "SFCTL_AI_MAX_STEPS", default_value_t = DEFAULT_MAX_STEPS, global = true)]
+    pub max_steps: usize,
+
+    /// Stop when the same command ran this many times with the same result in one question
+    #[arg(long, env = "SFCTL_AI_MAX_REPEATS", default_value_t = DEFAULT_MAX_REPEATS, global = true)]
+    pub max_repeats: usize,
+}
+
+impl Default for LoopConfig {
+    fn default() -> Self {
+        LoopConfig {
+            max_steps: DEFAULT_MAX_STEPS,
+            max_repeats: DEFAULT_MAX_REPEATS,
+        }
+    }
+}
+
+/// Why the agent stopped before the model gave an answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model used all the steps.
+    StepLimit(usize),
+    /// The model kept running the same command with the same result.
+    Repeated {
+        steps: usize,
+        command: String,
+        times: usize,
+    },
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::StepLimit(steps) => {
+                write!(f, "Stopped after {steps} steps without a final answer")
+            }
+            StopReason::Repeated {
+                steps,
+                command,
+                times,
+            } => write!(
+                f,
+                "Stopped after {steps} steps, the command `{command}` ran {times} times with the same result"
+            ),
+        }
+    }
+}
+
+/// A command handled by the chat, with its approval and outcome.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandRecord {
+    pub command: String,
+    pub approval: Approval,
+    pub outcome: Outcome,
+}
+
+/// Result of [`AiChat::ask`].
+#[derive(Debug, Clone)]
+pub struct AskResult {
+    /// The model's final answer.
+    pub answer: String,
+    /// The commands handled to answer.
+    pub commands: Vec<CommandRecord>,
+    /// Set when the agent was stopped before the model finished.
+    pub stopped: Option<StopReason>,
+}
+
+impl AskResult {
+    /// Whether the agent was stopped or some commands were not run because they were
+    /// denied, declined or skipped, so the answer may be incomplete.
+    pub fn is_incomplete(&self) -> bool {
+        self.stopped.is_some() || self.commands.iter().any(|c| c.outcome == Outcome::NotRun)
+    }
 }
 
 impl AiChat {
+    /// Set the policy deciding which commands run, need approval or are denied.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// Set how commands that need approval are handled.
+    pub fn set_approval_mode(&mut self, mode: ApprovalMode) {
+        self.approval_mode = mode;
+    }
+
+    /// Print answers and notices to stdout, see [`AiChat::ask`] for the non interactive use.
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// Set the limits on how long the model may run commands.
+    pub fn set_loop_config(&mut self, config: LoopConfig) {
+        self.limits = config;
+    }
+
+    /// Set how failed model requests are retried.
+    pub fn set_retry_config(&mut self, config: RetryConfig) {
+        self.retry = config;
+    }
+
+    /// Start counting steps and repeats again, e.g. on a new question.
+    fn reset_turn(&mut self) {
+        self.turn_steps = 0;
+        self.turn_results.clear();
+    }
+
+    /// Whether the agent must stop before running the pending commands.
+    pub fn check_limits(&self) -> Option<StopReason> {
+        if self.pending_ps_commands.is_empty() && self.pending_ps_commands_results.is_empty() {
+            return None;
+        }
+        if self.turn_steps > self.limits.max_steps {
+            return Some(StopReason::StepLimit(self.limits.max_steps));
+        }
+        for (i, (command, result)) in self.turn_results.iter().enumerate() {
+            let times = 1 + self.turn_results[i + 1..]
+                .iter()
+                .filter(|(c, r)| c == command && r == result)
+                .count();
+            if times >= self.limits.max_repeats {
+                return Some(StopReason::Repeated {
+                    steps: self.turn_steps - 1,
+                    command: command.clone(),
+                    times,
+                });
+            }
+        }
+        None
+    }
+
+    /// Answer the pending commands without running them and record it in the history,
+    /// so the conversation can go on with a new question.
+    pub fn stop_turn(&mut self, reason: &StopReason) {
+        tracing::info!("{}", reason);
+        self.drop_pending(&format!("Not run, the agent was stopped: {reason}"));
+        self.req = self.req.clone().append_message(ChatMessage::system(format!(
+            "{reason}. The pending commands were not run."
+        )));
+        self.reset_turn();
+        self.save_session();
+    }
+
+    /// Give up the current question after an error. The pending commands are answered
+    /// without running them, so the conversation can go on with a new question.
+    pub fn abort_turn(&mut self, error: &Error) {
+        tracing::info!("Turn aborted: {}", error);
+        self.drop_pending(&format!("Not run, the request failed: {error}"));
+        self.reset_turn();
+        self.save_session();
+    }
+
+    /// Answer the pending commands with the note and move the results to the history.
+    fn drop_pending(&mut self, note: &str) {
+        while let Some(pending) = self.pending_ps_commands.pop_front() {
+            self.pending_ps_commands_results
+                .push_back((pending, note.to_string()));
+        }
+        self.append_results();
+    }
+
+    /// Set the limits on what is sent to the model.
+    pub fn set_context_config(&mut self, config: ContextConfig) {
+        self.context = config;
+    }
+
+    /// Save the conversation to the store after each step. A resumed session
+    /// continues with its history and command records.
+    pub fn set_session(&mut self, store: SessionStore, session: Session) {
+        self.req.messages = session.resumable_messages();
+        self.commands = session.commands.clone();
+        self.outputs = session.outputs.clone();
+        self.session = Some((store, session));
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        self.session
+            .as_ref()
+            .map(|(_, session)| session.id.as_str())
+    }
+
+    fn save_session(&mut self) {
+        let Some((store, session)) = &mut self.session else {
+            return;
+        };
+        session.messages = self.req.messages.clone();
+        session.commands = self.commands.clone();
+        session.outputs = self.outputs.clone();
+        session.updated = chrono::Local::now().to_rfc3339();
+        session.user = crate::audit::current_user();
+        if let Some(endpoint) = self.backend.endpoint() {
+            session.cluster = Some(endpoint);
+        }
+        if session.title.is_empty()
+            && let Some(question) = session
+                .messages
+                .iter()
+                .find(|m| matches!(m.role, ChatRole::User))
+                .and_then(|m| m.content.text())
+        {
+            session.title = question.lines().next().unwrap_or_default().to_string();
+        }
+        if let Err(e) = store.save(session) {
+            tracing::info!("Failed to save session {}: {e}", session.id);
+        }
+    }
+
+    /// Tell the user about a command, on stderr when not interactive to keep stdout for the answer.
+    fn notify(&self, message: &str) {
+        if self.interactive {
+            println!("{message}");
+        } else {
+            eprintln!("{message}");
+        }
+    }
+
     pub async fn process_ps_command(&mut self) {
-        while let Some(code) = self.pending_ps_commands.pop_front() {
-            let code = PwshSession::trim_command(&code);
-            // classify the command
-            let kind = crate::cmd_parse::classify_cmd(&code);
-            let need_ack = !matches!(kind, CmdKind::Read);
-
-            // ask user permission to run the command
-            let ack = if need_ack {
-                crate::ack::ack_command(&code).await
+        while let Some(pending) = self.pending_ps_commands.pop_front() {
+            if self.process_one_command(pending).await.0 == AckChoice::SkipAll {
+                while let Some(skipped) = self.pending_ps_commands.pop_front() {
+                    let mut entry = self.audit_entry(
+                        &skipped.command,
+                        &self.policy.evaluate(&skipped.command).describe(),
+                        Approval::Skipped,
+                        Outcome::NotRun,
+                    );
+                    entry.risk = crate::catalog::assess(&skipped.command).level.to_string();
+                    self.record_audit(entry);
+                    self.commands.push(CommandRecord {
+                        command: skipped.command.clone(),
+                        approval: Approval::Skipped,
+                        outcome: Outcome::NotRun,
+                    });
+                    let content = format!("User skipped the command: {}", skipped.command);
+                    self.pending_ps_commands_results
+                        .push_back((skipped, content));
+                }
+            }
+        }
+    }
+
+    /// Run a command given by the user directly, with the same policy, approval and audit
+    /// as the model's commands. Returns the outcome and the output.
+    pub async fn run_command(&mut self, command: &str, json: bool) -> (Outcome, String) {
+        let pending = PendingCommand {
+            call_id: None,
+            command: command.to_string(),
+            json,
+        };
+        let outcome = self.process_one_command(pending).await.1;
+        let (_, content) = self
+            .pending_ps_commands_results
+            .pop_back()
+            .expect("the command result was queued");
+        (outcome, content)
+    }
+
+    /// Check, run and audit a command, then queue its result for the model.
+    async fn process_one_command(&mut self, pending: PendingCommand) -> (AckChoice, Outcome) {
+        let mut code = PwshSession::trim_command(&pending.command);
+        // notes for the model, sent before the command output
+        let mut notes = Vec::new();
+
+        // assess the risk of the command and check the policy
+        let assessment = crate::catalog::assess(&code);
+        let mut decision = self.policy.evaluate(&code);
+        tracing::info!("Command risk: {:?}, policy: {:?}", assessment, decision);
+
+        // the read-only mode denies anything that is not a read, whatever the policy says
+        let mut denied_by_mode = self.approval_mode == ApprovalMode::ReadOnly
+            && decision.action != PolicyAction::Deny
+            && assessment.level.needs_ack();
+
+        // ask user permission to run the command
+        let mut choice = AckChoice::Once;
+        let mut approval = Approval::Auto;
+        if decision.action == PolicyAction::Ask && !denied_by_mode {
+            if decision.rule.is_none() && self.is_session_approved(&code) {
+                tracing::info!("Command approved for the session: {}", code);
+                approval = Approval::Session;
             } else {
-                true
-            };
-            let tools_content = if !ack {
-                tracing::info!("User declined to run the command: {}", code);
-                println!("Please provide reason for declining:");
-                let reason = crate::ack::get_user_input().await;
-                tracing::info!("User reason for declining: {}", reason);
-                if !reason.is_empty() {
-                    self.pending_user_input.push_back(reason);
+                match self.approval_mode {
+                    ApprovalMode::Prompt => {
+                        if decision.rule.is_some() {
+                            println!("Approval required, {}", decision.describe());
+                        }
+                        choice = crate::ack::ack_command(&code, &assessment).await;
+                        approval = Approval::User;
+                    }
+                    // nobody to ask, explicit ask rules and destructive commands deny
+                    ApprovalMode::Auto => {
+                        denied_by_mode =
+                            decision.rule.is_some() || assessment.level == RiskLevel::Destructive
+                    }
+                    ApprovalMode::ReadOnly => denied_by_mode = true,
                 }
-                format!("User declined to run the command: {}", code)
+            }
+        }
+        match &choice {
+            AckChoice::Session => {
+                self.approve_for_session(&code);
+                approval = Approval::Session;
+            }
+            AckChoice::Decline => approval = Approval::Declined,
+            AckChoice::SkipAll => approval = Approval::Skipped,
+            AckChoice::Edit(edited) => {
+                approval = Approval::Edited;
+                tracing::info!("User edited the command {} to: {}", code, edited);
+                notes.push(format!("User edited the command to: {edited}"));
+                code = PwshSession::trim_command(edited);
+                // the policy still applies to the edited command
+                decision = self.policy.evaluate(&code);
+            }
+            _ => {}
+        }
+
+        let mut outcome = Outcome::NotRun;
+        // what repeat detection compares, the report without its duration
+        let mut repeat_key = None;
+        let started = Instant::now();
+        let tools_content = if decision.action == PolicyAction::Deny {
+            approval = Approval::Denied;
+            self.notify(&format!(
+                "Command denied, {}: {}",
+                decision.describe(),
+                code
+            ));
+            format!("Command denied, {}: {}", decision.describe(), code)
+        } else if denied_by_mode {
+            approval = Approval::Denied;
+            let reason = match decision.rule {
+                Some(_) => decision.describe(),
+                None => format!("{} risk", assessment.level),
+            };
+            self.notify(&format!(
+                "Command denied by the {} approval mode ({}): {}",
+                self.approval_mode, reason, code
+            ));
+            format!(
+                "Command denied by the {} approval mode ({}): {}",
+                self.approval_mode, reason, code
+            )
+        } else if choice == AckChoice::Decline {
+            tracing::info!("User declined to run the command: {}", code);
+            println!("Please provide reason for declining:");
+            let reason = crate::ack::get_user_input().await;
+            tracing::info!("User reason for declining: {}", reason);
+            if !reason.is_empty() {
+                self.pending_user_input.push_back(reason);
+            }
+            format!("User declined to run the command: {}", code)
+        } else if choice == AckChoice::SkipAll {
+            tracing::info!("User skipped all pending commands");
+            format!("User skipped the command: {}", code)
+        } else {
+            let result = if pending.json {
+                self.backend
+                    .run_command_json(code.as_str(), DEFAULT_JSON_DEPTH)
+                    .await
+                    .map(|v| match json_error(&v) {
+                        Some(_) => (Outcome::Failed, v.to_string()),
+                        None => (Outcome::Succeeded, v.to_string()),
+                    })
             } else {
-                self.pwsh_session
-                    .run_command(code.as_str())
+                self.backend
+                    .run_command_outcome(code.as_str())
                     .await
-                    .unwrap_or_else(|e| format!("Error running command: {e}"))
+                    .map(|res| {
+                        repeat_key = Some(
+                            CommandOutcome {
+                                duration: Duration::ZERO,
+                                ..res.clone()
+                            }
+                            .to_report(),
+                        );
+                        if res.is_success() {
+                            (Outcome::Succeeded, res.to_report())
+                        } else {
+                            (Outcome::Failed, res.to_report())
+                        }
+                    })
             };
-            tracing::info!("Tool Response: {}", tools_content);
-            self.pending_ps_commands_results
-                .push_back((code, tools_content));
+            let (res_outcome, content) =
+                result.unwrap_or_else(|e| (Outcome::Error, format!("Error running command: {e}")));
+            if res_outcome == Outcome::Failed {
+                tracing::info!("Command failed: {}", code);
+                if self.interactive {
+                    println!("Command failed: {}", code);
+                } else {
+                    eprintln!("Command failed: {}", code);
+                }
+            }
+            outcome = res_outcome;
+            content
+        };
+        self.commands.push(CommandRecord {
+            command: code.clone(),
+            approval,
+            outcome,
+        });
+        let mut entry = self.audit_entry(&code, &decision.describe(), approval, outcome);
+        entry.risk = crate::catalog::assess(&code).level.to_string();
+        if outcome != Outcome::NotRun {
+            entry = entry.with_duration(started.elapsed());
+        }
+        self.record_audit(entry);
+        // let the model know which rule applied, denials already say it
+        if decision.rule.is_some() && decision.action != PolicyAction::Deny && !denied_by_mode {
+            notes.push(format!("Policy: {}", decision.describe()));
+        }
+        let repeat_key = [
+            notes.as_slice(),
+            &[repeat_key.unwrap_or(tools_content.clone())],
+        ]
+        .concat()
+        .join("\n");
+        notes.push(tools_content);
+        let tools_content = notes.join("\n");
+        tracing::info!("Tool Response: {}", tools_content);
+        self.turn_results.push((code.clone(), repeat_key));
+        self.pending_ps_commands_results.push_back((
+            PendingCommand {
+                command: code,
+                ..pending
+            },
+            tools_content,
+        ));
+        self.save_session();
+        (choice, outcome)
+    }
+
+    /// Record every command in this audit log.
+    pub fn set_audit_log(&mut self, audit: Arc<AuditLog>) {
+        self.audit = Some(audit);
+    }
+
+    fn audit_entry(
+        &self,
+        command: &str,
+        policy: &str,
+        approval: Approval,
+        outcome: Outcome,
+    ) -> AuditEntry {
+        AuditEntry {
+            cluster: self.backend.endpoint(),
+            policy: policy.to_string(),
+            approval,
+            outcome,
+            ..AuditEntry::new("repl", command)
+        }
+    }
+
+    fn record_audit(&self, entry: AuditEntry) {
+        if let Some(audit) = &self.audit {
+            audit.record(entry);
         }
     }
 
-    pub async fn send_ps_result_to_chat(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Remember that the user approved the cmdlets of the command for this session,
+    /// up to the risk they had in it.
+    pub fn approve_for_session(&mut self, command: &str) {
+        for statement in crate::cmd_parse::statements(command) {
+            let level = crate::catalog::statement_risk(&statement);
+            if let Some(Token::Word(name)) = statement.first()
+                && level.needs_ack()
+            {
+                tracing::info!("Cmdlet approved for the session: {} ({} risk)", name, level);
+                let approved = self
+                    .session_approved_cmdlets
+                    .entry(name.to_lowercase())
+                    .or_insert(level);
+                *approved = (*approved).max(level);
+            }
+        }
+    }
+
+    /// Whether every command that needs approval in the script was approved for the session,
+    /// at its risk or higher. E.g. approving a node restart does not approve a forced one.
+    fn is_session_approved(&self, command: &str) -> bool {
+        let statements = crate::cmd_parse::statements(command);
+        !statements.is_empty()
+            && statements.iter().all(|statement| {
+                let level = crate::catalog::statement_risk(statement);
+                if !level.needs_ack() {
+                    return true;
+                }
+                match statement.first() {
+                    Some(Token::Word(name)) => self
+                        .session_approved_cmdlets
+                        .get(&name.to_lowercase())
+                        .is_some_and(|approved| *approved >= level),
+                    _ => false,
+                }
+            })
+    }
+
+    pub async fn send_ps_result_to_chat(&mut self) -> Result<()> {
         if self.pending_ps_commands_results.is_empty() {
             tracing::info!("No pending PowerShell command results to send.");
             return Ok(());
         }
-        while let Some((code, tool_response)) = self.pending_ps_commands_results.pop_front() {
-            self.req = self.req.clone().append_message(ChatMessage::system(format!(
-                "Tool call: ```\n{}\n```\n
+        self.append_results();
+        self.run_prompt().await
+    }
+
+    /// Move the command results and user input to the chat history.
+    fn append_results(&mut self) {
+        while let Some((cmd, tool_response)) = self.pending_ps_commands_results.pop_front() {
+            // only command outputs can be long, other results are notices or output parts
+            let tool_response = if cmd.command.is_empty() {
+                tool_response
+            } else {
+                self.fit_output(tool_response)
+            };
+            let msg = match cmd.call_id {
+                Some(call_id) => ChatMessage::from(ToolResponse::new(call_id, tool_response)),
+                None => ChatMessage::system(format!(
+                    "Tool call: ```\n{}\n```\n
                 Tool response: ```\n{}\n```",
-                code, tool_response
-            )));
+                    cmd.command, tool_response
+                )),
+            };
+            self.req = self.req.clone().append_message(msg);
         }
         while let Some(reason) = self.pending_user_input.pop_front() {
             self.req = self
@@ -147,62 +738,216 @@ impl AiChat {
                 .clone()
                 .append_message(ChatMessage::user(reason.to_string()));
         }
-        self.run_prompt().await
     }
 
-    pub async fn run_prompt(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.req = self.req.clone();
-
-        let mut chat_stream = self
-            .client
-            .exec_chat_stream(MODEL, self.req.clone(), None)
-            .await?;
+    /// Truncate a long command output, keeping the full output for [`READ_OUTPUT_TOOL_NAME`].
+    fn fit_output(&mut self, output: String) -> String {
+        let id = self.outputs.len() + 1;
+        match context::truncate_output(&output, self.context.max_output_chars(), id) {
+            Some(truncated) => {
+                tracing::info!("Truncated output {} of {} characters", id, output.len());
+                self.outputs.push(output);
+                truncated
+            }
+            None => output,
+        }
+    }
 
-        tracing::info!("--- Capturing tool calls ---");
-        let mut chunks: Vec<String> = vec![];
-        while let Some(result) = chat_stream.stream.next().await {
-            match result? {
-                ChatStreamEvent::Start => {
-                    tracing::info!("Stream started");
-                }
-                ChatStreamEvent::Chunk(chunk) => {
-                    chunks.push(chunk.content);
-                }
-                ChatStreamEvent::ToolCallChunk(tool_chunk) => {
-                    panic!("Tool call chunk not supported: {:?}", tool_chunk);
-                }
-                ChatStreamEvent::ReasoningChunk(chunk) => {
-                    tracing::info!("Reasoning: {}", chunk.content);
-                }
-                ChatStreamEvent::End(_end) => {
-                    tracing::info!("Stream ended");
-                }
+    /// Answer a [`READ_OUTPUT_TOOL_NAME`] call.
+    fn read_output(&self, args: &ReadOutputArgs) -> String {
+        match args.id.checked_sub(1).and_then(|i| self.outputs.get(i)) {
+            Some(output) => {
+                context::read_output(output, args.id, args, self.context.max_output_chars())
             }
+            None => format!("No saved output with id {}", args.id),
         }
+    }
 
-        let chunks = chunks.join("");
+    /// Summarize the older turns when the history is over the context budget.
+    async fn fit_context(&mut self) {
+        let used = context::request_tokens(&self.req);
+        if used <= self.context.context_budget {
+            return;
+        }
+        let split = context::summary_split(&self.req.messages, self.context.context_budget / 2);
+        if split == 0 {
+            tracing::info!(
+                "History of {} tokens is over budget but cannot be summarized",
+                used
+            );
+            return;
+        }
+        let old: Vec<ChatMessage> = self.req.messages.drain(..split).collect();
+        tracing::info!("Summarizing {} messages of {} tokens", old.len(), used);
+        let summary = match summarize(&self.client, &self.model, &self.options, &old).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                tracing::info!("Failed to summarize the history: {e}");
+                format!(
+                    "{} earlier messages were removed to fit the context window.",
+                    old.len()
+                )
+            }
+        };
+        self.req.messages.insert(
+            0,
+            ChatMessage::system(format!("Summary of the earlier conversation:\n{summary}")),
+        );
+        self.notify("Summarized the earlier conversation to fit the context window.");
+    }
+
+    pub async fn run_prompt(&mut self) -> Result<()> {
+        self.fit_context().await;
+        self.save_session();
+
+        let (chunks, tool_calls) = self.exec_with_retry().await?;
         tracing::info!("Captured chunks: {}", chunks);
-        if chunks.is_empty() {
-            panic!("No chunks captured, cannot continue.");
+        if chunks.is_empty() && tool_calls.is_empty() {
+            return Err(Error::Parse(
+                "the model returned an empty response".to_string(),
+            ));
         }
 
-        let code_blocks = extract_code_blocks(&chunks);
+        if !tool_calls.is_empty() {
+            self.add_tool_calls(tool_calls);
+        } else {
+            // keep the answer in the history for follow up questions
+            self.req = self
+                .req
+                .clone()
+                .append_message(ChatMessage::assistant(chunks.clone()));
+        }
 
+        // Fallback for models without tool support.
+        let code_blocks = extract_code_blocks(&chunks);
         if code_blocks.is_empty() {
             tracing::info!("No code blocks captured.");
         } else {
-            self.pending_ps_commands.extend(code_blocks);
+            self.pending_ps_commands
+                .extend(code_blocks.into_iter().map(|command| PendingCommand {
+                    call_id: None,
+                    command,
+                    json: false,
+                }));
         }
+
+        if self.has_pending_commands() {
+            self.turn_steps += 1;
+        }
+
         let text_blocks = crate::model::extract_text_blocks(&chunks);
-        if text_blocks.is_empty() {
-            tracing::info!("No text blocks captured.");
+        let text = if !text_blocks.is_empty() {
+            text_blocks.join("\n")
+        } else if !chunks.trim().is_empty() && !chunks.contains("```tool_code") {
+            // Models using native tool calls usually answer in plain text.
+            chunks.trim().to_string()
         } else {
-            println!("{}", text_blocks.join("\n"));
+            tracing::info!("No text blocks captured.");
+            String::new()
+        };
+        if !text.is_empty() {
+            if self.interactive {
+                println!("{text}");
+            }
+            self.last_answer = text;
         }
+        self.save_session();
         Ok(())
     }
 
-    pub async fn get_user_input(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+    /// Send the chat request, retrying rate limits and transient failures.
+    /// Returns the text and the tool calls of the response.
+    async fn exec_with_retry(&mut self) -> Result<(String, Vec<ToolCall>)> {
+        let mut attempt = 0;
+        loop {
+            let error =
+                match stream_response(&self.client, &self.model, &self.req, &self.options).await {
+                    Ok(response) => return Ok(response),
+                    Err(e) => e,
+                };
+            attempt += 1;
+            let Some(transient) =
+                retry::transient(&error).filter(|_| attempt <= self.retry.max_retries)
+            else {
+                return Err(error.into());
+            };
+            let delay = self.retry.delay(attempt, transient.retry_after);
+            tracing::info!(
+                "Model request failed, retry {} in {:?}: {}",
+                attempt,
+                delay,
+                error
+            );
+            self.notify(&format!(
+                "Model request failed ({}), retrying in {:.1}s ({}/{})",
+                transient.reason,
+                delay.as_secs_f32(),
+                attempt,
+                self.retry.max_retries
+            ));
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Record the tool calls in the chat history and queue the commands to run.
+    fn add_tool_calls(&mut self, tool_calls: Vec<ToolCall>) {
+        // The tool responses must follow the assistant message with the tool calls.
+        self.req = self.req.clone().append_message(tool_calls.clone());
+        for tool_call in tool_calls {
+            let call_id = Some(tool_call.call_id.clone());
+            if tool_call.fn_name == READ_OUTPUT_TOOL_NAME {
+                let content = match serde_json::from_value::<ReadOutputArgs>(tool_call.fn_arguments)
+                {
+                    Ok(args) => self.read_output(&args),
+                    Err(e) => format!("Invalid arguments for {READ_OUTPUT_TOOL_NAME}: {e}"),
+                };
+                self.pending_ps_commands_results.push_back((
+                    PendingCommand {
+                        call_id,
+                        command: String::new(),
+                        json: false,
+                    },
+                    content,
+                ));
+                continue;
+            }
+            if tool_call.fn_name != PWSH_TOOL_NAME {
+                tracing::info!("Unknown tool requested: {}", tool_call.fn_name);
+                self.pending_ps_commands_results.push_back((
+                    PendingCommand {
+                        call_id,
+                        command: String::new(),
+                        json: false,
+                    },
+                    format!("Unknown tool: {}", tool_call.fn_name),
+                ));
+                continue;
+            }
+            match get_action_from_tool_call(&tool_call) {
+                Ok(action) => {
+                    tracing::info!("Tool call reason: {}", action.reason);
+                    self.pending_ps_commands.push_back(PendingCommand {
+                        call_id,
+                        command: action.command,
+                        json: action.json,
+                    });
+                }
+                Err(e) => {
+                    tracing::info!("Invalid tool call arguments: {e}");
+                    self.pending_ps_commands_results.push_back((
+                        PendingCommand {
+                            call_id,
+                            command: String::new(),
+                            json: false,
+                        },
+                        format!("Invalid arguments for {PWSH_TOOL_NAME}: {e}"),
+                    ));
+                }
+            }
+        }
+    }
+
+    pub async fn get_user_input(&mut self) -> Result<bool> {
         println!(">");
         let input = crate::ack::get_user_input().await;
         if input.is_empty() {
@@ -214,6 +959,7 @@ impl AiChat {
                 .clone()
                 .append_message(ChatMessage::user(input.clone()));
         }
+        self.reset_turn();
 
         Ok(true)
     }
@@ -222,30 +968,952 @@ impl AiChat {
         !self.pending_ps_commands.is_empty() || !self.pending_ps_commands_results.is_empty()
     }
 
-    pub async fn run_user_prompt_loop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let _print_options = PrintChatStreamOptions::from_print_events(false);
-        loop {
-            if !self.has_pending_commands() {
-                tracing::info!("No pending commands, waiting for user input.");
-                if self.get_user_input().await? {
-                    self.run_prompt().await?;
+    /// Answer a single question, running the model's commands until it stops asking for more.
+    pub async fn ask(&mut self, question: &str) -> Result<AskResult> {
+        let first_command = self.commands.len();
+        self.last_answer.clear();
+        self.reset_turn();
+        self.req = self.req.clone().append_message(ChatMessage::user(question));
+        self.run_prompt().await?;
+        let mut stopped = None;
+        while self.has_pending_commands() {
+            if let Some(reason) = self.check_limits() {
+                self.notify(&reason.to_string());
+                self.stop_turn(&reason);
+                // one more chance to answer with what was found so far
+                self.last_answer.clear();
+                self.req = self.req.clone().append_message(ChatMessage::system(
+                    "Answer with what you found so far, do not run more commands.",
+                ));
+                self.run_prompt().await?;
+                if self.has_pending_commands() {
+                    self.stop_turn(&reason);
                 }
+                stopped = Some(reason);
+                break;
             }
-
             self.process_ps_command().await;
             self.send_ps_result_to_chat().await?;
+        }
+        Ok(AskResult {
+            answer: self.last_answer.clone(),
+            commands: self.commands[first_command..].to_vec(),
+            stopped,
+        })
+    }
 
-            if self.pending_ps_commands.is_empty() {
-                self.req = self.req.clone().append_message(ChatMessage::system(
-                    "All commands executed. Please give the final response if any.",
-                ));
+    /// Chat until cancelled. Errors are reported and end the current question only.
+    pub async fn run_user_prompt_loop(&mut self) {
+        loop {
+            if let Err(e) = self.run_user_prompt_step().await {
+                println!("Error: {e}");
+                self.abort_turn(&e);
+            }
+        }
+    }
+
+    /// Get the next question or continue with the pending commands.
+    async fn run_user_prompt_step(&mut self) -> Result<()> {
+        let _print_options = PrintChatStreamOptions::from_print_events(false);
+        if !self.has_pending_commands() {
+            tracing::info!("No pending commands, waiting for user input.");
+            if self.get_user_input().await? {
                 self.run_prompt().await?;
             }
-            tracing::info!("pending commands: {:?}", self.pending_ps_commands);
-            tracing::info!(
-                "pending commands results: {:?}",
-                self.pending_ps_commands_results
+        }
+
+        if let Some(reason) = self.check_limits() {
+            println!("{reason}.");
+            println!(
+                "Continue for another {} steps? (yes/no)",
+                self.limits.max_steps
+            );
+            let answer = crate::ack::get_user_input().await;
+            if matches!(crate::ack::parse_ack_choice(&answer), AckChoice::Once) {
+                tracing::info!("User continued after: {}", reason);
+                self.reset_turn();
+                // the current batch counts as the first step
+                self.turn_steps = 1;
+            } else {
+                self.stop_turn(&reason);
+                return Ok(());
+            }
+        }
+
+        self.process_ps_command().await;
+        self.send_ps_result_to_chat().await?;
+
+        if self.pending_ps_commands.is_empty() {
+            self.req = self.req.clone().append_message(ChatMessage::system(
+                "All commands executed. Please give the final response if any.",
+            ));
+            self.run_prompt().await?;
+        }
+        tracing::info!("pending commands: {:?}", self.pending_ps_commands);
+        tracing::info!(
+            "pending commands results: {:?}",
+            self.pending_ps_commands_results
+        );
+        Ok(())
+    }
+}
+
+/// Stream the model response, returning its text and tool calls.
+async fn stream_response(
+    client: &Client,
+    model: &str,
+    req: &ChatRequest,
+    options: &ChatOptions,
+) -> genai::Result<(String, Vec<ToolCall>)> {
+    // Tool call chunks may be partial, so use the captured ones at the end of the stream.
+    let options = options.clone().with_capture_tool_calls(true);
+    let mut chat_stream = client
+        .exec_chat_stream(model, req.clone(), Some(&options))
+        .await?;
+
+    tracing::info!("--- Capturing tool calls ---");
+    let mut chunks: Vec<String> = vec![];
+    let mut tool_calls: Vec<ToolCall> = vec![];
+    while let Some(result) = chat_stream.stream.next().await {
+        match result? {
+            ChatStreamEvent::Start => {
+                tracing::info!("Stream started");
+            }
+            ChatStreamEvent::Chunk(chunk) => {
+                chunks.push(chunk.content);
+            }
+            ChatStreamEvent::ToolCallChunk(tool_chunk) => {
+                tracing::info!("Tool call chunk: {:?}", tool_chunk.tool_call);
+            }
+            ChatStreamEvent::ReasoningChunk(chunk) => {
+                tracing::info!("Reasoning: {}", chunk.content);
+            }
+            ChatStreamEvent::End(end) => {
+                tracing::info!("Stream ended");
+                if let Some(captured) = end.captured_tool_calls() {
+                    tool_calls.extend(captured.into_iter().cloned());
+                }
+            }
+        }
+    }
+    Ok((chunks.join(""), tool_calls))
+}
+
+/// Ask the model for a summary of the messages.
+async fn summarize(
+    client: &Client,
+    model: &str,
+    options: &ChatOptions,
+    messages: &[ChatMessage],
+) -> Result<String> {
+    let req = ChatRequest::default()
+        .with_system(SUMMARY_PROMPT)
+        .append_message(ChatMessage::user(context::render_transcript(messages)));
+    let mut chat_stream = client.exec_chat_stream(model, req, Some(options)).await?;
+    let mut summary = String::new();
+    while let Some(result) = chat_stream.stream.next().await {
+        if let ChatStreamEvent::Chunk(chunk) = result? {
+            summary.push_str(&chunk.content);
+        }
+    }
+    if summary.trim().is_empty() {
+        return Err(Error::Parse(
+            "the model returned an empty summary".to_string(),
+        ));
+    }
+    Ok(summary.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ScriptedBackend;
+    use crate::provider::Provider;
+    use futures::future::BoxFuture;
+    use genai::chat::MessageContent;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_util::sync::CancellationToken;
+
+    fn test_chat(backend: ScriptedBackend) -> AiChat {
+        let conn = AiConnection::new(AiConfig::default()).unwrap();
+        conn.create_chat_with_backend(Box::new(backend))
+    }
+
+    /// Minimal OpenAI compatible server streaming the given responses in order,
+    /// one per chat request. Returns the base url.
+    async fn mock_llm(responses: Vec<Vec<serde_json::Value>>) -> String {
+        mock_http(responses.into_iter().map(sse_response).collect()).await
+    }
+
+    /// Server answering each request with the next raw http response. Returns the base url.
+    async fn mock_http(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                read_request(&mut stream).await;
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{addr}/v1/")
+    }
+
+    /// Http response streaming the deltas as chat completion chunks.
+    fn sse_response(deltas: Vec<serde_json::Value>) -> String {
+        let mut body = String::new();
+        for delta in deltas {
+            let chunk = json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion.chunk",
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": null }]
+            });
+            body.push_str(&format!("data: {chunk}\n\n"));
+        }
+        body.push_str("data: [DONE]\n\n");
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Read a whole http request, the chat requests are larger than a single read.
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|l| {
+                        l.to_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|v| v.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    return text;
+                }
+            }
+            if n == 0 {
+                return text;
+            }
+        }
+    }
+
+    fn mock_tool_call(id: &str, command: &str) -> serde_json::Value {
+        json!({
+            "role": "assistant",
+            "tool_calls": [{
+                "index": 0,
+                "id": id,
+                "type": "function",
+                "function": {
+                    "name": PWSH_TOOL_NAME,
+                    "arguments": json!({ "reason": "check", "command": command }).to_string()
+                }
+            }]
+        })
+    }
+
+    fn mock_chat(base_url: String, backend: impl CommandBackend + 'static) -> AiChat {
+        let conn = AiConnection::new(AiConfig {
+            provider: Provider::Ollama,
+            model: Some("mock".to_string()),
+            base_url: Some(base_url),
+            api_key_env: None,
+        })
+        .unwrap();
+        conn.create_chat_with_backend(Box::new(backend))
+    }
+
+    #[tokio::test]
+    async fn test_process_tool_calls() {
+        let backend = ScriptedBackend::new()
+            .with_text(
+                "Get-ServiceFabricClusterHealth",
+                "AggregatedHealthState : Ok",
+            )
+            .with_json(
+                "Get-ServiceFabricNode",
+                serde_json::json!([{ "NodeName": "_Node_0", "HealthState": "Ok" }]),
             );
+        let commands = backend.commands();
+        let mut chat = test_chat(backend);
+
+        chat.add_tool_calls(vec![
+            ToolCall {
+                call_id: "call_1".to_string(),
+                fn_name: PWSH_TOOL_NAME.to_string(),
+                fn_arguments: json!({
+                    "reason": "check health",
+                    "command": "Get-ServiceFabricClusterHealth"
+                }),
+            },
+            ToolCall {
+                call_id: "call_2".to_string(),
+                fn_name: PWSH_TOOL_NAME.to_string(),
+                fn_arguments: json!({
+                    "reason": "list nodes",
+                    "command": "Get-ServiceFabricNode",
+                    "json": true
+                }),
+            },
+            ToolCall {
+                call_id: "call_3".to_string(),
+                fn_name: "other_tool".to_string(),
+                fn_arguments: json!({}),
+            },
+        ]);
+        assert!(chat.has_pending_commands());
+        chat.process_ps_command().await;
+
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec!["Get-ServiceFabricClusterHealth", "Get-ServiceFabricNode"]
+        );
+        let results = chat
+            .pending_ps_commands_results
+            .iter()
+            .map(|(cmd, res)| (cmd.call_id.clone().unwrap(), res.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, "call_3");
+        assert_eq!(results[0].1, "Unknown tool: other_tool");
+        assert_eq!(results[1].0, "call_1");
+        assert!(results[1].1.starts_with("Status: Succeeded"));
+        assert!(results[1].1.contains("AggregatedHealthState : Ok"));
+        assert_eq!(results[2].0, "call_2");
+        assert_eq!(
+            results[2].1,
+            r#"[{"HealthState":"Ok","NodeName":"_Node_0"}]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_failed_command() {
+        let mut chat = test_chat(ScriptedBackend::new());
+        chat.pending_ps_commands.push_back(PendingCommand {
+            call_id: None,
+            command: "Get-ServiceFabricApplication".to_string(),
+            json: false,
+        });
+        chat.process_ps_command().await;
+
+        let (cmd, res) = chat.pending_ps_commands_results.pop_front().unwrap();
+        assert_eq!(cmd.command, "Get-ServiceFabricApplication");
+        assert!(res.starts_with("Status: Failed"), "{res}");
+        assert!(res.contains("CommandNotFoundException"));
+
+        // json output reports failures as an error object
+        chat.pending_ps_commands.push_back(PendingCommand {
+            call_id: None,
+            command: "Get-ServiceFabricApplication".to_string(),
+            json: true,
+        });
+        chat.process_ps_command().await;
+        let (_, res) = chat.pending_ps_commands_results.pop_front().unwrap();
+        assert!(res.contains("\"Error\""), "{res}");
+        let outcomes = chat.commands.iter().map(|c| c.outcome).collect::<Vec<_>>();
+        assert_eq!(outcomes, vec![Outcome::Failed, Outcome::Failed]);
+    }
+
+    #[tokio::test]
+    async fn test_session_approval() {
+        let backend = ScriptedBackend::new()
+            .with_text("Remove-ServiceFabricApplication", "")
+            .with_text("Get-ServiceFabricApplication", "");
+        let commands = backend.commands();
+        let mut chat = test_chat(backend);
+        assert!(!chat.is_session_approved("Remove-ServiceFabricApplication fabric:/App"));
+
+        chat.approve_for_session(
+            "Get-ServiceFabricApplication | Remove-ServiceFabricApplication -Force",
+        );
+        assert_eq!(
+            chat.session_approved_cmdlets,
+            HashMap::from([(
+                "remove-servicefabricapplication".to_string(),
+                RiskLevel::Destructive
+            )])
+        );
+        assert!(chat.is_session_approved("remove-servicefabricapplication fabric:/Other"));
+        assert!(!chat.is_session_approved(
+            "Remove-ServiceFabricApplication fabric:/App; Restart-ServiceFabricNode n1"
+        ));
+
+        // approving a cmdlet at one risk does not approve it at a higher one
+        chat.approve_for_session("Restart-ServiceFabricNode -NodeName n1");
+        assert!(chat.is_session_approved("Restart-ServiceFabricNode -NodeName n2"));
+        assert!(!chat.is_session_approved("Restart-ServiceFabricNode -NodeName n2 -Force"));
+        chat.approve_for_session("Restart-ServiceFabricNode -NodeName n2 -Force");
+        chat.approve_for_session("Restart-ServiceFabricNode -NodeName n1");
+        assert!(chat.is_session_approved("Restart-ServiceFabricNode -NodeName n2 -Force"));
+
+        // Runs without prompting
+        chat.pending_ps_commands.push_back(PendingCommand {
+            call_id: None,
+            command: "Remove-ServiceFabricApplication fabric:/Other".to_string(),
+            json: false,
+        });
+        chat.process_ps_command().await;
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec!["Remove-ServiceFabricApplication fabric:/Other"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_policy() {
+        let backend = ScriptedBackend::new().with_text(
+            "Get-ServiceFabricApplication",
+            "ApplicationName : fabric:/App",
+        );
+        let commands = backend.commands();
+        let mut chat = test_chat(backend);
+        chat.set_policy(
+            Policy::from_toml(
+                r#"
+[[rule]]
+name = "protect-system"
+action = "deny"
+target = "fabric:/System*"
+
+[[rule]]
+name = "read-apps"
+action = "allow"
+cmdlet = "Get-ServiceFabricApplication"
+"#,
+            )
+            .unwrap(),
+        );
+        for (id, command) in [
+            (
+                "call_1",
+                "Remove-ServiceFabricService fabric:/System/NamingService",
+            ),
+            ("call_2", "Get-ServiceFabricApplication"),
+        ] {
+            chat.pending_ps_commands.push_back(PendingCommand {
+                call_id: Some(id.to_string()),
+                command: command.to_string(),
+                json: false,
+            });
+        }
+        chat.process_ps_command().await;
+
+        // The denied command never reaches the backend
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec!["Get-ServiceFabricApplication"]
+        );
+        let (cmd, res) = chat.pending_ps_commands_results.pop_front().unwrap();
+        assert_eq!(cmd.call_id.as_deref(), Some("call_1"));
+        assert_eq!(
+            res,
+            "Command denied, deny by policy rule 'protect-system': Remove-ServiceFabricService fabric:/System/NamingService"
+        );
+        let (_, res) = chat.pending_ps_commands_results.pop_front().unwrap();
+        assert!(
+            res.starts_with("Policy: allow by policy rule 'read-apps'\nStatus: Succeeded"),
+            "{res}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_approval_mode() {
+        let backend = ScriptedBackend::new()
+            .with_text("Get-ServiceFabricNode", "NodeName : _Node_0")
+            .with_text("Restart-ServiceFabricNode -NodeName _Node_0", "Done")
+            .with_text("Remove-ServiceFabricApplication", "");
+        let commands = backend.commands();
+        let mut chat = test_chat(backend);
+        chat.set_policy(
+            Policy::from_toml(
+                r#"
+[[rule]]
+name = "node-restart"
+action = "allow"
+cmdlet = "Restart-ServiceFabricNode"
+"#,
+            )
+            .unwrap(),
+        );
+
+        // Read-only runs reads, and denies writes even if the policy allows them
+        chat.set_approval_mode(ApprovalMode::ReadOnly);
+        let (outcome, res) = chat.run_command("Get-ServiceFabricNode", false).await;
+        assert_eq!(outcome, Outcome::Succeeded);
+        assert!(res.contains("NodeName : _Node_0"), "{res}");
+        let (outcome, res) = chat
+            .run_command("Restart-ServiceFabricNode -NodeName _Node_0", false)
+            .await;
+        assert_eq!(outcome, Outcome::NotRun);
+        assert_eq!(
+            res,
+            "Command denied by the read-only approval mode (allow by policy rule 'node-restart'): Restart-ServiceFabricNode -NodeName _Node_0"
+        );
+        let (outcome, res) = chat.run_command("Stop-Computer", false).await;
+        assert_eq!(outcome, Outcome::NotRun);
+        assert_eq!(
+            res,
+            "Command denied by the read-only approval mode (unknown risk): Stop-Computer"
+        );
+
+        // Auto runs commands the catalog would ask for, explicit ask rules deny
+        chat.set_approval_mode(ApprovalMode::Auto);
+        let (outcome, _) = chat
+            .run_command("Restart-ServiceFabricNode -NodeName _Node_0", false)
+            .await;
+        assert_eq!(outcome, Outcome::Succeeded);
+        chat.set_policy(
+            Policy::from_toml(
+                "[[rule]]\nname = \"nodes\"\naction = \"ask\"\ncmdlet = \"*-ServiceFabricNode\"",
+            )
+            .unwrap(),
+        );
+        let (outcome, res) = chat
+            .run_command("Restart-ServiceFabricNode -NodeName _Node_0", false)
+            .await;
+        assert_eq!(outcome, Outcome::NotRun);
+        assert!(
+            res.starts_with(
+                "Command denied by the auto approval mode (ask by policy rule 'nodes')"
+            ),
+            "{res}"
+        );
+
+        // destructive commands need a rule allowing them
+        chat.set_policy(Policy::default());
+        let (outcome, _) = chat
+            .run_command("Restart-ServiceFabricNode -NodeName _Node_0", false)
+            .await;
+        assert_eq!(outcome, Outcome::Succeeded);
+        let (outcome, res) = chat
+            .run_command("Remove-ServiceFabricApplication fabric:/App", false)
+            .await;
+        assert_eq!(outcome, Outcome::NotRun);
+        assert_eq!(
+            res,
+            "Command denied by the auto approval mode (destructive risk): Remove-ServiceFabricApplication fabric:/App"
+        );
+        chat.set_policy(
+            Policy::from_toml(
+                "[[rule]]\nname = \"cleanup\"\naction = \"allow\"\ncmdlet = \"Remove-ServiceFabricApplication\"",
+            )
+            .unwrap(),
+        );
+        let (outcome, _) = chat
+            .run_command("Remove-ServiceFabricApplication fabric:/App", false)
+            .await;
+        assert_eq!(outcome, Outcome::Succeeded);
+
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                "Get-ServiceFabricNode",
+                "Restart-ServiceFabricNode -NodeName _Node_0",
+                "Restart-ServiceFabricNode -NodeName _Node_0",
+                "Remove-ServiceFabricApplication fabric:/App",
+            ]
+        );
+        assert!(!chat.has_pending_commands());
+    }
+
+    #[tokio::test]
+    async fn test_ask() {
+        let base_url = mock_llm(vec![
+            vec![mock_tool_call("call_1", "Get-ServiceFabricNode")],
+            vec![mock_tool_call(
+                "call_2",
+                "Restart-ServiceFabricNode -NodeName _Node_2",
+            )],
+            vec![
+                json!({ "role": "assistant", "content": "Node _Node_2 is " }),
+                json!({ "content": "down." }),
+            ],
+        ])
+        .await;
+        let backend =
+            ScriptedBackend::new().with_text("Get-ServiceFabricNode", "NodeName : _Node_2");
+        let commands = backend.commands();
+        let mut chat = mock_chat(base_url, backend);
+        chat.set_interactive(false);
+        chat.set_approval_mode(ApprovalMode::ReadOnly);
+
+        let result = chat.ask("why is node _Node_2 unhealthy?").await.unwrap();
+        assert_eq!(result.answer, "Node _Node_2 is down.");
+        assert_eq!(
+            result.commands,
+            vec![
+                CommandRecord {
+                    command: "Get-ServiceFabricNode".to_string(),
+                    approval: Approval::Auto,
+                    outcome: Outcome::Succeeded,
+                },
+                CommandRecord {
+                    command: "Restart-ServiceFabricNode -NodeName _Node_2".to_string(),
+                    approval: Approval::Denied,
+                    outcome: Outcome::NotRun,
+                },
+            ]
+        );
+        assert!(result.is_incomplete());
+        assert_eq!(*commands.lock().unwrap(), vec!["Get-ServiceFabricNode"]);
+    }
+
+    #[tokio::test]
+    async fn test_error_recovery() {
+        let base_url = mock_llm(vec![
+            vec![mock_tool_call("call_1", "Get-ServiceFabricNode")],
+            // empty response
+            vec![],
+            vec![json!({ "role": "assistant", "content": "All nodes are up." })],
+        ])
+        .await;
+        let backend = ScriptedBackend::new().with_text("Get-ServiceFabricNode", "NodeStatus : Up");
+        let mut chat = mock_chat(base_url, backend);
+        chat.set_interactive(false);
+
+        let err = chat.ask("are all nodes up?").await.unwrap_err();
+        assert!(matches!(err, Error::Parse(_)), "{err}");
+        chat.abort_turn(&err);
+        let result = chat.ask("are all nodes up now?").await.unwrap();
+        assert_eq!(result.answer, "All nodes are up.");
+
+        // pending commands are answered so the history stays valid
+        chat.add_tool_calls(vec![ToolCall {
+            call_id: "call_2".to_string(),
+            fn_name: PWSH_TOOL_NAME.to_string(),
+            fn_arguments: json!({ "reason": "check", "command": "Get-ServiceFabricNode" }),
+        }]);
+        chat.abort_turn(&err);
+        assert!(!chat.has_pending_commands());
+        let MessageContent::ToolResponses(responses) = &chat.req.messages.last().unwrap().content
+        else {
+            panic!("expected a tool response: {:?}", chat.req.messages.last());
+        };
+        assert_eq!(responses[0].call_id, "call_2");
+        assert!(responses[0].content.contains("the request failed"));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let error = |status: &str, headers: &str| {
+            format!(
+                "HTTP/1.1 {status}\r\n{headers}Content-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}"
+            )
+        };
+        let base_url = mock_http(vec![
+            error("429 Too Many Requests", "Retry-After: 0\r\n"),
+            error("503 Service Unavailable", ""),
+            sse_response(vec![
+                json!({ "role": "assistant", "content": "All nodes are up." }),
+            ]),
+            error("400 Bad Request", ""),
+            error("500 Internal Server Error", ""),
+            error("500 Internal Server Error", ""),
+        ])
+        .await;
+        let mut chat = mock_chat(base_url, ScriptedBackend::new());
+        chat.set_interactive(false);
+        // no waiting in tests
+        chat.set_retry_config(RetryConfig {
+            max_retries: 1,
+            max_retry_wait: 0,
+        });
+
+        // the 503 is the second failure of the request, over the limit of one retry
+        let err = chat.ask("are all nodes up?").await.unwrap_err();
+        assert!(matches!(err, Error::Provider(_)), "{err}");
+        chat.abort_turn(&err);
+        chat.set_retry_config(RetryConfig {
+            max_retries: 2,
+            max_retry_wait: 0,
+        });
+        let result = chat.ask("are all nodes up?").await.unwrap();
+        assert_eq!(result.answer, "All nodes are up.");
+
+        // client errors are not retried
+        let err = chat.ask("and now?").await.unwrap_err();
+        assert!(err.to_string().contains("400"), "{err}");
+        chat.abort_turn(&err);
+        chat.set_retry_config(RetryConfig {
+            max_retries: 1,
+            max_retry_wait: 0,
+        });
+        let err = chat.ask("and now?").await.unwrap_err();
+        assert!(err.to_string().contains("500"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_session_resume() {
+        let dir = std::env::temp_dir().join(format!("sfctl-ai-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = SessionStore::new(&dir);
+
+        let base_url = mock_llm(vec![
+            vec![mock_tool_call("call_1", "Get-ServiceFabricNode")],
+            vec![json!({ "role": "assistant", "content": "All nodes are up." })],
+            vec![json!({ "role": "assistant", "content": "Yes, 3 of them." })],
+        ])
+        .await;
+        let backend =
+            ScriptedBackend::new().with_text("Get-ServiceFabricNode", "NodeName : _Node_0");
+        let mut chat = mock_chat(base_url.clone(), backend.clone());
+        chat.set_interactive(false);
+        chat.set_session(store.clone(), Session::new());
+        let id = chat.session_id().unwrap().to_string();
+        chat.ask("are all nodes up?").await.unwrap();
+
+        let saved = store.load(&id).unwrap();
+        assert_eq!(saved.title, "are all nodes up?");
+        // question, tool call, tool response and answer
+        assert_eq!(saved.messages.len(), 4);
+        assert_eq!(saved.commands.len(), 1);
+        assert_eq!(store.list().unwrap()[0].id, id);
+
+        // A teammate picks up the conversation
+        let mut chat = mock_chat(base_url, backend);
+        chat.set_interactive(false);
+        chat.set_session(store.clone(), saved);
+        assert_eq!(chat.req.messages.len(), 4);
+        let result = chat.ask("are there 3?").await.unwrap();
+        assert_eq!(result.answer, "Yes, 3 of them.");
+        assert!(result.commands.is_empty());
+        let saved = store.load(&id).unwrap();
+        assert_eq!(saved.messages.len(), 6);
+        assert_eq!(saved.commands.len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_context_window() {
+        let base_url = mock_llm(vec![
+            vec![mock_tool_call("call_1", "Get-ServiceFabricApplication")],
+            vec![json!({
+                "role": "assistant",
+                "tool_calls": [{
+                    "index": 0,
+                    "id": "call_2",
+                    "type": "function",
+                    "function": {
+                        "name": READ_OUTPUT_TOOL_NAME,
+                        "arguments": json!({ "id": 1, "offset": 0, "length": 30 }).to_string()
+                    }
+                }]
+            })],
+            vec![json!({ "role": "assistant", "content": "There are 500 applications." })],
+            // summary of the first turn, then the answer
+            vec![json!({ "role": "assistant", "content": "The cluster has 500 applications." })],
+            vec![json!({ "role": "assistant", "content": "Yes." })],
+        ])
+        .await;
+        let apps: String = (0..500)
+            .map(|i| format!("ApplicationName : fabric:/App{i:03}\n"))
+            .collect();
+        let backend = ScriptedBackend::new().with_text("Get-ServiceFabricApplication", &apps);
+        let mut chat = mock_chat(base_url, backend);
+        chat.set_interactive(false);
+        chat.set_context_config(ContextConfig {
+            context_budget: 1_000_000,
+            max_output_tokens: 100,
+        });
+
+        let result = chat.ask("how many applications?").await.unwrap();
+        assert_eq!(result.answer, "There are 500 applications.");
+        assert_eq!(chat.outputs.len(), 1);
+        let responses: Vec<String> = chat
+            .req
+            .messages
+            .iter()
+            .filter_map(|m| match &m.content {
+                MessageContent::ToolResponses(r) => Some(r[0].content.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(responses.len(), 2);
+        assert!(
+            responses[0].contains("saved as output 1"),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].len() < 1000, "{}", responses[0]);
+        assert!(
+            responses[1].starts_with("Output 1, characters 0-30 of "),
+            "{}",
+            responses[1]
+        );
+
+        // Over budget, the first turn is summarized
+        chat.set_context_config(ContextConfig {
+            context_budget: 10,
+            max_output_tokens: 100,
+        });
+        let result = chat.ask("really?").await.unwrap();
+        assert_eq!(result.answer, "Yes.");
+        assert_eq!(
+            chat.req.messages[0].content.text(),
+            Some("Summary of the earlier conversation:\nThe cluster has 500 applications.")
+        );
+        assert_eq!(chat.req.messages[1].content.text(), Some("really?"));
+        assert_eq!(chat.req.messages.len(), 3);
+    }
+
+    /// Reports a longer duration on every run, like a real session does.
+    struct SlowingBackend {
+        inner: ScriptedBackend,
+        runs: u64,
+    }
+
+    impl CommandBackend for SlowingBackend {
+        fn run_command<'a>(
+            &'a mut self,
+            command: &'a str,
+        ) -> BoxFuture<'a, std::io::Result<String>> {
+            self.inner.run_command(command)
+        }
+
+        fn run_command_json<'a>(
+            &'a mut self,
+            command: &'a str,
+            depth: u32,
+        ) -> BoxFuture<'a, std::io::Result<serde_json::Value>> {
+            self.inner.run_command_json(command, depth)
+        }
+
+        fn run_command_outcome<'a>(
+            &'a mut self,
+            command: &'a str,
+        ) -> BoxFuture<'a, std::io::Result<CommandOutcome>> {
+            self.runs += 1;
+            let runs = self.runs;
+            Box::pin(async move {
+                let outcome = self.inner.run_command_outcome(command).await?;
+                Ok(CommandOutcome {
+                    duration: Duration::from_millis(10 * runs),
+                    ..outcome
+                })
+            })
+        }
+
+        fn set_timeout(&mut self, timeout: Option<Duration>) {
+            self.inner.set_timeout(timeout);
+        }
+
+        fn set_cancellation_token(&mut self, token: CancellationToken) {
+            self.inner.set_cancellation_token(token);
         }
     }
+
+    #[tokio::test]
+    async fn test_repeats_ignore_duration() {
+        let retry = "Get-ServiceFabricApplication -ApplicationName fabric:/Missing";
+        let base_url = mock_llm(vec![
+            vec![mock_tool_call("call_1", retry)],
+            vec![mock_tool_call("call_2", retry)],
+            vec![mock_tool_call("call_3", retry)],
+            vec![mock_tool_call("call_4", retry)],
+            vec![json!({ "role": "assistant", "content": "fabric:/Missing was not found." })],
+        ])
+        .await;
+        let inner = ScriptedBackend::new();
+        let commands = inner.commands();
+        let mut chat = mock_chat(base_url, SlowingBackend { inner, runs: 0 });
+        chat.set_interactive(false);
+        let result = chat.ask("is fabric:/Missing healthy?").await.unwrap();
+        assert_eq!(
+            result.stopped,
+            Some(StopReason::Repeated {
+                steps: 3,
+                command: retry.to_string(),
+                times: 3
+            })
+        );
+        assert_eq!(commands.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_loop_limits() {
+        // The model retries the same failing command
+        let retry = "Get-ServiceFabricApplication -ApplicationName fabric:/Missing";
+        let base_url = mock_llm(vec![
+            vec![mock_tool_call("call_1", retry)],
+            vec![mock_tool_call("call_2", retry)],
+            vec![mock_tool_call("call_3", retry)],
+            vec![mock_tool_call("call_4", retry)],
+            vec![json!({ "role": "assistant", "content": "fabric:/Missing was not found." })],
+        ])
+        .await;
+        let backend = ScriptedBackend::new();
+        let commands = backend.commands();
+        let mut chat = mock_chat(base_url, backend);
+        chat.set_interactive(false);
+        let result = chat.ask("is fabric:/Missing healthy?").await.unwrap();
+        assert_eq!(
+            result.stopped,
+            Some(StopReason::Repeated {
+                steps: 3,
+                command: retry.to_string(),
+                times: 3
+            })
+        );
+        assert_eq!(
+            result.stopped.as_ref().unwrap().to_string(),
+            format!(
+                "Stopped after 3 steps, the command `{retry}` ran 3 times with the same result"
+            )
+        );
+        assert_eq!(result.answer, "fabric:/Missing was not found.");
+        assert!(result.is_incomplete());
+        assert_eq!(commands.lock().unwrap().len(), 3);
+        // The call that was not run still has a response
+        let last_response = chat
+            .req
+            .messages
+            .iter()
+            .rev()
+            .find_map(|m| match &m.content {
+                MessageContent::ToolResponses(r) => Some(r[0].clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(last_response.call_id, "call_4");
+        assert!(
+            last_response
+                .content
+                .starts_with("Not run, the agent was stopped")
+        );
+
+        // The model keeps running different commands
+        let base_url = mock_llm(vec![
+            vec![mock_tool_call("call_1", "Get-ServiceFabricNode")],
+            vec![mock_tool_call("call_2", "Get-ServiceFabricApplication")],
+            vec![mock_tool_call("call_3", "Get-ServiceFabricService")],
+            vec![json!({ "role": "assistant", "content": "Partial answer." })],
+        ])
+        .await;
+        let backend = ScriptedBackend::new()
+            .with_text("Get-ServiceFabricNode", "NodeName : _Node_0")
+            .with_text(
+                "Get-ServiceFabricApplication",
+                "ApplicationName : fabric:/App",
+            );
+        let commands = backend.commands();
+        let mut chat = mock_chat(base_url, backend);
+        chat.set_interactive(false);
+        chat.set_loop_config(LoopConfig {
+            max_steps: 2,
+            max_repeats: 3,
+        });
+        let result = chat.ask("what runs in the cluster?").await.unwrap();
+        assert_eq!(result.stopped, Some(StopReason::StepLimit(2)));
+        assert_eq!(result.answer, "Partial answer.");
+        assert_eq!(commands.lock().unwrap().len(), 2);
+        assert!(!chat.has_pending_commands());
+    }
 }
@@ -0,0 +1,459 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::policy::wildcard_match;
+
+/// Default audit log location, next to the other logs.
+pub const DEFAULT_AUDIT_LOG: &str = "logs/audit.jsonl";
+
+/// Previous hash of the first record.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Audit log selection.
+#[derive(Debug, Clone, clap::Args)]
+pub struct AuditConfig {
+    /// Audit log of the executed cluster commands (json lines)
+    #[arg(long, env = "SFCTL_AI_AUDIT_LOG", default_value = DEFAULT_AUDIT_LOG, global = true)]
+    pub audit_log: PathBuf,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            audit_log: PathBuf::from(DEFAULT_AUDIT_LOG),
+        }
+    }
+}
+
+/// How the command was approved, or why it did not run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Approval {
+    /// No approval needed by the policy.
+    Auto,
+    /// Approved by the user for this run.
+    User,
+    /// Approved by the user for the session.
+    Session,
+    /// Edited and approved by the user.
+    Edited,
+    /// Declined by the user.
+    Declined,
+    /// Skipped by the user with the remaining commands.
+    Skipped,
+    /// Denied by the policy.
+    Denied,
+}
+
+/// Result of the command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Succeeded,
+    /// Ran and reported errors.
+    Failed,
+    /// Could not run, e.g. timeout or the backend failed.
+    Error,
+    NotRun,
+}
+
+/// What happened to a command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// RFC 3339 time the command finished.
+    pub timestamp: String,
+    pub user: String,
+    /// Cluster endpoint, None if not connected.
+    pub cluster: Option<String>,
+    /// Where the command came from, e.g. `repl` or `mcp`.
+    pub source: String,
+    pub command: String,
+    /// Risk level from the cmdlet catalog.
+    pub risk: String,
+    /// Policy decision, e.g. `allow by policy rule 'reads'`.
+    pub policy: String,
+    pub approval: Approval,
+    pub outcome: Outcome,
+    pub duration_ms: Option<u64>,
+}
+
+impl AuditEntry {
+    /// Entry stamped with the current time and user.
+    pub fn new(source: &str, command: &str) -> Self {
+        AuditEntry {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            user: current_user(),
+            cluster: None,
+            source: source.to_string(),
+            command: command.to_string(),
+            risk: String::new(),
+            policy: String::new(),
+            approval: Approval::Auto,
+            outcome: Outcome::NotRun,
+            duration_ms: None,
+        }
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration_ms = Some(duration.as_millis() as u64);
+        self
+    }
+}
+
+/// An audit log line. Each record contains the hash of the previous one,
+/// so edits or removals break the chain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+fn record_hash(seq: u64, prev_hash: &str, entry: &AuditEntry) -> String {
+    let entry = serde_json::to_string(entry).expect("audit entry serializes");
+    let digest = Sha256::digest(format!("{seq}\n{prev_hash}\n{entry}"));
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Append only, hash chained audit log in json lines format.
+/// Several processes may append to the same log, each append locks the file and continues
+/// the chain from its last record. Removing records from the end of the log leaves a valid
+/// chain, detecting that needs the last hash kept somewhere else.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Open the log, failing if its last record is invalid.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent()
+            && !dir.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(dir)?;
+        }
+        if path.exists() {
+            last_record(&mut File::open(path)?)?;
+        }
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, entry: AuditEntry) -> std::io::Result<AuditRecord> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        // other processes append to the log too, the lock is released when the file closes
+        file.lock()?;
+        let (seq, prev_hash) = match last_record(&mut file)? {
+            Some(last) => (last.seq + 1, last.hash),
+            None => (1, GENESIS_HASH.to_string()),
+        };
+        let hash = record_hash(seq, &prev_hash, &entry);
+        let record = AuditRecord {
+            seq,
+            entry,
+            prev_hash,
+            hash,
+        };
+        file.write_all(format!("{}\n", serde_json::to_string(&record)?).as_bytes())?;
+        file.flush()?;
+        Ok(record)
+    }
+
+    /// Append, logging failures instead of returning them.
+    /// An audit failure should not stop the command flow.
+    pub fn record(&self, entry: AuditEntry) {
+        if let Err(e) = self.append(entry) {
+            tracing::error!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// The last record of the log, reading the file backwards from its end.
+fn last_record(file: &mut File) -> std::io::Result<Option<AuditRecord>> {
+    let mut start = file.seek(SeekFrom::End(0))?;
+    let mut tail = Vec::new();
+    let line = loop {
+        let trimmed = tail.trim_ascii_end();
+        match trimmed.iter().rposition(|&b| b == b'\n') {
+            Some(i) => break &trimmed[i + 1..],
+            None if start == 0 => break trimmed,
+            None => {}
+        }
+        let end = start;
+        start = end.saturating_sub(4096);
+        let mut chunk = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+    };
+    if line.trim_ascii().is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_slice(line).map(Some).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("last line: invalid audit record: {e}"),
+        )
+    })
+}
+
+fn read_records(path: &Path) -> std::io::Result<Vec<AuditRecord>> {
+    let file = std::fs::File::open(path)?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("line {}: invalid audit record: {e}", i + 1),
+            )
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Check the hash chain of the log. Returns the number of records.
+pub fn verify(path: &Path) -> std::io::Result<usize> {
+    let records = read_records(path)?;
+    let mut prev_hash = GENESIS_HASH.to_string();
+    for (i, record) in records.iter().enumerate() {
+        let invalid = |reason: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("record {}: {reason}", i + 1),
+            )
+        };
+        if record.seq != i as u64 + 1 {
+            return Err(invalid(&format!(
+                "expected sequence {}, found {}",
+                i + 1,
+                record.seq
+            )));
+        }
+        if record.prev_hash != prev_hash {
+            return Err(invalid(
+                "previous hash does not match, records were removed or reordered",
+            ));
+        }
+        if record_hash(record.seq, &record.prev_hash, &record.entry) != record.hash {
+            return Err(invalid("hash does not match, the record was modified"));
+        }
+        prev_hash = record.hash.clone();
+    }
+    Ok(records.len())
+}
+
+/// Filter for [`query`]. Patterns support `*` and `?` wildcards.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct AuditQuery {
+    /// Command pattern, e.g. "Remove-*"
+    #[arg(long)]
+    pub command: Option<String>,
+    /// User name
+    #[arg(long)]
+    pub user: Option<String>,
+    /// Cluster endpoint pattern
+    #[arg(long)]
+    pub cluster: Option<String>,
+    /// Only records at or after this RFC 3339 time, e.g. 2025-01-31T00:00:00Z
+    #[arg(long)]
+    pub since: Option<chrono::DateTime<chrono::FixedOffset>>,
+    /// Only the last N matching records
+    #[arg(long)]
+    pub last: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        let entry = &record.entry;
+        if let Some(pattern) = &self.command
+            && !wildcard_match(pattern, &entry.command)
+        {
+            return false;
+        }
+        if let Some(user) = &self.user
+            && !entry.user.eq_ignore_ascii_case(user)
+        {
+            return false;
+        }
+        if let Some(pattern) = &self.cluster
+            && !entry
+                .cluster
+                .as_deref()
+                .is_some_and(|c| wildcard_match(pattern, c))
+        {
+            return false;
+        }
+        if let Some(since) = &self.since {
+            match chrono::DateTime::parse_from_rfc3339(&entry.timestamp) {
+                Ok(time) if time >= *since => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Records matching the filter, oldest first.
+pub fn query(path: &Path, filter: &AuditQuery) -> std::io::Result<Vec<AuditRecord>> {
+    let mut records: Vec<_> = read_records(path)?
+        .into_iter()
+        .filter(|r| filter.matches(r))
+        .collect();
+    if let Some(last) = filter.last {
+        records.drain(..records.len().saturating_sub(last));
+    }
+    Ok(records)
+}
+
+/// Audit log subcommands.
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum AuditCommand {
+    /// Check that the audit log was not tampered with
+    Verify,
+    /// Print matching audit records as json lines
+    Query(AuditQuery),
+}
+
+impl AuditCommand {
+    pub fn run(&self, path: &Path) -> std::io::Result<()> {
+        match self {
+            AuditCommand::Verify => {
+                let count = verify(path)?;
+                println!("{}: {} records, hash chain intact", path.display(), count);
+            }
+            AuditCommand::Query(filter) => {
+                for record in query(path, filter)? {
+                    println!("{}", serde_json::to_string(&record)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(command: &str, user: &str) -> AuditEntry {
+        AuditEntry {
+            user: user.to_string(),
+            cluster: Some("localhost:19000".to_string()),
+            risk: "read".to_string(),
+            policy: "allow by default policy".to_string(),
+            outcome: Outcome::Succeeded,
+            ..AuditEntry::new("repl", command)
+        }
+        .with_duration(Duration::from_millis(12))
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let dir = std::env::temp_dir().join(format!("sfctl-ai-audit-{}", std::process::id()));
+        let path = dir.join("audit.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        let log = AuditLog::open(&path).unwrap();
+        let first = log.append(entry("Get-ServiceFabricNode", "alice")).unwrap();
+        assert_eq!(first.seq, 1);
+        assert_eq!(first.prev_hash, GENESIS_HASH);
+        drop(log);
+
+        // Reopening continues the chain
+        let log = AuditLog::open(&path).unwrap();
+        let second = log
+            .append(entry("Remove-ServiceFabricApplication fabric:/App", "bob"))
+            .unwrap();
+        assert_eq!(second.seq, 2);
+        assert_eq!(second.prev_hash, first.hash);
+        log.append(entry("Get-ServiceFabricApplication", "alice"))
+            .unwrap();
+        assert_eq!(verify(&path).unwrap(), 3);
+
+        // Logs opened by other processes continue the same chain
+        let other = AuditLog::open(&path).unwrap();
+        std::thread::scope(|scope| {
+            for log in [&log, &other] {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        log.append(entry("Get-ServiceFabricNode", "carol")).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(verify(&path).unwrap(), 23);
+
+        let found = query(
+            &path,
+            &AuditQuery {
+                user: Some("alice".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(found.len(), 2);
+        let found = query(
+            &path,
+            &AuditQuery {
+                command: Some("remove-*".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(found, vec![second]);
+        let found = query(
+            &path,
+            &AuditQuery {
+                last: Some(1),
+                since: Some(chrono::DateTime::parse_from_rfc3339("2000-01-01T00:00:00Z").unwrap()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(found[0].entry.command, "Get-ServiceFabricNode");
+
+        // Tampering with a record breaks the chain
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replacen("bob", "eve", 1)).unwrap();
+        let err = verify(&path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "record 2: hash does not match, the record was modified"
+        );
+
+        // So does removing one
+        let lines: Vec<_> = content.lines().collect();
+        std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(verify(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio_util::sync::CancellationToken;
+
+use crate::pwsh::{self, CommandOutcome, DEFAULT_COMMAND_TIMEOUT, ErrorRecord, PwshSession};
+use crate::rest::{DEFAULT_GATEWAY_URL, RestBackend};
+
+/// Backends selectable from the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackendKind {
+    /// ServiceFabric powershell module
+    Pwsh,
+    /// Service Fabric REST api on the HTTP gateway
+    Rest,
+}
+
+/// Backend selection.
+#[derive(Debug, Clone, clap::Args)]
+pub struct BackendConfig {
+    /// Backend running the cluster commands
+    #[arg(long, env = "SFCTL_AI_BACKEND", value_enum, default_value_t = BackendKind::Pwsh, global = true)]
+    pub backend: BackendKind,
+
+    /// Service Fabric HTTP gateway url, used by the rest backend
+    #[arg(long, env = "SFCTL_AI_GATEWAY_URL", default_value = DEFAULT_GATEWAY_URL, global = true)]
+    pub gateway_url: String,
+
+    /// Max seconds a single command may run, 0 waits forever. On timeout the pwsh session is
+    /// restarted
+    #[arg(long, env = "SFCTL_AI_COMMAND_TIMEOUT", default_value_t = DEFAULT_COMMAND_TIMEOUT.as_secs(), global = true)]
+    pub command_timeout: u64,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            backend: BackendKind::Pwsh,
+            gateway_url: DEFAULT_GATEWAY_URL.to_string(),
+            command_timeout: DEFAULT_COMMAND_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+impl BackendConfig {
+    /// Create the configured backend. Must be called within a tokio runtime.
+    pub fn create_backend(&self) -> crate::Result<Box<dyn CommandBackend>> {
+        let mut backend: Box<dyn CommandBackend> = match self.backend {
+            BackendKind::Pwsh => Box::new(PwshSession::new()?),
+            BackendKind::Rest => Box::new(RestBackend::new(&self.gateway_url)),
+        };
+        backend.set_timeout(self.command_timeout());
+        Ok(backend)
+    }
+
+    /// Max time a single command may run, None waits forever.
+    pub fn command_timeout(&self) -> Option<Duration> {
+        match self.command_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+}
+
+/// Something that can run Service Fabric commands, e.g. a powershell session.
+/// The methods mirror [`PwshSession`], see there for details.
+pub trait CommandBackend: Send {
+    /// Run the command and return the formatted output.
+    fn run_command<'a>(&'a mut self, command: &'a str) -> BoxFuture<'a, std::io::Result<String>>;
+
+    /// Run the command and return the output objects as json.
+    /// Failures are returned as `{"Error": message}`, see [`json_error`].
+    fn run_command_json<'a>(
+        &'a mut self,
+        command: &'a str,
+        depth: u32,
+    ) -> BoxFuture<'a, std::io::Result<serde_json::Value>>;
+
+    /// Run the command and report the output, errors and status separately.
+    fn run_command_outcome<'a>(
+        &'a mut self,
+        command: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<CommandOutcome>>;
+
+    /// Set the max time a single command may run. None waits forever.
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+
+    /// Commands are cancelled when the token is cancelled.
+    fn set_cancellation_token(&mut self, token: CancellationToken);
+
+    /// Cluster endpoint the commands run against, None if not connected.
+    fn endpoint(&self) -> Option<String> {
+        None
+    }
+
+    /// Commands restoring the session state in a new session, e.g. the cluster connection.
+    fn bootstrap(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Error message of a json command output, the backends report failures as `{"Error": message}`.
+pub fn json_error(output: &serde_json::Value) -> Option<String> {
+    match output.as_object() {
+        Some(object) if object.len() == 1 => object.get("Error")?.as_str().map(str::to_string),
+        _ => None,
+    }
+}
+
+impl CommandBackend for PwshSession {
+    fn run_command<'a>(&'a mut self, command: &'a str) -> BoxFuture<'a, std::io::Result<String>> {
+        Box::pin(PwshSession::run_command(self, command))
+    }
+
+    fn run_command_json<'a>(
+        &'a mut self,
+        command: &'a str,
+        depth: u32,
+    ) -> BoxFuture<'a, std::io::Result<serde_json::Value>> {
+        Box::pin(PwshSession::run_command_json(self, command, depth))
+    }
+
+    fn run_command_outcome<'a>(
+        &'a mut self,
+        command: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<CommandOutcome>> {
+        Box::pin(PwshSession::run_command_outcome(self, command))
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        PwshSession::set_timeout(self, timeout);
+    }
+
+    fn set_cancellation_token(&mut self, token: CancellationToken) {
+        PwshSession::set_cancellation_token(self, token);
+    }
+
+    fn endpoint(&self) -> Option<String> {
+        PwshSession::endpoint(self)
+    }
+
+    fn bootstrap(&self) -> Vec<String> {
+        PwshSession::bootstrap(self).to_vec()
+    }
+}
+
+/// Canned result of a scripted command.
+#[derive(Debug, Clone)]
+pub enum ScriptedResponse {
+    Text(String),
+    Json(serde_json::Value),
+    /// The command fails with this error record.
+    Error(ErrorRecord),
+}
+
+/// In memory backend returning canned outputs, for tests without powershell.
+/// A command matches a rule if it starts with the rule pattern, ignoring case.
+/// The first matching rule wins. Commands without a match fail like an unknown cmdlet.
+/// Module imports and cluster connections are recorded as the bootstrap, like in [`PwshSession`].
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedBackend {
+    rules: Vec<(String, ScriptedResponse)>,
+    commands: Arc<Mutex<Vec<String>>>,
+    timeout: Arc<Mutex<Option<Duration>>>,
+    bootstrap: Vec<String>,
+}
+
+impl ScriptedBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_response(mut self, pattern: &str, response: ScriptedResponse) -> Self {
+        self.rules.push((pattern.to_lowercase(), response));
+        self
+    }
+
+    pub fn with_text(self, pattern: &str, output: &str) -> Self {
+        self.with_response(pattern, ScriptedResponse::Text(output.to_string()))
+    }
+
+    pub fn with_json(self, pattern: &str, output: serde_json::Value) -> Self {
+        self.with_response(pattern, ScriptedResponse::Json(output))
+    }
+
+    pub fn with_error(self, pattern: &str, error: ErrorRecord) -> Self {
+        self.with_response(pattern, ScriptedResponse::Error(error))
+    }
+
+    /// Handle to the commands run so far. Stays valid after the backend is moved.
+    pub fn commands(&self) -> Arc<Mutex<Vec<String>>> {
+        self.commands.clone()
+    }
+
+    /// Handle to the timeout set last.
+    pub fn timeout(&self) -> Arc<Mutex<Option<Duration>>> {
+        self.timeout.clone()
+    }
+
+    fn respond(&mut self, command: &str) -> ScriptedResponse {
+        let command = PwshSession::trim_command(command).trim().to_string();
+        self.commands.lock().unwrap().push(command.clone());
+        let lower = command.to_lowercase();
+        let response = self
+            .rules
+            .iter()
+            .find(|(pattern, _)| lower.starts_with(pattern.as_str()))
+            .map(|(_, response)| response.clone())
+            .unwrap_or_else(|| {
+                let name = command.split_whitespace().next().unwrap_or_default();
+                ScriptedResponse::Error(ErrorRecord {
+                    message: format!(
+                        "The term '{name}' is not recognized as a name of a cmdlet, function, script file, or executable program."
+                    ),
+                    category: "ObjectNotFound".to_string(),
+                    fully_qualified_error_id: "CommandNotFoundException".to_string(),
+                    target_object: Some(name.to_string()),
+                })
+            });
+        // like a session, only commands that worked set up its state
+        if !matches!(response, ScriptedResponse::Error(_)) {
+            pwsh::update_bootstrap(&mut self.bootstrap, &command);
+        }
+        response
+    }
+}
+
+impl CommandBackend for ScriptedBackend {
+    fn run_command<'a>(&'a mut self, command: &'a str) -> BoxFuture<'a, std::io::Result<String>> {
+        let output = match self.respond(command) {
+            ScriptedResponse::Text(text) => text,
+            ScriptedResponse::Json(value) => value.to_string(),
+            ScriptedResponse::Error(e) => e.message,
+        };
+        Box::pin(async move { Ok(output) })
+    }
+
+    fn run_command_json<'a>(
+        &'a mut self,
+        command: &'a str,
+        _depth: u32,
+    ) -> BoxFuture<'a, std::io::Result<serde_json::Value>> {
+        let output = match self.respond(command) {
+            ScriptedResponse::Text(text) => serde_json::Value::String(text),
+            ScriptedResponse::Json(value) => value,
+            ScriptedResponse::Error(e) => serde_json::json!({ "Error": e.message }),
+        };
+        Box::pin(async move { Ok(output) })
+    }
+
+    fn run_command_outcome<'a>(
+        &'a mut self,
+        command: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<CommandOutcome>> {
+        let mut outcome = CommandOutcome {
+            stdout: String::new(),
+            stderr: String::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            status: true,
+            exit_code: None,
+            duration: Duration::ZERO,
+        };
+        match self.respond(command) {
+            ScriptedResponse::Text(text) => outcome.stdout = text,
+            ScriptedResponse::Json(value) => outcome.stdout = value.to_string(),
+            ScriptedResponse::Error(e) => {
+                outcome.status = false;
+                outcome.errors.push(e);
+            }
+        }
+        Box::pin(async move { Ok(outcome) })
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
+
+    fn set_cancellation_token(&mut self, _token: CancellationToken) {}
+
+    fn endpoint(&self) -> Option<String> {
+        pwsh::connection_endpoint(&self.bootstrap)
+    }
+
+    fn bootstrap(&self) -> Vec<String> {
+        self.bootstrap.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scripted_backend() {
+        let mut backend = ScriptedBackend::new()
+            .with_text(
+                "Get-ServiceFabricClusterHealth",
+                "AggregatedHealthState : Ok",
+            )
+            .with_json(
+                "Get-ServiceFabricNode",
+                serde_json::json!([{ "NodeName": "_Node_0" }]),
+            );
+        let commands = backend.commands();
+
+        let output = backend
+            .run_command("get-servicefabricclusterhealth")
+            .await
+            .unwrap();
+        assert_eq!(output, "AggregatedHealthState : Ok");
+
+        let output = backend
+            .run_command_json("Get-ServiceFabricNode -NodeName _Node_0", 4)
+            .await
+            .unwrap();
+        assert_eq!(output[0]["NodeName"], "_Node_0");
+        assert_eq!(json_error(&output), None);
+        assert_eq!(
+            json_error(&serde_json::json!({ "Error": "Node not found" })).as_deref(),
+            Some("Node not found")
+        );
+
+        let outcome = backend
+            .run_command_outcome("# comment\nRemove-ServiceFabricApplication fabric:/App")
+            .await
+            .unwrap();
+        assert!(!outcome.is_success());
+        assert_eq!(
+            outcome.errors[0].fully_qualified_error_id,
+            "CommandNotFoundException"
+        );
+
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                "get-servicefabricclusterhealth",
+                "Get-ServiceFabricNode -NodeName _Node_0",
+                "Remove-ServiceFabricApplication fabric:/App",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let mut config = BackendConfig::default();
+        assert_eq!(config.command_timeout(), Some(DEFAULT_COMMAND_TIMEOUT));
+        config.command_timeout = 0;
+        assert_eq!(config.command_timeout(), None);
+    }
+}
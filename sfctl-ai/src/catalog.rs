@@ -0,0 +1,389 @@
+use crate::cmd_parse::{self, CmdKind, Token};
+
+/// Risk of running a command against the cluster, from least to most dangerous.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RiskLevel {
+    /// Queries only, no changes.
+    Read,
+    /// Changes that do not affect running workloads, e.g. provisioning or health reports.
+    SafeWrite,
+    /// Not in the catalog and not recognizable.
+    Unknown,
+    /// Affects availability of running workloads, e.g. restarts and upgrades.
+    Disruptive,
+    /// Removes state or data that cannot be recovered.
+    Destructive,
+}
+
+impl RiskLevel {
+    pub fn needs_ack(&self) -> bool {
+        *self != RiskLevel::Read
+    }
+}
+
+impl std::fmt::Display for RiskLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            RiskLevel::Read => "read",
+            RiskLevel::SafeWrite => "safe-write",
+            RiskLevel::Unknown => "unknown",
+            RiskLevel::Disruptive => "disruptive",
+            RiskLevel::Destructive => "destructive",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Overrides the level of a cmdlet when the parameter is present,
+/// and has the value if one is given. Values match ignoring case.
+struct ParamRule {
+    cmdlet: &'static str,
+    param: &'static str,
+    value: Option<&'static str>,
+    level: RiskLevel,
+}
+
+use RiskLevel::*;
+
+/// ServiceFabric module cmdlets that do not follow the verb conventions,
+/// or need a finer level than the verb gives. Names are lowercase.
+const CATALOG: &[(&str, RiskLevel)] = &[
+    // Connection and diagnostics
+    ("connect-servicefabriccluster", Read),
+    ("test-servicefabricclusterconnection", Read),
+    ("test-servicefabricapplicationpackage", Read),
+    ("test-servicefabricclustermanifest", Read),
+    // Provisioning
+    ("copy-servicefabricapplicationpackage", SafeWrite),
+    ("register-servicefabricapplicationtype", SafeWrite),
+    ("copy-servicefabricclusterpackage", SafeWrite),
+    ("register-servicefabricclusterpackage", SafeWrite),
+    ("new-servicefabricapplication", SafeWrite),
+    ("new-servicefabricservice", SafeWrite),
+    ("update-servicefabricapplication", SafeWrite),
+    ("update-servicefabricservice", SafeWrite),
+    ("send-servicefabricclusterhealthreport", SafeWrite),
+    ("send-servicefabricnodehealthreport", SafeWrite),
+    ("send-servicefabricapplicationhealthreport", SafeWrite),
+    ("send-servicefabricservicehealthreport", SafeWrite),
+    ("send-servicefabricpartitionhealthreport", SafeWrite),
+    ("send-servicefabricreplicahealthreport", SafeWrite),
+    ("enable-servicefabricnode", SafeWrite),
+    ("stop-servicefabricchaos", SafeWrite),
+    ("reset-servicefabricpartitionload", SafeWrite),
+    // Availability of running workloads
+    ("restart-servicefabricnode", Disruptive),
+    ("disable-servicefabricnode", Disruptive),
+    ("start-servicefabricnodetransition", Disruptive),
+    ("restart-servicefabricdeployedcodepackage", Disruptive),
+    ("restart-servicefabricreplica", Disruptive),
+    ("remove-servicefabricreplica", Disruptive),
+    ("move-servicefabricprimaryreplica", Disruptive),
+    ("move-servicefabricsecondaryreplica", Disruptive),
+    ("move-servicefabricinstance", Disruptive),
+    ("repair-servicefabricpartition", Disruptive),
+    ("start-servicefabricpartitionrestart", Disruptive),
+    ("start-servicefabricchaos", Disruptive),
+    ("start-servicefabricapplicationupgrade", Disruptive),
+    ("update-servicefabricapplicationupgrade", Disruptive),
+    ("resume-servicefabricapplicationupgrade", Disruptive),
+    ("start-servicefabricapplicationrollback", Disruptive),
+    ("start-servicefabricclusterupgrade", Disruptive),
+    ("update-servicefabricclusterupgrade", Disruptive),
+    ("resume-servicefabricclusterupgrade", Disruptive),
+    ("start-servicefabricclusterrollback", Disruptive),
+    ("start-servicefabricclusterconfigurationupgrade", Disruptive),
+    // Loss of state or data
+    ("remove-servicefabricapplication", Destructive),
+    ("remove-servicefabricservice", Destructive),
+    ("remove-servicefabricapplicationtype", Destructive),
+    ("unregister-servicefabricapplicationtype", Destructive),
+    ("remove-servicefabricapplicationpackage", Destructive),
+    ("remove-servicefabricclusterpackage", Destructive),
+    ("unregister-servicefabricclusterpackage", Destructive),
+    ("remove-servicefabricnodestate", Destructive),
+    ("start-servicefabricpartitiondataloss", Destructive),
+    ("invoke-servicefabricpartitiondataloss", Destructive),
+    ("start-servicefabricpartitionquorumloss", Destructive),
+    ("invoke-servicefabricpartitionquorumloss", Destructive),
+];
+
+/// Parameter aware rules, the first match for a cmdlet wins.
+const PARAM_RULES: &[ParamRule] = &[
+    // Starting a node back is harmless, stopping it is not
+    ParamRule {
+        cmdlet: "start-servicefabricnodetransition",
+        param: "start",
+        value: None,
+        level: SafeWrite,
+    },
+    // Removing the node or its data loses the replicas on it
+    ParamRule {
+        cmdlet: "disable-servicefabricnode",
+        param: "intent",
+        value: Some("removedata"),
+        level: Destructive,
+    },
+    ParamRule {
+        cmdlet: "disable-servicefabricnode",
+        param: "intent",
+        value: Some("removenode"),
+        level: Destructive,
+    },
+    // Forcing a node operation skips the safety checks that keep quorum and replicas
+    ParamRule {
+        cmdlet: "disable-servicefabricnode",
+        param: "force",
+        value: None,
+        level: Destructive,
+    },
+    ParamRule {
+        cmdlet: "restart-servicefabricnode",
+        param: "force",
+        value: None,
+        level: Destructive,
+    },
+    ParamRule {
+        cmdlet: "remove-servicefabricnodestate",
+        param: "force",
+        value: None,
+        level: Destructive,
+    },
+    // Forced removal skips the graceful close of the replica
+    ParamRule {
+        cmdlet: "remove-servicefabricreplica",
+        param: "forceremove",
+        value: None,
+        level: Destructive,
+    },
+    // Unmonitored upgrades do not roll back on health failures
+    ParamRule {
+        cmdlet: "start-servicefabricapplicationupgrade",
+        param: "unmonitoredauto",
+        value: None,
+        level: Destructive,
+    },
+    ParamRule {
+        cmdlet: "start-servicefabricclusterupgrade",
+        param: "unmonitoredauto",
+        value: None,
+        level: Destructive,
+    },
+];
+
+/// Risk assessment of a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assessment {
+    pub level: RiskLevel,
+    /// Head of the statement that gave the level, e.g. the cmdlet name.
+    /// None for an empty script.
+    pub command: Option<String>,
+}
+
+/// Risk level of a single cmdlet invocation with its parameters.
+pub fn cmdlet_risk(name: &str, params: &[(String, Option<String>)]) -> RiskLevel {
+    let lower = name.to_lowercase();
+    let level = base_risk(name, &lower);
+    let rule = PARAM_RULES.iter().find(|rule| {
+        rule.cmdlet == lower
+            && params.iter().any(|(param, value)| {
+                names_param(param, rule, level)
+                    && rule
+                        .value
+                        .is_none_or(|v| value.as_deref().is_some_and(|x| x.eq_ignore_ascii_case(v)))
+            })
+    });
+    match rule {
+        Some(rule) => rule.level,
+        None => level,
+    }
+}
+
+/// Whether a parameter name of the command line names the parameter of the rule.
+/// PowerShell accepts any unambiguous prefix, e.g. `-Forc` for `-Force`. Other parameters
+/// of the cmdlet may share a prefix, so rules lowering the level need the full name.
+fn names_param(param: &str, rule: &ParamRule, level: RiskLevel) -> bool {
+    if rule.level < level {
+        param == rule.param
+    } else {
+        !param.is_empty() && rule.param.starts_with(param)
+    }
+}
+
+/// Risk level of the cmdlet without its parameters.
+fn base_risk(name: &str, lower: &str) -> RiskLevel {
+    if let Some((_, level)) = CATALOG.iter().find(|(cmdlet, _)| *cmdlet == lower) {
+        return *level;
+    }
+    // Fall back to the verb
+    match cmd_parse::classify_cmdlet(name) {
+        CmdKind::Read => Read,
+        CmdKind::Write if lower.starts_with("remove-") => Destructive,
+        CmdKind::Write => SafeWrite,
+        CmdKind::Unknown => Unknown,
+    }
+}
+
+/// Risk level of one pipeline element, see [`cmd_parse::statements`].
+/// Redirecting output to a file makes it at least Unknown.
+pub fn statement_risk(tokens: &[Token]) -> RiskLevel {
+    let level = head_risk(tokens);
+    if cmd_parse::redirects_to_file(tokens) {
+        level.max(Unknown)
+    } else {
+        level
+    }
+}
+
+fn head_risk(tokens: &[Token]) -> RiskLevel {
+    match tokens.first() {
+        Some(Token::Word(word)) if !word.starts_with(|c: char| c.is_ascii_digit()) => {
+            let level = cmdlet_risk(word, &cmd_parse::parameters(tokens));
+            if cmd_parse::calls_method(tokens) {
+                level.max(Unknown)
+            } else {
+                level
+            }
+        }
+        _ => match cmd_parse::classify_statement(tokens) {
+            CmdKind::Read => Read,
+            _ => Unknown,
+        },
+    }
+}
+
+/// Assess a script as its most dangerous statement.
+pub fn assess(script: &str) -> Assessment {
+    let mut assessment = Assessment {
+        level: Unknown,
+        command: None,
+    };
+    for statement in cmd_parse::statements(script) {
+        let level = statement_risk(&statement);
+        if assessment.command.is_none() || level > assessment.level {
+            assessment.level = level;
+            assessment.command = Some(match &statement[0] {
+                Token::Word(w) | Token::Str(w) => w.clone(),
+                Token::Variable(v) => format!("${v}"),
+                other => format!("{other:?}"),
+            });
+        }
+    }
+    assessment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assess() {
+        let level = |s: &str| assess(s).level;
+        assert_eq!(level("Connect-ServiceFabricCluster"), Read);
+        assert_eq!(
+            level("Get-ServiceFabricNode | Select-Object NodeName"),
+            Read
+        );
+        assert_eq!(level("Import-Module ServiceFabric"), Read);
+        assert_eq!(
+            level("Send-ServiceFabricNodeHealthReport -NodeName n1 -HealthState Warning"),
+            SafeWrite
+        );
+        assert_eq!(level("Restart-ServiceFabricNode -NodeName n1"), Disruptive);
+        assert_eq!(level("Start-ServiceFabricChaos"), Disruptive);
+        assert_eq!(level("Invoke-Expression $x"), Unknown);
+        assert_eq!(level("(Get-Item C:\\important).Delete()"), Unknown);
+        assert_eq!(level("$(Get-Item C:\\important).Delete()"), Unknown);
+        assert_eq!(level("Remove-Item C:\\temp"), Destructive);
+        assert_eq!(level(""), Unknown);
+
+        // Redirections write files on the host
+        for op in [">", ">>", "2>", "*>"] {
+            assert_eq!(
+                level(&format!("Get-ServiceFabricNode {op} C:\\x.txt")),
+                Unknown,
+                "{op}"
+            );
+        }
+        assert_eq!(
+            level("Remove-ServiceFabricApplication fabric:/App > C:\\x.txt"),
+            Destructive
+        );
+        assert_eq!(level("Get-ServiceFabricNode 2>&1"), Read);
+
+        // Parameter aware rules
+        assert_eq!(
+            level("Start-ServiceFabricNodeTransition -Start -NodeName n1"),
+            SafeWrite
+        );
+        assert_eq!(
+            level("Start-ServiceFabricNodeTransition -Stop -NodeName n1"),
+            Disruptive
+        );
+        assert_eq!(
+            level("Disable-ServiceFabricNode n1 -Intent Restart"),
+            Disruptive
+        );
+        assert_eq!(
+            level("Disable-ServiceFabricNode n1 -Intent RemoveData -Force"),
+            Destructive
+        );
+        assert_eq!(
+            level("Disable-ServiceFabricNode n1 -Intent Restart -Force"),
+            Destructive
+        );
+        assert_eq!(
+            level("Restart-ServiceFabricNode -NodeName n1 -Force"),
+            Destructive
+        );
+        assert_eq!(
+            level("Remove-ServiceFabricNodeState -NodeName n1 -Force"),
+            Destructive
+        );
+        // PowerShell takes prefixes of parameter names and any dash
+        assert_eq!(
+            level("Restart-ServiceFabricNode -NodeName n1 -Forc"),
+            Destructive
+        );
+        assert_eq!(
+            level("Restart-ServiceFabricNode -NodeName n1 \u{2013}Force"),
+            Destructive
+        );
+        assert_eq!(
+            level("Restart-ServiceFabricNode -NodeName n1 \u{2014}FORCE:$true"),
+            Destructive
+        );
+        assert_eq!(
+            level("Remove-ServiceFabricReplica -PartitionId p -Force"),
+            Destructive
+        );
+        assert_eq!(
+            level("Disable-ServiceFabricNode n1 -Int RemoveNode"),
+            Destructive
+        );
+        // but lowering the level needs the full name
+        assert_eq!(
+            level("Start-ServiceFabricNodeTransition -Sta -NodeName n1"),
+            Disruptive
+        );
+        assert_eq!(
+            level("Remove-ServiceFabricReplica -PartitionId p"),
+            Disruptive
+        );
+        assert_eq!(
+            level("Remove-ServiceFabricReplica -PartitionId p -ForceRemove"),
+            Destructive
+        );
+
+        // Most dangerous statement wins
+        let a = assess(
+            "Get-ServiceFabricApplication | ForEach-Object { Remove-ServiceFabricApplication $_.ApplicationName -Force }",
+        );
+        assert_eq!(a.level, Destructive);
+        assert_eq!(
+            a.command.as_deref(),
+            Some("Remove-ServiceFabricApplication")
+        );
+    }
+}
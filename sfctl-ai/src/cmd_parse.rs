@@ -5,25 +5,69 @@ pub enum CmdKind {
     Unknown,
 }
 
-/// Simple heuristic to classify PowerShell commands into Read, Write, or Unknown
+impl CmdKind {
+    /// Higher is more dangerous.
+    fn rank(&self) -> u8 {
+        match self {
+            CmdKind::Read => 0,
+            CmdKind::Unknown => 1,
+            CmdKind::Write => 2,
+        }
+    }
+
+    /// The more dangerous of the two kinds.
+    pub fn most_dangerous(self, other: CmdKind) -> CmdKind {
+        if other.rank() > self.rank() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// Classify a powershell script as the most dangerous of all the commands in it,
+/// including commands in pipelines, script blocks and sub-expressions.
 pub fn classify_cmd(cmd: &str) -> CmdKind {
-    let cmd = cmd.trim_start();
+    let statements = statements(cmd);
+    if statements.is_empty() {
+        return CmdKind::Unknown;
+    }
+    statements
+        .iter()
+        .map(|s| classify_statement(s))
+        .fold(CmdKind::Read, CmdKind::most_dangerous)
+}
+
+/// Simple heuristic to classify a single cmdlet name into Read, Write, or Unknown
+pub fn classify_cmdlet(name: &str) -> CmdKind {
+    let name = name.to_lowercase();
 
     // Special cases
-    if cmd.starts_with("Import-Module") {
+    if name == "import-module" {
+        return CmdKind::Read;
+    }
+    // Read only helpers commonly used in pipelines
+    if matches!(
+        name.as_str(),
+        "where-object"
+            | "sort-object"
+            | "measure-object"
+            | "format-table"
+            | "format-list"
+            | "out-string"
+            | "convertto-json"
+    ) {
         return CmdKind::Read;
     }
 
-    if cmd.contains("|") {
-        CmdKind::Unknown // Don't classify piped commands
-    } else if cmd.starts_with("Get-") || cmd.starts_with("Select-") || cmd.starts_with("Read-") {
+    if name.starts_with("get-") || name.starts_with("select-") || name.starts_with("read-") {
         CmdKind::Read
-    } else if cmd.starts_with("Set-")
-        || cmd.starts_with("New-")
-        || cmd.starts_with("Add-")
-        || cmd.starts_with("Remove-")
-        || cmd.starts_with("Update-")
-        || cmd.starts_with("Write-")
+    } else if name.starts_with("set-")
+        || name.starts_with("new-")
+        || name.starts_with("add-")
+        || name.starts_with("remove-")
+        || name.starts_with("update-")
+        || name.starts_with("write-")
     {
         CmdKind::Write
     } else {
@@ -31,6 +75,486 @@ pub fn classify_cmd(cmd: &str) -> CmdKind {
     }
 }
 
+/// Classify one pipeline element by its head, ignoring nested commands.
+/// Redirecting output to a file makes a statement at least Unknown.
+pub fn classify_statement(tokens: &[Token]) -> CmdKind {
+    let kind = classify_head(tokens);
+    if redirects_to_file(tokens) {
+        kind.most_dangerous(CmdKind::Unknown)
+    } else {
+        kind
+    }
+}
+
+fn classify_head(tokens: &[Token]) -> CmdKind {
+    let kind = match tokens.first() {
+        None => CmdKind::Read,
+        Some(Token::Word(word)) => {
+            let first = word.chars().next().unwrap_or_default();
+            if first.is_ascii_digit() {
+                // Number literal
+                CmdKind::Read
+            } else {
+                classify_cmdlet(word)
+            }
+        }
+        // Expressions, nested commands are separate statements
+        Some(
+            Token::Str(_)
+            | Token::Variable(_)
+            | Token::ScriptBlock(_)
+            | Token::SubExpression(_)
+            | Token::Hashtable(_),
+        ) => CmdKind::Read,
+        Some(Token::Redirect(_)) => CmdKind::Unknown,
+        Some(Token::Pipe | Token::StatementEnd) => CmdKind::Read,
+    };
+    if calls_method(tokens) {
+        kind.most_dangerous(CmdKind::Unknown)
+    } else {
+        kind
+    }
+}
+
+/// Whether the statement calls a method, e.g. `$file.Delete()` or `(Get-Item x).Delete()`.
+/// Methods can do anything.
+pub fn calls_method(tokens: &[Token]) -> bool {
+    tokens.windows(2).any(|w| {
+        matches!(w, [Token::Word(word), Token::SubExpression(_)]
+            if word.starts_with('.') && word[1..].starts_with(|c: char| c.is_alphabetic() || c == '_'))
+    })
+}
+
+/// All pipeline elements of a script, including the ones nested in script blocks
+/// and sub-expressions. Leading assignments are stripped, so a command statement
+/// starts with the command name.
+pub fn statements(script: &str) -> Vec<Vec<Token>> {
+    let mut result = Vec::new();
+    for statement in split_statements(&tokenize(script)) {
+        let mut tokens = statement.as_slice();
+        // Skip assignments, e.g. `$nodes = Get-ServiceFabricNode`
+        while let [Token::Variable(_), Token::Word(op), rest @ ..] = tokens {
+            if !matches!(op.as_str(), "=" | "+=" | "-=" | "*=" | "/=" | "%=" | "??=") {
+                break;
+            }
+            tokens = rest;
+        }
+        if tokens.is_empty() {
+            continue;
+        }
+        result.push(tokens.to_vec());
+        for token in tokens {
+            nested_statements(token, &mut result);
+        }
+    }
+    result
+}
+
+fn nested_statements(token: &Token, result: &mut Vec<Vec<Token>>) {
+    match token {
+        Token::ScriptBlock(inner) | Token::SubExpression(inner) => {
+            result.extend(statements(inner));
+        }
+        // Hashtable entries are not commands, but values may contain nested commands
+        Token::Hashtable(inner) => {
+            for token in tokenize(inner) {
+                nested_statements(&token, result);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Dashes PowerShell accepts in front of a parameter name.
+const DASHES: [char; 4] = ['-', '\u{2013}', '\u{2014}', '\u{2015}'];
+
+/// Parameters of a command statement, lowercased without the dash,
+/// with the following argument if any. Switches have no value.
+/// PowerShell also takes en and em dashes for the dash, e.g. `–Force`.
+pub fn parameters(tokens: &[Token]) -> Vec<(String, Option<String>)> {
+    let mut params = Vec::new();
+    let mut iter = tokens.iter().skip(1).peekable();
+    while let Some(token) = iter.next() {
+        let Token::Word(word) = token else { continue };
+        let Some(name) = word.strip_prefix(DASHES) else {
+            continue;
+        };
+        if !name.starts_with(|c: char| c.is_alphabetic()) {
+            continue;
+        }
+        // -Name:value
+        if let Some((name, value)) = name.split_once(':') {
+            params.push((name.to_lowercase(), Some(value.to_string())));
+            continue;
+        }
+        let value = match iter.peek() {
+            Some(Token::Word(w)) if !w.starts_with(DASHES) => Some(w.clone()),
+            Some(Token::Str(s)) => Some(s.clone()),
+            _ => None,
+        };
+        if value.is_some() {
+            iter.next();
+        }
+        params.push((name.to_lowercase(), value));
+    }
+    params
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    /// Bare word: command name, parameter, argument or operator.
+    Word(String),
+    /// Quoted string content.
+    Str(String),
+    /// Variable name without the `$`.
+    Variable(String),
+    /// Inner text of `{ ... }`.
+    ScriptBlock(String),
+    /// Inner text of `$( ... )`, `( ... )` or `@( ... )`.
+    SubExpression(String),
+    /// Inner text of `@{ ... }`.
+    Hashtable(String),
+    /// Redirection operator, e.g. `>`, `>>`, `2>`, `*>` or `2>&1`.
+    Redirect(String),
+    /// `|`
+    Pipe,
+    /// `;`, newline, `&&` or `||`
+    StatementEnd,
+}
+
+/// Split tokens into pipeline elements, on statement ends and pipes.
+pub fn split_statements(tokens: &[Token]) -> Vec<Vec<Token>> {
+    tokens
+        .split(|t| matches!(t, Token::Pipe | Token::StatementEnd))
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_vec())
+        .collect()
+}
+
+/// Tokenize a powershell script.
+/// Script blocks and sub-expressions are kept as raw text, to be tokenized recursively.
+pub fn tokenize(script: &str) -> Vec<Token> {
+    let chars: Vec<char> = script.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            ' ' | '\t' | '\r' => i += 1,
+            '\n' | ';' => {
+                tokens.push(Token::StatementEnd);
+                i += 1;
+            }
+            // Line continuation
+            '`' if matches!(next, Some('\n')) => i += 2,
+            '`' if matches!(next, Some('\r')) && chars.get(i + 2) == Some(&'\n') => i += 3,
+            '#' => i = skip_line_comment(&chars, i),
+            '<' if next == Some('#') => i = skip_block_comment(&chars, i),
+            '|' if next == Some('|') => {
+                tokens.push(Token::StatementEnd);
+                i += 2;
+            }
+            '|' => {
+                tokens.push(Token::Pipe);
+                i += 1;
+            }
+            '&' if next == Some('&') => {
+                tokens.push(Token::StatementEnd);
+                i += 2;
+            }
+            '\'' => {
+                let (s, end) = read_single_quoted(&chars, i + 1);
+                tokens.push(Token::Str(s));
+                i = end;
+            }
+            '"' => {
+                let (s, embedded, end) = read_double_quoted(&chars, i + 1);
+                tokens.push(Token::Str(s));
+                tokens.extend(embedded.into_iter().map(Token::SubExpression));
+                i = end;
+            }
+            '@' if matches!(next, Some('\'') | Some('"')) => {
+                let (s, embedded, end) = read_here_string(&chars, i + 1);
+                tokens.push(Token::Str(s));
+                tokens.extend(embedded.into_iter().map(Token::SubExpression));
+                i = end;
+            }
+            '{' => {
+                let (inner, end) = read_balanced(&chars, i + 1, '{', '}');
+                tokens.push(Token::ScriptBlock(inner));
+                i = end;
+            }
+            '(' => {
+                let (inner, end) = read_balanced(&chars, i + 1, '(', ')');
+                tokens.push(Token::SubExpression(inner));
+                i = end;
+            }
+            '@' if next == Some('(') => {
+                let (inner, end) = read_balanced(&chars, i + 2, '(', ')');
+                tokens.push(Token::SubExpression(inner));
+                i = end;
+            }
+            '@' if next == Some('{') => {
+                let (inner, end) = read_balanced(&chars, i + 2, '{', '}');
+                tokens.push(Token::Hashtable(inner));
+                i = end;
+            }
+            '$' if next == Some('(') => {
+                let (inner, end) = read_balanced(&chars, i + 2, '(', ')');
+                tokens.push(Token::SubExpression(inner));
+                i = end;
+            }
+            '$' if next == Some('{') => {
+                // Braced variable name, e.g. ${my var}
+                let end = find_char(&chars, i + 2, '}');
+                tokens.push(Token::Variable(chars[i + 2..end].iter().collect()));
+                i = (end + 1).min(chars.len());
+            }
+            '$' => {
+                let mut end = i + 1;
+                while end < chars.len()
+                    && (chars[end].is_alphanumeric() || matches!(chars[end], '_' | ':' | '?'))
+                {
+                    end += 1;
+                }
+                tokens.push(Token::Variable(chars[i + 1..end].iter().collect()));
+                i = end;
+            }
+            '=' => {
+                tokens.push(Token::Word("=".to_string()));
+                i += 1;
+            }
+            '>' => {
+                let end = read_redirect(&chars, i);
+                tokens.push(Token::Redirect(chars[i..end].iter().collect()));
+                i = end;
+            }
+            // Stream redirection, e.g. 2> or *>>
+            '1'..='6' | '*' if next == Some('>') && starts_token(&chars, i) => {
+                let end = read_redirect(&chars, i + 1);
+                tokens.push(Token::Redirect(chars[i..end].iter().collect()));
+                i = end;
+            }
+            // Unmatched closing brackets
+            '}' | ')' => i += 1,
+            _ => {
+                let (word, end) = read_word(&chars, i);
+                tokens.push(Token::Word(word));
+                i = end;
+            }
+        }
+    }
+    tokens
+}
+
+fn find_char(chars: &[char], start: usize, c: char) -> usize {
+    chars[start.min(chars.len())..]
+        .iter()
+        .position(|&x| x == c)
+        .map_or(chars.len(), |p| start + p)
+}
+
+/// Returns the index of the newline ending the comment.
+fn skip_line_comment(chars: &[char], start: usize) -> usize {
+    find_char(chars, start, '\n')
+}
+
+/// Returns the index after `#>`.
+fn skip_block_comment(chars: &[char], start: usize) -> usize {
+    let mut i = start + 2;
+    while i < chars.len() {
+        if chars[i] == '#' && chars.get(i + 1) == Some(&'>') {
+            return i + 2;
+        }
+        i += 1;
+    }
+    chars.len()
+}
+
+/// Whether a token may start at the index, i.e. it follows whitespace or a separator.
+fn starts_token(chars: &[char], i: usize) -> bool {
+    i == 0 || chars[i - 1].is_whitespace() || matches!(chars[i - 1], ';' | '|' | '{' | '(')
+}
+
+/// Read a redirection operator starting at its `>`: `>`, `>>` or a stream merge like `>&1`.
+/// Returns the index after the operator.
+fn read_redirect(chars: &[char], start: usize) -> usize {
+    let mut i = start + 1;
+    if chars.get(i) == Some(&'>') {
+        i += 1;
+    } else if chars.get(i) == Some(&'&') && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
+        i += 2;
+    }
+    i
+}
+
+/// Whether the statement redirects output to a file, e.g. `> C:\x.txt` or `2>> log.txt`.
+/// Merging streams, e.g. `2>&1`, and discarding output with `> $null` do not write files.
+pub fn redirects_to_file(tokens: &[Token]) -> bool {
+    tokens.iter().enumerate().any(|(i, token)| match token {
+        Token::Redirect(op) => {
+            !op.contains('&')
+                && !matches!(tokens.get(i + 1), Some(Token::Variable(v)) if v.eq_ignore_ascii_case("null"))
+        }
+        _ => false,
+    })
+}
+
+/// Read a bare word until whitespace or a special character.
+/// A backtick escapes the next character.
+fn read_word(chars: &[char], start: usize) -> (String, usize) {
+    let mut word = String::new();
+    let mut i = start;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => break,
+            ';' | '|' | '{' | '}' | '(' | ')' | '\'' | '"' | '>' => break,
+            '&' if chars.get(i + 1) == Some(&'&') => break,
+            '`' => {
+                if let Some(&escaped) = chars.get(i + 1) {
+                    word.push(escaped);
+                }
+                i += 2;
+                continue;
+            }
+            c => word.push(c),
+        }
+        i += 1;
+    }
+    // A lone & is the call operator
+    if word.is_empty() {
+        word.push(chars[start]);
+        i = start + 1;
+    }
+    (word, i)
+}
+
+/// Read a single quoted string starting after the opening quote.
+/// `''` is an escaped quote. Returns the content and the index after the closing quote.
+fn read_single_quoted(chars: &[char], start: usize) -> (String, usize) {
+    let mut s = String::new();
+    let mut i = start;
+    while i < chars.len() {
+        if chars[i] == '\'' {
+            if chars.get(i + 1) == Some(&'\'') {
+                s.push('\'');
+                i += 2;
+                continue;
+            }
+            return (s, i + 1);
+        }
+        s.push(chars[i]);
+        i += 1;
+    }
+    (s, i)
+}
+
+/// Read a double quoted string starting after the opening quote.
+/// Returns the content, the embedded `$( ... )` sub-expressions and the index after the closing quote.
+fn read_double_quoted(chars: &[char], start: usize) -> (String, Vec<String>, usize) {
+    let mut s = String::new();
+    let mut embedded = Vec::new();
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '`' => {
+                if let Some(&escaped) = chars.get(i + 1) {
+                    s.push(escaped);
+                }
+                i += 2;
+            }
+            '"' if chars.get(i + 1) == Some(&'"') => {
+                s.push('"');
+                i += 2;
+            }
+            '"' => return (s, embedded, i + 1),
+            '$' if chars.get(i + 1) == Some(&'(') => {
+                let (inner, end) = read_balanced(chars, i + 2, '(', ')');
+                s.extend(&chars[i..end.min(chars.len())]);
+                embedded.push(inner);
+                i = end;
+            }
+            c => {
+                s.push(c);
+                i += 1;
+            }
+        }
+    }
+    (s, embedded, i.min(chars.len()))
+}
+
+/// Read a here-string starting at the quote after `@`.
+/// It ends with the quote followed by `@` at the start of a line.
+fn read_here_string(chars: &[char], start: usize) -> (String, Vec<String>, usize) {
+    let quote = chars[start];
+    let mut s = String::new();
+    let mut embedded = Vec::new();
+    let mut i = start + 1;
+    while i < chars.len() {
+        if chars[i] == '\n' && chars.get(i + 1) == Some(&quote) && chars.get(i + 2) == Some(&'@') {
+            return (s, embedded, i + 3);
+        }
+        if quote == '"' && chars[i] == '$' && chars.get(i + 1) == Some(&'(') {
+            let (inner, end) = read_balanced(chars, i + 2, '(', ')');
+            s.extend(&chars[i..end.min(chars.len())]);
+            embedded.push(inner);
+            i = end;
+            continue;
+        }
+        s.push(chars[i]);
+        i += 1;
+    }
+    (s, embedded, chars.len())
+}
+
+/// Read until the bracket matching the already consumed opening bracket,
+/// skipping strings and comments. Returns the inner text and the index after the closing bracket.
+fn read_balanced(chars: &[char], start: usize, open: char, close: char) -> (String, usize) {
+    let mut depth = 1;
+    let mut i = start;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '`' => {
+                i += 2;
+                continue;
+            }
+            '\'' => {
+                i = read_single_quoted(chars, i + 1).1;
+                continue;
+            }
+            '"' => {
+                i = read_double_quoted(chars, i + 1).2;
+                continue;
+            }
+            '#' if i == start || chars[i - 1].is_whitespace() || chars[i - 1] == ';' => {
+                i = skip_line_comment(chars, i);
+                continue;
+            }
+            '<' if chars.get(i + 1) == Some(&'#') => {
+                i = skip_block_comment(chars, i);
+                continue;
+            }
+            c if c == open => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return (chars[start..i].iter().collect(), i + 1);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    // Unbalanced, take the rest
+    (
+        chars[start.min(chars.len())..].iter().collect(),
+        chars.len(),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -44,4 +568,204 @@ mod tests {
             CmdKind::Unknown
         );
     }
+
+    #[test]
+    fn test_classify_script() {
+        // Statements
+        assert_eq!(
+            classify_cmd("Get-ServiceFabricNode; Remove-ServiceFabricApplication fabric:/App"),
+            CmdKind::Write
+        );
+        assert_eq!(
+            classify_cmd("Get-ServiceFabricNode\nRestart-ServiceFabricNode -NodeName n1"),
+            CmdKind::Unknown
+        );
+        assert_eq!(
+            classify_cmd("Get-ServiceFabricNode && Remove-ServiceFabricNodeState -NodeName n1"),
+            CmdKind::Write
+        );
+
+        // Pipelines
+        assert_eq!(
+            classify_cmd(
+                "Get-ServiceFabricService -ApplicationName fabric:/System | Select-Object -Property ServiceName -First 1"
+            ),
+            CmdKind::Read
+        );
+        assert_eq!(
+            classify_cmd("Get-ServiceFabricApplication | Remove-ServiceFabricApplication -Force"),
+            CmdKind::Write
+        );
+
+        // Quoting
+        assert_eq!(
+            classify_cmd(
+                "Get-ServiceFabricApplication -ApplicationName 'fabric:/a; Remove-Item x'"
+            ),
+            CmdKind::Read
+        );
+        assert_eq!(
+            classify_cmd(
+                r#"Get-ServiceFabricApplication -ApplicationName "fabric:/a | Remove-Item""#
+            ),
+            CmdKind::Read
+        );
+        assert_eq!(
+            classify_cmd(r#"Write-Output "Node: $(Remove-ServiceFabricNodeState -NodeName n1)""#),
+            CmdKind::Write
+        );
+        assert_eq!(
+            classify_cmd(r#"Get-ServiceFabricNode -NodeName "$(Restart-ServiceFabricNode n1)""#),
+            CmdKind::Unknown
+        );
+
+        // Script blocks and sub-expressions
+        assert_eq!(
+            classify_cmd(
+                "Get-ServiceFabricApplication | ForEach-Object { Remove-ServiceFabricApplication $_.ApplicationName }"
+            ),
+            CmdKind::Write
+        );
+        assert_eq!(
+            classify_cmd("Get-ServiceFabricNode | Where-Object { $_.HealthState -ne 'Ok' }"),
+            CmdKind::Read
+        );
+        assert_eq!(
+            classify_cmd("Get-ServiceFabricNode -NodeName (Remove-Item x)"),
+            CmdKind::Write
+        );
+        assert_eq!(
+            classify_cmd("$nodes = Get-ServiceFabricNode; $nodes.Count"),
+            CmdKind::Read
+        );
+        assert_eq!(
+            classify_cmd("$nodes=@(Get-ServiceFabricNode); $nodes[0].NodeName"),
+            CmdKind::Read
+        );
+        assert_eq!(
+            classify_cmd("$app = @{ Name = $(New-ServiceFabricApplication fabric:/a t 1) }"),
+            CmdKind::Write
+        );
+        assert_eq!(classify_cmd("$file.Delete()"), CmdKind::Unknown);
+        assert_eq!(
+            classify_cmd("(Get-Item C:\\important).Delete()"),
+            CmdKind::Unknown
+        );
+        assert_eq!(
+            classify_cmd("$(Get-Item C:\\important).Delete()"),
+            CmdKind::Unknown
+        );
+        assert_eq!(
+            classify_cmd("@{ a = 1 }.Clear(); { 1 }.Invoke()"),
+            CmdKind::Unknown
+        );
+        assert_eq!(
+            classify_cmd("(Get-ServiceFabricNode).NodeName"),
+            CmdKind::Read
+        );
+        assert_eq!(
+            classify_cmd("[System.IO.File]::Delete('x')"),
+            CmdKind::Unknown
+        );
+
+        // Comments
+        assert_eq!(
+            classify_cmd("# Remove-ServiceFabricApplication\nGet-ServiceFabricNode"),
+            CmdKind::Read
+        );
+        assert_eq!(
+            classify_cmd("Get-ServiceFabricNode <# ; Remove-Item x #>"),
+            CmdKind::Read
+        );
+
+        // Redirections write files
+        for script in [
+            r"Get-ServiceFabricNode > C:\x.txt",
+            r"Get-ServiceFabricNode >> C:\x.txt",
+            r"Get-ServiceFabricNode 2> C:\x.txt",
+            r"Get-ServiceFabricNode 2>> C:\x.txt",
+            r"Get-ServiceFabricNode *> C:\x.txt",
+            r"Get-ServiceFabricNode>C:\x.txt",
+            r"Get-ServiceFabricNode | Select-Object NodeName > 'C:\x y.txt'",
+        ] {
+            assert_eq!(classify_cmd(script), CmdKind::Unknown, "{script}");
+        }
+        assert_eq!(classify_cmd("Get-ServiceFabricNode 2>&1"), CmdKind::Read);
+        assert_eq!(classify_cmd("Get-ServiceFabricNode > $null"), CmdKind::Read);
+        assert_eq!(
+            classify_cmd("Get-ServiceFabricNode -NodeName 'a > b'"),
+            CmdKind::Read
+        );
+
+        // Case insensitive
+        assert_eq!(
+            classify_cmd("remove-servicefabricapplication fabric:/App"),
+            CmdKind::Write
+        );
+        assert_eq!(classify_cmd(""), CmdKind::Unknown);
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("Get-X -Name 'a b' | Select-Object { $_.Name }"),
+            vec![
+                Token::Word("Get-X".to_string()),
+                Token::Word("-Name".to_string()),
+                Token::Str("a b".to_string()),
+                Token::Pipe,
+                Token::Word("Select-Object".to_string()),
+                Token::ScriptBlock(" $_.Name ".to_string()),
+            ]
+        );
+        assert_eq!(
+            tokenize("$x = \"it's `\"$(Get-Y)`\"\" ; `\n@{a=1}"),
+            vec![
+                Token::Variable("x".to_string()),
+                Token::Word("=".to_string()),
+                Token::Str("it's \"$(Get-Y)\"".to_string()),
+                Token::SubExpression("Get-Y".to_string()),
+                Token::StatementEnd,
+                Token::Hashtable("a=1".to_string()),
+            ]
+        );
+        assert_eq!(
+            split_statements(&tokenize("a | b; c\n\nd")).len(),
+            4,
+            "empty statements are dropped"
+        );
+        assert_eq!(
+            statements("$x = Get-X -Id (Get-Y); Invoke-Command { Remove-Z }")
+                .iter()
+                .map(|s| s[0].clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Token::Word("Get-X".to_string()),
+                Token::Word("Get-Y".to_string()),
+                Token::Word("Invoke-Command".to_string()),
+                Token::Word("Remove-Z".to_string()),
+            ]
+        );
+        assert_eq!(
+            tokenize("Get-X >> a.txt 2>&1 *>b"),
+            vec![
+                Token::Word("Get-X".to_string()),
+                Token::Redirect(">>".to_string()),
+                Token::Word("a.txt".to_string()),
+                Token::Redirect("2>&1".to_string()),
+                Token::Redirect("*>".to_string()),
+                Token::Word("b".to_string()),
+            ]
+        );
+        assert_eq!(
+            parameters(&tokenize(
+                "Disable-X -NodeName 'n 1' -Intent:RemoveData -Force"
+            )),
+            vec![
+                ("nodename".to_string(), Some("n 1".to_string())),
+                ("intent".to_string(), Some("RemoveData".to_string())),
+                ("force".to_string(), None),
+            ]
+        );
+    }
 }
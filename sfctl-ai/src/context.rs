@@ -0,0 +1,313 @@
+use genai::chat::{ChatMessage, ChatRequest, ChatRole, MessageContent, Tool};
+use serde::Deserialize;
+use serde_json::json;
+
+pub const DEFAULT_CONTEXT_BUDGET: usize = 32_000;
+pub const DEFAULT_MAX_OUTPUT_TOKENS: usize = 4_000;
+
+/// Name of the native tool the model calls to read a truncated command output.
+pub const READ_OUTPUT_TOOL_NAME: &str = "read_command_output";
+
+/// Rough characters per token of English text and command output.
+const CHARS_PER_TOKEN: usize = 4;
+/// Tokens the provider adds for each message, e.g. the role.
+const MESSAGE_OVERHEAD: usize = 4;
+/// Characters kept of each message when rendering old turns to summarize them.
+const SUMMARY_MESSAGE_CHARS: usize = 2_000;
+
+/// Limits on what is sent to the model.
+#[derive(Debug, Clone, clap::Args)]
+pub struct ContextConfig {
+    /// Estimated tokens of chat history sent to the model. Older turns are summarized above it
+    #[arg(long, env = "SFCTL_AI_CONTEXT_BUDGET", default_value_t = DEFAULT_CONTEXT_BUDGET, global = true)]
+    pub context_budget: usize,
+
+    /// Estimated tokens of a single command output sent to the model.
+    /// Longer outputs are truncated, the model can read the rest in parts
+    #[arg(long, env = "SFCTL_AI_MAX_OUTPUT_TOKENS", default_value_t = DEFAULT_MAX_OUTPUT_TOKENS, global = true)]
+    pub max_output_tokens: usize,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            context_budget: DEFAULT_CONTEXT_BUDGET,
+            max_output_tokens: DEFAULT_MAX_OUTPUT_TOKENS,
+        }
+    }
+}
+
+impl ContextConfig {
+    /// Max characters of a command output sent at once.
+    pub fn max_output_chars(&self) -> usize {
+        self.max_output_tokens * CHARS_PER_TOKEN
+    }
+}
+
+/// Tool definition for reading parts of a truncated command output.
+/// The arguments match [`ReadOutputArgs`].
+pub fn read_output_tool() -> Tool {
+    Tool::new(READ_OUTPUT_TOOL_NAME)
+        .with_description(
+            "Read part of a command output that was truncated because it was too long",
+        )
+        .with_schema(json!({
+            "type": "object",
+            "properties": {
+                "id": {
+                    "type": "integer",
+                    "description": "Output id given in the truncation notice"
+                },
+                "offset": {
+                    "type": "integer",
+                    "description": "Character offset to start reading at, 0 by default"
+                },
+                "length": {
+                    "type": "integer",
+                    "description": "Number of characters to read, as much as allowed by default"
+                }
+            },
+            "required": ["id"]
+        }))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ReadOutputArgs {
+    pub id: usize,
+    #[serde(default)]
+    pub offset: usize,
+    pub length: Option<usize>,
+}
+
+/// Estimated tokens of a text.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Estimated tokens of a message, including the tool calls and responses.
+pub fn message_tokens(message: &ChatMessage) -> usize {
+    let content = match &message.content {
+        MessageContent::Text(text) => estimate_tokens(text),
+        MessageContent::ToolCalls(calls) => calls
+            .iter()
+            .map(|c| estimate_tokens(&c.fn_name) + estimate_tokens(&c.fn_arguments.to_string()))
+            .sum(),
+        MessageContent::ToolResponses(responses) => {
+            responses.iter().map(|r| estimate_tokens(&r.content)).sum()
+        }
+        content => content.text().map(estimate_tokens).unwrap_or_default(),
+    };
+    content + MESSAGE_OVERHEAD
+}
+
+/// Estimated tokens of the whole request: system prompt, tools and messages.
+pub fn request_tokens(req: &ChatRequest) -> usize {
+    let system = req
+        .system
+        .as_deref()
+        .map(estimate_tokens)
+        .unwrap_or_default();
+    let tools = req
+        .tools
+        .iter()
+        .flatten()
+        .map(|t| {
+            serde_json::to_string(t)
+                .map(|s| estimate_tokens(&s))
+                .unwrap_or_default()
+        })
+        .sum::<usize>();
+    system + tools + req.messages.iter().map(message_tokens).sum::<usize>()
+}
+
+/// Shorten an output longer than `max_chars`, keeping its start and end.
+/// The notice tells the model how to read the rest with [`READ_OUTPUT_TOOL_NAME`].
+/// Returns None if the output fits.
+pub fn truncate_output(output: &str, max_chars: usize, id: usize) -> Option<String> {
+    let chars: Vec<char> = output.chars().collect();
+    if chars.len() <= max_chars {
+        return None;
+    }
+    let head_end = cut_at_line(&chars, max_chars * 2 / 3);
+    let tail_start = chars.len() - max_chars / 3;
+    // start the tail on a new line if there is one close by
+    let tail_start = chars[tail_start..]
+        .iter()
+        .position(|&c| c == '\n')
+        .filter(|&p| p < max_chars / 6)
+        .map_or(tail_start, |p| tail_start + p + 1);
+    let head: String = chars[..head_end].iter().collect();
+    let tail: String = chars[tail_start..].iter().collect();
+    Some(format!(
+        "{head}\n[... characters {head_end}-{tail_start} of {} omitted. The full output is saved as output {id}, call {READ_OUTPUT_TOOL_NAME} to read the omitted part ...]\n{tail}",
+        chars.len()
+    ))
+}
+
+/// End of the head at the last line break before `max`, or `max` if there is none close by.
+fn cut_at_line(chars: &[char], max: usize) -> usize {
+    chars[..max]
+        .iter()
+        .rposition(|&c| c == '\n')
+        .filter(|&p| p > max / 2)
+        .unwrap_or(max)
+}
+
+/// The requested part of a saved output, at most `max_chars` long.
+pub fn read_output(output: &str, id: usize, args: &ReadOutputArgs, max_chars: usize) -> String {
+    let total = output.chars().count();
+    let start = args.offset.min(total);
+    let length = args.length.unwrap_or(max_chars).min(max_chars);
+    let part: String = output.chars().skip(start).take(length).collect();
+    let end = start + part.chars().count();
+    format!("Output {id}, characters {start}-{end} of {total}:\n{part}")
+}
+
+/// Index of the first message to keep so the kept messages fit in `keep_tokens`.
+/// The kept part starts at a user message, so tool calls stay with their responses.
+/// Returns 0 if nothing can be summarized.
+pub fn summary_split(messages: &[ChatMessage], keep_tokens: usize) -> usize {
+    let is_turn_start = |m: &ChatMessage| {
+        matches!(m.role, ChatRole::User) && matches!(m.content, MessageContent::Text(_))
+    };
+    let mut kept = 0;
+    let mut split = None;
+    for (i, message) in messages.iter().enumerate().rev() {
+        kept += message_tokens(message);
+        if kept > keep_tokens {
+            // even the last turn is too large, keep at least that one
+            return split
+                .or_else(|| messages.iter().rposition(is_turn_start))
+                .unwrap_or(0);
+        }
+        if is_turn_start(message) {
+            split = Some(i);
+        }
+    }
+    0
+}
+
+/// Render messages as a transcript for the summarizing request.
+pub fn render_transcript(messages: &[ChatMessage]) -> String {
+    let clip = |text: &str| -> String {
+        if text.chars().count() <= SUMMARY_MESSAGE_CHARS {
+            text.to_string()
+        } else {
+            let clipped: String = text.chars().take(SUMMARY_MESSAGE_CHARS).collect();
+            format!("{clipped} [...]")
+        }
+    };
+    let mut lines = Vec::new();
+    for message in messages {
+        match &message.content {
+            MessageContent::ToolCalls(calls) => {
+                for call in calls {
+                    lines.push(format!(
+                        "assistant called {}: {}",
+                        call.fn_name,
+                        clip(&call.fn_arguments.to_string())
+                    ));
+                }
+            }
+            MessageContent::ToolResponses(responses) => {
+                for response in responses {
+                    lines.push(format!("tool: {}", clip(&response.content)));
+                }
+            }
+            content => {
+                if let Some(text) = content.text() {
+                    let role = message.role.to_string().to_lowercase();
+                    lines.push(format!("{role}: {}", clip(text)));
+                }
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use genai::chat::{ToolCall, ToolResponse};
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(
+            message_tokens(&ChatMessage::user("abcd")),
+            1 + MESSAGE_OVERHEAD
+        );
+        let req = ChatRequest::default()
+            .with_system("abcdefgh")
+            .append_message(ChatMessage::user("abcd"));
+        assert_eq!(request_tokens(&req), 2 + 1 + MESSAGE_OVERHEAD);
+    }
+
+    #[test]
+    fn test_truncate_output() {
+        assert_eq!(truncate_output("short", 100, 1), None);
+
+        let output: String = (0..100).map(|i| format!("line {i:02}\n")).collect();
+        let truncated = truncate_output(&output, 200, 3).unwrap();
+        assert!(truncated.starts_with("line 00\n"), "{truncated}");
+        assert!(truncated.ends_with("line 99\n"), "{truncated}");
+        assert!(truncated.contains("saved as output 3"), "{truncated}");
+        assert!(truncated.chars().count() < 400, "{truncated}");
+
+        // single line json is cut anywhere
+        let json = "x".repeat(1000);
+        let truncated = truncate_output(&json, 300, 1).unwrap();
+        assert!(
+            truncated.contains("characters 200-900 of 1000 omitted"),
+            "{truncated}"
+        );
+
+        let args = ReadOutputArgs {
+            id: 1,
+            offset: 995,
+            length: None,
+        };
+        assert_eq!(
+            read_output(&json, 1, &args, 300),
+            "Output 1, characters 995-1000 of 1000:\nxxxxx"
+        );
+        let args = ReadOutputArgs {
+            id: 1,
+            offset: 0,
+            length: Some(1000),
+        };
+        assert_eq!(
+            read_output(&json, 1, &args, 3),
+            "Output 1, characters 0-3 of 1000:\nxxx"
+        );
+    }
+
+    #[test]
+    fn test_summary_split() {
+        let messages = vec![
+            ChatMessage::user("first question"),
+            ChatMessage::from(vec![ToolCall {
+                call_id: "call_1".to_string(),
+                fn_name: "run_pwsh_command".to_string(),
+                fn_arguments: json!({ "command": "Get-ServiceFabricNode" }),
+            }]),
+            ChatMessage::from(ToolResponse::new("call_1", "x".repeat(400))),
+            ChatMessage::assistant("first answer"),
+            ChatMessage::user("second question"),
+            ChatMessage::assistant("second answer"),
+        ];
+        // keeps the last turn, the tool call stays with its response
+        assert_eq!(summary_split(&messages, 30), 4);
+        assert_eq!(summary_split(&messages, 1000), 0);
+        // the last turn alone is too large, it is still kept
+        assert_eq!(summary_split(&messages, 5), 4);
+
+        let transcript = render_transcript(&messages[..2]);
+        assert_eq!(
+            transcript,
+            "user: first question\nassistant called run_pwsh_command: {\"command\":\"Get-ServiceFabricNode\"}"
+        );
+    }
+}
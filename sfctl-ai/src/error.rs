@@ -0,0 +1,78 @@
+use std::fmt;
+
+/// Errors of the assistant, the chat loop reports them and goes on with the next question.
+#[derive(Debug)]
+pub enum Error {
+    /// The model request failed, e.g. the provider rejected it or the stream broke.
+    Provider(Box<genai::Error>),
+    /// The command backend failed, e.g. pwsh could not be started.
+    Backend(std::io::Error),
+    /// A response could not be used, e.g. the model returned nothing.
+    Parse(String),
+    /// The policy file is invalid.
+    Policy(String),
+    /// The provider or other settings are invalid.
+    Config(String),
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Provider(e) => write!(f, "Model request failed: {e}"),
+            Error::Backend(e) => write!(f, "{e}"),
+            Error::Parse(message) => write!(f, "Invalid response: {message}"),
+            Error::Policy(message) => write!(f, "{message}"),
+            Error::Config(message) => write!(f, "Invalid configuration: {message}"),
+            Error::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Provider(e) => Some(e.as_ref()),
+            Error::Backend(e) | Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<genai::Error> for Error {
+    fn from(e: genai::Error) -> Self {
+        Error::Provider(Box::new(e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Say what was being done in an IO error, keeping its kind.
+pub(crate) fn io_context(e: std::io::Error, context: impl fmt::Display) -> std::io::Error {
+    std::io::Error::new(e.kind(), format!("{context}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let e = Error::Backend(io_context(
+            std::io::Error::new(std::io::ErrorKind::NotFound, "program not found"),
+            "Failed to start pwsh",
+        ));
+        assert_eq!(e.to_string(), "Failed to start pwsh: program not found");
+        assert!(std::error::Error::source(&e).is_some());
+        assert_eq!(
+            Error::Parse("the model returned an empty response".to_string()).to_string(),
+            "Invalid response: the model returned an empty response"
+        );
+    }
+}
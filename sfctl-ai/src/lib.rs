@@ -1,14 +1,126 @@
 use tokio_util::sync::CancellationToken;
 pub mod ack;
 pub mod ai;
+pub mod audit;
+pub mod backend;
+pub mod catalog;
 pub mod cmd_parse;
+pub mod context;
+pub mod error;
 pub mod model;
+pub mod policy;
+pub mod pool;
+pub mod provider;
 pub mod pwsh;
+pub mod rest;
+pub mod retry;
+pub mod session;
 
-pub async fn app_loop(token: CancellationToken) {
-    let ai_conn = ai::AiConnection::new().unwrap();
-    let mut chat = ai_conn.create_chat();
-    println!("Welcome");
+pub use error::{Error, Result};
+
+/// Everything needed to start a chat with a cluster.
+#[derive(Debug, Clone, Default)]
+pub struct ChatConfig {
+    pub ai: provider::AiConfig,
+    pub backend: backend::BackendConfig,
+    pub policy: policy::Policy,
+    pub audit: audit::AuditConfig,
+    pub approval: ack::ApprovalMode,
+    pub context: context::ContextConfig,
+    pub limits: ai::LoopConfig,
+    pub retry: retry::RetryConfig,
+    /// Cluster connection endpoint to connect to before the chat starts.
+    /// Defaults to the cluster of a resumed session.
+    pub endpoint: Option<String>,
+    /// Save the conversation, None to keep it in memory only.
+    pub session: Option<session::SessionConfig>,
+}
+
+/// Create the chat with its backend, policy and audit log, connected to the configured cluster.
+pub async fn create_chat(config: &ChatConfig) -> Result<ai::AiChat> {
+    if let Some(endpoint) = &config.endpoint
+        && !pwsh::is_endpoint(endpoint)
+    {
+        return Err(Error::Config(format!(
+            "Invalid endpoint, expected host:port: {endpoint}"
+        )));
+    }
+    let ai_conn = ai::AiConnection::new(config.ai.clone())?;
+    let backend = config.backend.create_backend()?;
+    let mut chat = ai_conn.create_chat_with_backend(backend);
+    chat.set_policy(config.policy.clone());
+    chat.set_approval_mode(config.approval);
+    chat.set_context_config(config.context.clone());
+    chat.set_loop_config(config.limits.clone());
+    chat.set_retry_config(config.retry.clone());
+    let audit = audit::AuditLog::open(&config.audit.audit_log).map_err(|e| {
+        error::io_context(
+            e,
+            format!(
+                "Failed to open audit log {}",
+                config.audit.audit_log.display()
+            ),
+        )
+    })?;
+    chat.set_audit_log(std::sync::Arc::new(audit));
+
+    let mut endpoint = config.endpoint.clone();
+    if let Some(session_config) = &config.session {
+        let store = session::SessionStore::new(&session_config.session_dir);
+        let session = match &session_config.resume {
+            Some(id) => store
+                .load(id)
+                .map_err(|e| error::io_context(e, "Failed to resume session"))?,
+            None => session::Session::new(),
+        };
+        if endpoint.is_none()
+            && let Some(cluster) = &session.cluster
+        {
+            // the session file is not trusted more than the command line
+            if !pwsh::is_endpoint(cluster) {
+                return Err(Error::Config(format!(
+                    "Invalid endpoint in the resumed session, expected host:port: {cluster}"
+                )));
+            }
+            endpoint = Some(cluster.clone());
+        }
+        chat.set_session(store, session);
+    }
+
+    if let Some(endpoint) = &endpoint {
+        // run through the chat so the connection is checked by the policy and audited
+        for command in [
+            "Import-Module ServiceFabric".to_string(),
+            format!(
+                "Connect-ServiceFabricCluster -ConnectionEndpoint {}",
+                pwsh::quote(endpoint)
+            ),
+        ] {
+            let (outcome, output) = chat.run_command(&command, false).await;
+            if outcome != audit::Outcome::Succeeded {
+                return Err(Error::Backend(std::io::Error::other(format!(
+                    "Failed to connect to {endpoint}:\n{output}"
+                ))));
+            }
+        }
+        tracing::info!("Connected to {}", endpoint);
+    }
+    Ok(chat)
+}
+
+/// Chat with the user until the token is cancelled. Fails if the chat cannot be created.
+pub async fn app_loop(token: CancellationToken, config: ChatConfig) -> Result<()> {
+    let mut chat = create_chat(&config).await.inspect_err(|e| {
+        tracing::info!("{e}");
+    })?;
+    match (
+        chat.session_id(),
+        config.session.as_ref().and_then(|s| s.resume.as_ref()),
+    ) {
+        (Some(id), Some(_)) => println!("Welcome back, resuming session {id}"),
+        (Some(id), None) => println!("Welcome, session {id}"),
+        _ => println!("Welcome"),
+    }
     loop {
         println!(">");
         tokio::select! {
@@ -23,6 +135,7 @@ pub async fn app_loop(token: CancellationToken) {
         }
     }
     tracing::info!("Exiting app loop.");
+    Ok(())
 }
 
 // #[cfg(test)]
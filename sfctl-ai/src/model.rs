@@ -1,9 +1,13 @@
+use genai::chat::ToolCall;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Action {
     pub reason: String,
     pub command: String,
+    /// Return the command output as json instead of formatted text.
+    #[serde(default)]
+    pub json: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,6 +44,15 @@ pub fn get_response_enum_from_response_str(input: &str) -> Vec<ResponseEnum> {
     responses
 }
 
+/// Parse the arguments of a native tool call into an [`Action`].
+pub fn get_action_from_tool_call(tool_call: &ToolCall) -> Result<Action, serde_json::Error> {
+    match &tool_call.fn_arguments {
+        // Some providers send the arguments as a json encoded string
+        serde_json::Value::String(s) => serde_json::from_str::<Action>(s),
+        v => serde_json::from_value::<Action>(v.clone()),
+    }
+}
+
 fn extract_json_blocks(input: &str) -> Vec<String> {
     let mut blocks = Vec::new();
     let mut rest = input;
@@ -118,6 +131,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_get_action_from_tool_call() {
+        let tool_call = ToolCall {
+            call_id: "call_1".to_string(),
+            fn_name: "run_pwsh_command".to_string(),
+            fn_arguments: serde_json::json!({
+                "reason": "check cluster health",
+                "command": "Get-ServiceFabricClusterHealth"
+            }),
+        };
+        let action = get_action_from_tool_call(&tool_call).unwrap();
+        assert_eq!(action.reason, "check cluster health");
+        assert_eq!(action.command, "Get-ServiceFabricClusterHealth");
+        assert!(!action.json);
+
+        let tool_call = ToolCall {
+            fn_arguments: serde_json::Value::String(
+                r#"{"reason": "list nodes", "command": "Get-ServiceFabricNode", "json": true}"#
+                    .to_string(),
+            ),
+            ..tool_call
+        };
+        let action = get_action_from_tool_call(&tool_call).unwrap();
+        assert_eq!(action.command, "Get-ServiceFabricNode");
+        assert!(action.json);
+
+        let tool_call = ToolCall {
+            fn_arguments: serde_json::json!({ "cmd": "Get-ServiceFabricNode" }),
+            ..tool_call
+        };
+        assert!(get_action_from_tool_call(&tool_call).is_err());
+    }
+
     #[test]
     fn test_extract_code_blocks() {
         let mycode = r#"
@@ -0,0 +1,353 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::catalog::{self, RiskLevel};
+use crate::cmd_parse::{self, Token};
+use crate::error::{Error, io_context};
+
+/// What to do with a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    /// Run without asking.
+    Allow,
+    /// Ask the user before running.
+    Ask,
+    /// Never run.
+    Deny,
+}
+
+impl std::fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PolicyAction::Allow => "allow",
+            PolicyAction::Ask => "ask",
+            PolicyAction::Deny => "deny",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A policy rule. All the given conditions must match a command.
+/// Patterns are case insensitive and support `*` and `?` wildcards.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    /// Name reported to the model and user when the rule matches.
+    pub name: Option<String>,
+    pub action: PolicyAction,
+    /// Pattern for the cmdlet name, e.g. `*-ServiceFabricNode*`.
+    pub cmdlet: Option<String>,
+    /// Pattern for a parameter name without the dash, e.g. `Force*`.
+    pub parameter: Option<String>,
+    /// Pattern for the value of the matched parameter, e.g. `Remove*`.
+    pub value: Option<String>,
+    /// Pattern for any argument of the command, e.g. `fabric:/System*`.
+    pub target: Option<String>,
+    /// Matches commands at or above this risk level.
+    pub risk: Option<RiskLevel>,
+}
+
+/// Command approval policy, usually loaded from a toml file:
+///
+/// ```toml
+/// # action when no rule matches, defaults to asking for non read commands
+/// default = "ask"
+///
+/// [[rule]]
+/// name = "protect-system"
+/// action = "deny"
+/// target = "fabric:/System*"
+///
+/// [[rule]]
+/// name = "node-operations"
+/// action = "ask"
+/// cmdlet = "*-ServiceFabricNode*"
+/// ```
+///
+/// Rules are evaluated in order for each command of a script, the first match wins.
+/// A script gets the most restrictive action of its commands.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub default: Option<PolicyAction>,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<PolicyRule>,
+}
+
+/// Policy file selection.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct PolicyConfig {
+    /// Command approval policy file (toml). Without it non read commands need approval
+    #[arg(long = "policy", env = "SFCTL_AI_POLICY", global = true)]
+    pub policy_file: Option<PathBuf>,
+}
+
+impl PolicyConfig {
+    /// Load the configured policy, or the default one.
+    pub fn load(&self) -> crate::Result<Policy> {
+        match &self.policy_file {
+            Some(path) => Policy::load(path),
+            None => Ok(Policy::default()),
+        }
+    }
+}
+
+/// Result of evaluating a script against the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDecision {
+    pub action: PolicyAction,
+    /// Name of the rule that decided, None if the default applied.
+    pub rule: Option<String>,
+}
+
+impl PolicyDecision {
+    /// Explanation for the model and user, e.g. `deny by policy rule 'protect-system'`.
+    pub fn describe(&self) -> String {
+        match &self.rule {
+            Some(rule) => format!("{} by policy rule '{}'", self.action, rule),
+            None => format!("{} by default policy", self.action),
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_toml(s: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(s)
+    }
+
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| io_context(e, format!("Failed to read policy file {}", path.display())))?;
+        Self::from_toml(&content)
+            .map_err(|e| Error::Policy(format!("Invalid policy file {}: {e}", path.display())))
+    }
+
+    /// Decide what to do with a script.
+    pub fn evaluate(&self, script: &str) -> PolicyDecision {
+        let statements = cmd_parse::statements(script);
+        if statements.is_empty() {
+            return PolicyDecision {
+                action: self.default.unwrap_or(PolicyAction::Ask),
+                rule: None,
+            };
+        }
+        let mut decision = PolicyDecision {
+            action: PolicyAction::Allow,
+            rule: None,
+        };
+        for statement in statements {
+            let d = self.evaluate_statement(&statement);
+            if d.action > decision.action
+                || (d.action == decision.action && decision.rule.is_none())
+            {
+                decision = d;
+            }
+        }
+        decision
+    }
+
+    fn evaluate_statement(&self, tokens: &[Token]) -> PolicyDecision {
+        let risk = catalog::statement_risk(tokens);
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.matches(tokens, risk) {
+                return PolicyDecision {
+                    action: rule.action,
+                    rule: Some(rule.name.clone().unwrap_or_else(|| format!("#{}", i + 1))),
+                };
+            }
+        }
+        let action = self.default.unwrap_or(if risk.needs_ack() {
+            PolicyAction::Ask
+        } else {
+            PolicyAction::Allow
+        });
+        PolicyDecision { action, rule: None }
+    }
+}
+
+impl PolicyRule {
+    fn matches(&self, tokens: &[Token], risk: RiskLevel) -> bool {
+        if let Some(pattern) = &self.cmdlet {
+            let Some(Token::Word(name)) = tokens.first() else {
+                return false;
+            };
+            if !wildcard_match(pattern, name) {
+                return false;
+            }
+        }
+        if self.parameter.is_some() || self.value.is_some() {
+            let params = cmd_parse::parameters(tokens);
+            let found = params.iter().any(|(name, value)| {
+                self.parameter
+                    .as_deref()
+                    .is_none_or(|p| wildcard_match(p, name))
+                    && self
+                        .value
+                        .as_deref()
+                        .is_none_or(|p| value.as_deref().is_some_and(|v| wildcard_match(p, v)))
+            });
+            if !found {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.target
+            && !arguments(tokens).any(|arg| wildcard_match(pattern, arg))
+        {
+            return false;
+        }
+        if let Some(min) = self.risk
+            && risk < min
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Argument values of a command, excluding parameter names.
+fn arguments(tokens: &[Token]) -> impl Iterator<Item = &str> {
+    tokens.iter().skip(1).filter_map(|token| match token {
+        Token::Word(w) => match w.strip_prefix('-') {
+            // -Name:value
+            Some(param) => param.split_once(':').map(|(_, value)| value),
+            None => Some(w.as_str()),
+        },
+        Token::Str(s) => Some(s.as_str()),
+        _ => None,
+    })
+}
+
+/// Case insensitive match with `*` for any sequence and `?` for any single char.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.to_lowercase().chars().collect();
+    let t: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Position of the last star and the text index it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+[[rule]]
+name = "protect-system"
+action = "deny"
+target = "fabric:/System*"
+
+[[rule]]
+name = "no-data-removal"
+action = "deny"
+cmdlet = "Disable-ServiceFabricNode"
+parameter = "Intent"
+value = "Remove*"
+
+[[rule]]
+name = "node-operations"
+action = "ask"
+cmdlet = "*-ServiceFabricNode*"
+
+[[rule]]
+action = "allow"
+cmdlet = "Get-ServiceFabric*"
+
+[[rule]]
+name = "destructive"
+action = "deny"
+risk = "destructive"
+"#;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("Get-*", "get-servicefabricnode"));
+        assert!(wildcard_match(
+            "*-ServiceFabricNode*",
+            "Restart-ServiceFabricNode"
+        ));
+        assert!(wildcard_match(
+            "fabric:/System*",
+            "fabric:/System/FailoverManagerService"
+        ));
+        assert!(wildcard_match("n?", "n1"));
+        assert!(!wildcard_match("n?", "n12"));
+        assert!(!wildcard_match("Get-*", "Remove-ServiceFabricApplication"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let policy = Policy::from_toml(POLICY).unwrap();
+        let decide = |s: &str| {
+            let d = policy.evaluate(s);
+            (d.action, d.rule)
+        };
+
+        assert_eq!(
+            decide("Get-ServiceFabricService -ApplicationName fabric:/System"),
+            (PolicyAction::Deny, Some("protect-system".to_string()))
+        );
+        assert_eq!(
+            decide("Restart-ServiceFabricReplica -ServiceName:'fabric:/System/NamingService'"),
+            (PolicyAction::Deny, Some("protect-system".to_string()))
+        );
+        assert_eq!(
+            decide("Disable-ServiceFabricNode -NodeName n1 -Intent RemoveData"),
+            (PolicyAction::Deny, Some("no-data-removal".to_string()))
+        );
+        assert_eq!(
+            decide("Get-ServiceFabricNode | Restart-ServiceFabricNode"),
+            (PolicyAction::Ask, Some("node-operations".to_string()))
+        );
+        assert_eq!(
+            decide("Get-ServiceFabricApplication"),
+            (PolicyAction::Allow, Some("#4".to_string()))
+        );
+        assert_eq!(
+            decide("Remove-ServiceFabricApplication fabric:/App"),
+            (PolicyAction::Deny, Some("destructive".to_string()))
+        );
+        // No rule, the catalog decides
+        assert_eq!(
+            decide("Select-Object -First 1"),
+            (PolicyAction::Allow, None)
+        );
+        assert_eq!(
+            decide("Start-ServiceFabricChaos"),
+            (PolicyAction::Ask, None)
+        );
+
+        assert!(Policy::from_toml("[[rule]]\naction = \"maybe\"").is_err());
+        assert!(Policy::from_toml("[[rule]]\naction = \"deny\"\ncmd = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = Policy::default();
+        assert_eq!(
+            policy.evaluate("Get-ServiceFabricNode").action,
+            PolicyAction::Allow
+        );
+        let d = policy.evaluate("Remove-ServiceFabricApplication fabric:/App");
+        assert_eq!(d.action, PolicyAction::Ask);
+        assert_eq!(d.describe(), "ask by default policy");
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::Error;
+use crate::backend::CommandBackend;
+
+pub const DEFAULT_MAX_SESSIONS: usize = 8;
+pub const DEFAULT_SESSION_IDLE_TIMEOUT: u64 = 1800;
+
+/// How often idle sessions are looked for at most.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits of the command sessions kept for the clients of a pool.
+#[derive(Debug, Clone, clap::Args)]
+pub struct PoolConfig {
+    /// Most command sessions, e.g. pwsh processes, at the same time. Each client gets its own
+    #[arg(long, env = "SFCTL_AI_MAX_SESSIONS", default_value_t = DEFAULT_MAX_SESSIONS)]
+    pub max_sessions: usize,
+
+    /// Seconds a client's session may be idle before it is closed. The next command of the
+    /// client starts a new session connected to the same cluster
+    #[arg(long, env = "SFCTL_AI_SESSION_IDLE_TIMEOUT", default_value_t = DEFAULT_SESSION_IDLE_TIMEOUT)]
+    pub session_idle_timeout: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            session_idle_timeout: DEFAULT_SESSION_IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// A command session, locked while a command runs.
+pub type SharedBackend = Arc<tokio::sync::Mutex<Box<dyn CommandBackend>>>;
+
+type BackendFactory =
+    Arc<dyn Fn() -> crate::Result<Box<dyn CommandBackend>> + Send + Sync + 'static>;
+
+/// Command sessions of many clients, e.g. the MCP clients of a server.
+/// Each client gets its own session on its first command, so clients neither wait for each
+/// other nor share a cluster connection. Sessions idle for too long are closed, and at most
+/// `max_sessions` are kept; the least recently used idle session makes room for a new one.
+/// A closed session is restarted with its bootstrap commands when its client comes back, and
+/// the client gets the error if they fail.
+pub struct BackendPool {
+    factory: BackendFactory,
+    max_sessions: usize,
+    idle_timeout: Duration,
+    state: Mutex<PoolState>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    next_client: u64,
+    sessions: HashMap<u64, PooledSession>,
+    /// Sessions being started, counted against the max sessions.
+    starting: usize,
+    /// Bootstrap commands of the closed sessions by client.
+    closed: HashMap<u64, Vec<String>>,
+}
+
+struct PooledSession {
+    backend: SharedBackend,
+    last_used: Instant,
+}
+
+impl PooledSession {
+    /// Whether a command runs or is about to, the pool holds the only other reference.
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.backend) > 1
+    }
+}
+
+impl BackendPool {
+    /// Pool creating the sessions with the factory. Idle sessions are closed by a background
+    /// task, so it must be called within a tokio runtime.
+    pub fn new(
+        config: &PoolConfig,
+        factory: impl Fn() -> crate::Result<Box<dyn CommandBackend>> + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        let pool = Arc::new(BackendPool {
+            factory: Arc::new(factory),
+            max_sessions: config.max_sessions.max(1),
+            idle_timeout: Duration::from_secs(config.session_idle_timeout),
+            state: Mutex::default(),
+        });
+        let weak = Arc::downgrade(&pool);
+        let period = pool
+            .idle_timeout
+            .clamp(Duration::from_secs(1), EVICTION_INTERVAL);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match weak.upgrade() {
+                    Some(pool) => pool.close_idle(),
+                    None => break,
+                }
+            }
+        });
+        pool
+    }
+
+    /// New client without a session yet.
+    pub fn client(self: &Arc<Self>) -> PoolClient {
+        let mut state = self.state.lock().unwrap();
+        state.next_client += 1;
+        PoolClient {
+            id: state.next_client,
+            pool: self.clone(),
+        }
+    }
+
+    /// Number of open sessions.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close the sessions that were not used within the idle timeout.
+    pub fn close_idle(&self) {
+        let mut state = self.state.lock().unwrap();
+        let idle = state
+            .sessions
+            .iter()
+            .filter(|(_, s)| !s.in_use() && s.last_used.elapsed() >= self.idle_timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in idle {
+            Self::close(&mut state, id);
+        }
+    }
+
+    /// Close the session of the client, keeping what is needed to restart it.
+    /// A session whose lock is taken is running a command and is kept.
+    fn close(state: &mut PoolState, client: u64) -> bool {
+        let Some(session) = state.sessions.get(&client) else {
+            return false;
+        };
+        let bootstrap = match session.backend.try_lock() {
+            Ok(backend) => backend.bootstrap(),
+            Err(_) => return false,
+        };
+        tracing::info!("Closing the idle command session of client {}", client);
+        state.sessions.remove(&client);
+        state.closed.insert(client, bootstrap);
+        true
+    }
+
+    async fn backend(&self, client: u64) -> crate::Result<SharedBackend> {
+        let reservation = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(session) = state.sessions.get_mut(&client) {
+                session.last_used = Instant::now();
+                return Ok(session.backend.clone());
+            }
+            if state.sessions.len() + state.starting >= self.max_sessions {
+                let mut idle = state
+                    .sessions
+                    .iter()
+                    .filter(|(_, s)| !s.in_use())
+                    .map(|(id, s)| (s.last_used, *id))
+                    .collect::<Vec<_>>();
+                idle.sort();
+                if !idle.into_iter().any(|(_, id)| Self::close(&mut state, id)) {
+                    return Err(Error::Backend(std::io::Error::other(format!(
+                        "All {} command sessions are running commands, try again later",
+                        self.max_sessions
+                    ))));
+                }
+            }
+            state.starting += 1;
+            Reservation { pool: self }
+        };
+
+        // Starting a session, e.g. spawning pwsh, blocks, so it runs without the pool locked
+        let factory = self.factory.clone();
+        let backend = tokio::task::spawn_blocking(move || factory())
+            .await
+            .map_err(|e| Error::Backend(std::io::Error::other(e)))??;
+
+        let (backend, mut guard, bootstrap) = {
+            let mut state = self.state.lock().unwrap();
+            reservation.release(&mut state);
+            // another command of the client may have started a session meanwhile
+            if let Some(session) = state.sessions.get_mut(&client) {
+                session.last_used = Instant::now();
+                return Ok(session.backend.clone());
+            }
+            let backend: SharedBackend = Arc::new(tokio::sync::Mutex::new(backend));
+            // commands of the client wait until the session is restored
+            let guard = backend.clone().try_lock_owned().unwrap();
+            state.sessions.insert(
+                client,
+                PooledSession {
+                    backend: backend.clone(),
+                    last_used: Instant::now(),
+                },
+            );
+            let bootstrap = state.closed.remove(&client).unwrap_or_default();
+            (backend, guard, bootstrap)
+        };
+        for command in &bootstrap {
+            tracing::info!("Replaying bootstrap command: {}", command);
+            let error = match guard.run_command_outcome(command).await {
+                Ok(outcome) if outcome.is_success() => continue,
+                Ok(outcome) => std::io::Error::other(outcome.to_report()),
+                Err(e) => e,
+            };
+            // the session is not what the client had, keep the bootstrap to try again later
+            let mut state = self.state.lock().unwrap();
+            if state
+                .sessions
+                .get(&client)
+                .is_some_and(|s| Arc::ptr_eq(&s.backend, &backend))
+            {
+                state.sessions.remove(&client);
+                state.closed.insert(client, bootstrap.clone());
+            }
+            return Err(Error::Backend(std::io::Error::new(
+                error.kind(),
+                format!("Failed to replay bootstrap command {command}: {error}"),
+            )));
+        }
+        Ok(backend)
+    }
+}
+
+/// A session slot reserved while the session starts, released if starting fails or is
+/// cancelled.
+struct Reservation<'a> {
+    pool: &'a BackendPool,
+}
+
+impl Reservation<'_> {
+    fn release(self, state: &mut PoolState) {
+        state.starting -= 1;
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.pool.state.lock().unwrap().starting -= 1;
+    }
+}
+
+/// A client of a [`BackendPool`], its session is closed when the client is dropped.
+pub struct PoolClient {
+    id: u64,
+    pool: Arc<BackendPool>,
+}
+
+impl PoolClient {
+    /// The session of the client, started if there is none.
+    /// Fails if the session cannot be started or all sessions are running commands.
+    pub async fn backend(&self) -> crate::Result<SharedBackend> {
+        self.pool.backend(self.id).await
+    }
+
+    pub fn pool(&self) -> &Arc<BackendPool> {
+        &self.pool
+    }
+}
+
+impl Drop for PoolClient {
+    fn drop(&mut self) {
+        let mut state = self.pool.state.lock().unwrap();
+        state.sessions.remove(&self.id);
+        state.closed.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ScriptedBackend;
+
+    /// Run the command in the client's session, return the cluster it is connected to.
+    async fn run(client: &PoolClient, command: &str) -> crate::Result<Option<String>> {
+        let backend = client.backend().await?;
+        let mut backend = backend.lock().await;
+        backend.run_command(command).await?;
+        Ok(backend.endpoint())
+    }
+
+    #[tokio::test]
+    async fn test_backend_pool() {
+        let backend = ScriptedBackend::new()
+            .with_text("Import-Module", "")
+            .with_text("Connect-ServiceFabricCluster", "True")
+            .with_text("Get-ServiceFabricNode", "NodeName : n1");
+        let commands = backend.commands();
+        let pool = BackendPool::new(
+            &PoolConfig {
+                max_sessions: 2,
+                session_idle_timeout: 3600,
+            },
+            move || Ok(Box::new(backend.clone())),
+        );
+
+        // each client has its own session and connection
+        let a = pool.client();
+        let b = pool.client();
+        run(
+            &a,
+            "Connect-ServiceFabricCluster -ConnectionEndpoint a:19000",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            run(&b, "Get-ServiceFabricNode").await.unwrap(),
+            None,
+            "b is not connected"
+        );
+        assert_eq!(
+            run(&a, "Get-ServiceFabricNode").await.unwrap().as_deref(),
+            Some("a:19000")
+        );
+        assert_eq!(pool.len(), 2);
+
+        // a session running a command is not closed for a new client
+        let running = a.backend().await.unwrap();
+        let _lock = running.lock().await;
+        let c = pool.client();
+        let busy = b.backend().await.unwrap();
+        assert!(c.backend().await.is_err());
+        drop(busy);
+
+        // the least recently used idle session makes room, and is restored when needed
+        assert_eq!(run(&c, "Get-ServiceFabricNode").await.unwrap(), None);
+        assert_eq!(pool.len(), 2);
+        drop(_lock);
+        drop(running);
+        commands.lock().unwrap().clear();
+        assert_eq!(
+            run(&b, "Get-ServiceFabricNode").await.unwrap(),
+            None,
+            "b closed, a made room for it"
+        );
+        assert_eq!(
+            run(&a, "Get-ServiceFabricNode").await.unwrap().as_deref(),
+            Some("a:19000")
+        );
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                "Get-ServiceFabricNode",
+                "Connect-ServiceFabricCluster -ConnectionEndpoint a:19000",
+                "Get-ServiceFabricNode",
+            ]
+        );
+
+        // dropped clients release their sessions
+        drop(a);
+        assert_eq!(pool.len(), 1);
+        drop(b);
+        drop(c);
+        assert!(pool.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backend_pool_replay_failure() {
+        // the cluster cannot be reached anymore when the session is restored
+        let starts = Mutex::new(0);
+        let pool = BackendPool::new(
+            &PoolConfig {
+                max_sessions: 1,
+                session_idle_timeout: 0,
+            },
+            move || {
+                let mut starts = starts.lock().unwrap();
+                *starts += 1;
+                let backend = ScriptedBackend::new().with_text("Get-ServiceFabricNode", "");
+                Ok(Box::new(match *starts {
+                    1 => backend.with_text("Connect-ServiceFabricCluster", "True"),
+                    _ => backend,
+                }))
+            },
+        );
+        let client = pool.client();
+        run(
+            &client,
+            "Connect-ServiceFabricCluster -ConnectionEndpoint a:19000",
+        )
+        .await
+        .unwrap();
+        pool.close_idle();
+        assert!(pool.is_empty());
+
+        // the client learns its session lost the connection, and it is not kept
+        let e = client.backend().await.err().unwrap();
+        assert!(
+            e.to_string().starts_with(
+                "Failed to replay bootstrap command Connect-ServiceFabricCluster -ConnectionEndpoint a:19000"
+            ),
+            "{e}"
+        );
+        assert!(pool.is_empty());
+        assert!(client.backend().await.is_err(), "replayed again");
+    }
+
+    #[tokio::test]
+    async fn test_backend_pool_starting() {
+        // sessions start only when the test lets them
+        let (start, started) = std::sync::mpsc::channel::<()>();
+        let started = Mutex::new(started);
+        let pool = BackendPool::new(
+            &PoolConfig {
+                max_sessions: 2,
+                session_idle_timeout: 3600,
+            },
+            move || {
+                started.lock().unwrap().recv().unwrap();
+                Ok(Box::new(ScriptedBackend::new()))
+            },
+        );
+        let a = Arc::new(pool.client());
+        let b = pool.client();
+        start.send(()).unwrap();
+        b.backend().await.unwrap();
+
+        // a starting session does not hold up the others, and counts against the max
+        let starting = tokio::spawn({
+            let a = a.clone();
+            async move { a.backend().await.map(|_| ()) }
+        });
+        while pool.state.lock().unwrap().starting == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(pool.len(), 1);
+        let _busy = b.backend().await.unwrap();
+        assert!(pool.client().backend().await.is_err());
+        start.send(()).unwrap();
+        starting.await.unwrap().unwrap();
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.state.lock().unwrap().starting, 0);
+    }
+
+    #[tokio::test]
+    async fn test_backend_pool_idle() {
+        let pool = BackendPool::new(
+            &PoolConfig {
+                max_sessions: 2,
+                session_idle_timeout: 0,
+            },
+            || Ok(Box::new(ScriptedBackend::new())),
+        );
+        let client = pool.client();
+        let backend = client.backend().await.unwrap();
+        pool.close_idle();
+        assert_eq!(pool.len(), 1, "in use");
+        drop(backend);
+        pool.close_idle();
+        assert!(pool.is_empty());
+        client.backend().await.unwrap();
+        assert_eq!(pool.len(), 1);
+    }
+}
@@ -0,0 +1,177 @@
+use genai::{
+    ModelIden, ServiceTarget,
+    adapter::AdapterKind,
+    chat::ChatOptions,
+    resolver::{AuthData, Endpoint},
+};
+
+/// LLM providers supported by sfctl-ai.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Provider {
+    Gemini,
+    #[value(name = "openai")]
+    OpenAi,
+    /// Azure OpenAI, requires the deployment url as base url.
+    #[value(name = "azure-openai")]
+    AzureOpenAi,
+    Anthropic,
+    /// Local Ollama server.
+    Ollama,
+}
+
+impl Provider {
+    pub fn adapter_kind(&self) -> AdapterKind {
+        match self {
+            Provider::Gemini => AdapterKind::Gemini,
+            Provider::OpenAi | Provider::AzureOpenAi => AdapterKind::OpenAI,
+            Provider::Anthropic => AdapterKind::Anthropic,
+            Provider::Ollama => AdapterKind::Ollama,
+        }
+    }
+
+    pub fn default_model(&self) -> &'static str {
+        match self {
+            Provider::Gemini => "gemini-2.0-flash",
+            Provider::OpenAi | Provider::AzureOpenAi => "gpt-4o",
+            Provider::Anthropic => "claude-3-5-sonnet-latest",
+            Provider::Ollama => "llama3.1",
+        }
+    }
+
+    /// Env var holding the api key. None if the provider does not need a key.
+    pub fn default_api_key_env(&self) -> Option<&'static str> {
+        match self {
+            Provider::Gemini => Some("GEMINI_API_KEY"),
+            Provider::OpenAi => Some("OPENAI_API_KEY"),
+            Provider::AzureOpenAi => Some("AZURE_OPENAI_API_KEY"),
+            Provider::Anthropic => Some("ANTHROPIC_API_KEY"),
+            Provider::Ollama => None,
+        }
+    }
+}
+
+/// LLM provider and model selection.
+/// Each value can be set by a flag or the matching env var.
+#[derive(Debug, Clone, clap::Args)]
+pub struct AiConfig {
+    /// LLM provider
+    #[arg(long, env = "SFCTL_AI_PROVIDER", value_enum, default_value_t = Provider::Gemini, global = true)]
+    pub provider: Provider,
+
+    /// Model name, defaults to a model of the provider
+    #[arg(long, env = "SFCTL_AI_MODEL", global = true)]
+    pub model: Option<String>,
+
+    /// Base url of the provider endpoint, e.g. an OpenAI compatible server
+    /// or the Azure OpenAI deployment url
+    #[arg(long, env = "SFCTL_AI_BASE_URL", global = true)]
+    pub base_url: Option<String>,
+
+    /// Env var to read the api key from, defaults to the provider's usual one
+    #[arg(long, env = "SFCTL_AI_API_KEY_ENV", global = true)]
+    pub api_key_env: Option<String>,
+}
+
+impl Default for AiConfig {
+    fn default() -> Self {
+        AiConfig {
+            provider: Provider::Gemini,
+            model: None,
+            base_url: None,
+            api_key_env: None,
+        }
+    }
+}
+
+impl AiConfig {
+    pub fn model(&self) -> &str {
+        self.model
+            .as_deref()
+            .unwrap_or_else(|| self.provider.default_model())
+    }
+
+    pub fn api_key_env(&self) -> Option<&str> {
+        self.api_key_env
+            .as_deref()
+            .or_else(|| self.provider.default_api_key_env())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.provider == Provider::AzureOpenAi && self.base_url.is_none() {
+            return Err("azure-openai provider requires --base-url with the deployment url".into());
+        }
+        Ok(())
+    }
+
+    /// Point the genai service target to the configured provider.
+    pub fn resolve_target(&self, mut target: ServiceTarget) -> ServiceTarget {
+        target.model = ModelIden::new(self.provider.adapter_kind(), target.model.model_name);
+        if let Some(base_url) = &self.base_url {
+            target.endpoint = Endpoint::from_owned(base_url.clone());
+        }
+        if let Some(env_name) = self.api_key_env() {
+            target.auth = AuthData::from_env(env_name);
+        }
+        target
+    }
+
+    /// Provider specific options for each chat request.
+    pub fn chat_options(&self) -> ChatOptions {
+        let options = ChatOptions::default();
+        match (self.provider, self.api_key_env()) {
+            // Azure OpenAI expects the key in the api-key header.
+            (Provider::AzureOpenAi, Some(env_name)) => match std::env::var(env_name) {
+                Ok(key) => options.with_extra_headers(("api-key".to_string(), key)),
+                Err(_) => options,
+            },
+            _ => options,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(model: &str) -> ServiceTarget {
+        ServiceTarget {
+            endpoint: Endpoint::from_static("https://example.com/"),
+            auth: AuthData::from_single("key"),
+            model: ModelIden::new(AdapterKind::Gemini, model),
+        }
+    }
+
+    #[test]
+    fn test_resolve_target() {
+        let config = AiConfig {
+            provider: Provider::Ollama,
+            model: Some("qwen3".to_string()),
+            base_url: Some("http://myhost:11434/v1/".to_string()),
+            api_key_env: None,
+        };
+        assert_eq!(config.model(), "qwen3");
+        let t = config.resolve_target(target(config.model()));
+        assert_eq!(t.model.adapter_kind, AdapterKind::Ollama);
+        assert_eq!(&*t.model.model_name, "qwen3");
+        assert_eq!(t.endpoint.base_url(), "http://myhost:11434/v1/");
+
+        let config = AiConfig {
+            provider: Provider::AzureOpenAi,
+            ..AiConfig::default()
+        };
+        assert!(config.validate().is_err());
+        assert_eq!(config.model(), "gpt-4o");
+        assert_eq!(config.api_key_env(), Some("AZURE_OPENAI_API_KEY"));
+        let t = config.resolve_target(target(config.model()));
+        assert_eq!(t.model.adapter_kind, AdapterKind::OpenAI);
+        assert_eq!(t.endpoint.base_url(), "https://example.com/");
+    }
+
+    #[test]
+    fn test_default_config() {
+        let config = AiConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.model(), "gemini-2.0-flash");
+        assert_eq!(config.api_key_env(), Some("GEMINI_API_KEY"));
+    }
+}
@@ -1,26 +1,371 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::process::{ChildStdin, ChildStdout, Command};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::json_error;
+use crate::cmd_parse;
+use crate::error::{Error, io_context};
+
+const TEXT_MARKER: &str = "___COMMAND_END___";
+
+/// Default max time a single command may run.
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Default depth for ConvertTo-Json. Deep enough for health states and their evaluations.
+pub const DEFAULT_JSON_DEPTH: u32 = 4;
+
+/// Wrapper script reporting the output, error records, warnings and status as one json line.
+/// `__SFCTL_COMMAND__` is replaced by the command.
+const OUTCOME_SCRIPT: &str = concat!(
+    "$global:LASTEXITCODE = $null; $__sfctl_errors = $null; $__sfctl_warnings = $null; ",
+    "$__sfctl_output = Invoke-Command -ScriptBlock { try { __SFCTL_COMMAND__ } catch { Write-Error -ErrorRecord $_ } } ",
+    "-ErrorVariable __sfctl_errors -WarningVariable __sfctl_warnings 2>$null 3>$null 6>&1 | Out-String -Width 4096; ",
+    "$__sfctl_ok = $?; ",
+    "[pscustomobject]@{ Output = $__sfctl_output; Success = $__sfctl_ok; ExitCode = $global:LASTEXITCODE; ",
+    "Warnings = @($__sfctl_warnings | ForEach-Object { $_.Message }); ",
+    "Errors = @($__sfctl_errors | ForEach-Object { [pscustomobject]@{ Message = $_.Exception.Message; ",
+    "Category = $_.CategoryInfo.Category.ToString(); FullyQualifiedErrorId = $_.FullyQualifiedErrorId; ",
+    "TargetObject = if ($null -ne $_.TargetObject) { \"$($_.TargetObject)\" } else { $null } } }) } ",
+    "| ConvertTo-Json -Depth 4 -Compress"
+);
+
+/// A record from the powershell error stream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ErrorRecord {
+    pub message: String,
+    /// e.g. ObjectNotFound
+    pub category: String,
+    /// e.g. CommandNotFoundException
+    pub fully_qualified_error_id: String,
+    /// The object the command was operating on, if any.
+    pub target_object: Option<String>,
+}
+
+/// Wrapper script converting the output objects to json, or the messages of the error records
+/// to an object with an Error property if the command wrote any.
+/// `__SFCTL_COMMAND__` is replaced by the command and `__SFCTL_DEPTH__` by the json depth.
+const JSON_SCRIPT: &str = concat!(
+    "$__sfctl_errors = $null; ",
+    "$__sfctl_output = Invoke-Command -ScriptBlock { try { __SFCTL_COMMAND__ } catch { Write-Error -ErrorRecord $_ } } ",
+    "-ErrorVariable __sfctl_errors 2>$null; ",
+    "$(if ($__sfctl_errors) { [pscustomobject]@{ Error = @($__sfctl_errors | ForEach-Object { $_.Exception.Message }) -join \"`n\" } } ",
+    "else { $__sfctl_output }) | ConvertTo-Json -Depth __SFCTL_DEPTH__ -Compress -EnumsAsStrings"
+);
+
+/// Status line emitted by [`OUTCOME_SCRIPT`].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RawOutcome {
+    output: Option<String>,
+    success: bool,
+    exit_code: Option<i32>,
+    #[serde(default)]
+    warnings: Vec<String>,
+    #[serde(default)]
+    errors: Vec<ErrorRecord>,
+}
+
+/// Result of running a command, with the streams kept apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutcome {
+    /// Formatted output objects.
+    pub stdout: String,
+    /// Anything the pwsh process wrote to stderr while the command ran.
+    pub stderr: String,
+    pub errors: Vec<ErrorRecord>,
+    pub warnings: Vec<String>,
+    /// Value of `$?` after the command.
+    pub status: bool,
+    /// Value of `$LASTEXITCODE`, None if no native program ran.
+    pub exit_code: Option<i32>,
+    pub duration: Duration,
+}
+
+impl CommandOutcome {
+    fn parse(status_line: &str, stderr: String, duration: Duration) -> std::io::Result<Self> {
+        let raw = serde_json::from_str::<RawOutcome>(status_line).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to parse command status: {e}. Output was: {status_line}"),
+            )
+        })?;
+        Ok(CommandOutcome {
+            stdout: raw.output.unwrap_or_default().trim().to_string(),
+            stderr,
+            errors: raw.errors,
+            warnings: raw.warnings,
+            status: raw.success,
+            exit_code: raw.exit_code,
+            duration,
+        })
+    }
+
+    /// The command succeeded if `$?` is true, nothing was written to
+    /// the error stream and native programs exited with 0.
+    pub fn is_success(&self) -> bool {
+        self.status && self.errors.is_empty() && self.exit_code.unwrap_or(0) == 0
+    }
+
+    /// Render the outcome as text for the model or the user.
+    pub fn to_report(&self) -> String {
+        let mut report = String::new();
+        if self.is_success() {
+            report.push_str("Status: Succeeded\n");
+        } else {
+            report.push_str("Status: Failed\n");
+        }
+        if let Some(code) = self.exit_code {
+            report.push_str(&format!("Exit code: {code}\n"));
+        }
+        report.push_str(&format!("Duration: {} ms\n", self.duration.as_millis()));
+        if !self.stdout.is_empty() {
+            report.push_str(&format!("Output:\n{}\n", self.stdout));
+        }
+        for e in &self.errors {
+            report.push_str(&format!(
+                "Error: {} (Category: {}, FullyQualifiedErrorId: {}",
+                e.message, e.category, e.fully_qualified_error_id
+            ));
+            if let Some(target) = &e.target_object {
+                report.push_str(&format!(", Target: {target}"));
+            }
+            report.push_str(")\n");
+        }
+        for w in &self.warnings {
+            report.push_str(&format!("Warning: {w}\n"));
+        }
+        if !self.stderr.is_empty() {
+            report.push_str(&format!("Stderr:\n{}\n", self.stderr));
+        }
+        report.trim_end().to_string()
+    }
+}
 
 pub struct PwshSession {
+    child: Child,
     stdin: ChildStdin,
     stdout: BufReader<ChildStdout>,
+    /// Lines written to stderr, collected by a background task.
+    stderr: Arc<Mutex<String>>,
+    /// Max time a single command may run. None waits forever.
+    timeout: Option<Duration>,
+    /// Cancels the running command.
+    cancel: CancellationToken,
+    /// Commands replayed after the pwsh process is restarted,
+    /// e.g. Import-Module and the last Connect-ServiceFabricCluster.
+    bootstrap: Vec<String>,
+}
+
+/// Record commands that set up session state, so they can be replayed on restart.
+/// Module imports are kept, and only the last cluster connection is kept.
+pub(crate) fn update_bootstrap(bootstrap: &mut Vec<String>, command: &str) {
+    let command = command.trim();
+    // Only record single statements
+    if command.contains(['\n', ';', '|']) {
+        return;
+    }
+    let lower = command.to_lowercase();
+    if lower.starts_with("import-module ") {
+        if !bootstrap.iter().any(|c| c.eq_ignore_ascii_case(command)) {
+            bootstrap.push(command.to_string());
+        }
+    } else if lower.starts_with("connect-servicefabriccluster") {
+        bootstrap.retain(|c| !c.to_lowercase().starts_with("connect-servicefabriccluster"));
+        bootstrap.push(command.to_string());
+    }
+}
+
+/// Endpoint of the last cluster connection in the bootstrap commands.
+/// Connecting without an endpoint uses the local cluster.
+pub(crate) fn connection_endpoint(bootstrap: &[String]) -> Option<String> {
+    let connect = bootstrap
+        .iter()
+        .rev()
+        .find(|c| c.to_lowercase().starts_with("connect-servicefabriccluster"))?;
+    let endpoint = cmd_parse::parameters(&cmd_parse::tokenize(connect))
+        .into_iter()
+        .find(|(name, _)| name == "connectionendpoint")
+        .and_then(|(_, value)| value);
+    Some(endpoint.unwrap_or_else(|| "localhost:19000".to_string()))
+}
+
+/// PowerShell single quoted string, safe for any value.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Whether the value is a cluster endpoint like `host:19000` or `[::1]:19000`,
+/// so it is safe to pass to Connect-ServiceFabricCluster.
+pub fn is_endpoint(value: &str) -> bool {
+    let Some((host, port)) = value.rsplit_once(':') else {
+        return false;
+    };
+    !host.is_empty()
+        && port.parse::<u16>().is_ok()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':' | '[' | ']'))
+}
+
+/// Wrap a command for text output, terminated by [`TEXT_MARKER`].
+fn wrap_text_command(command: &str) -> String {
+    // Use Invoke-Command with a marker to simplify parsing
+    format!(
+        "Invoke-Command -ScriptBlock {{ try {{ {} }} catch {{ Write-Output $_.Exception.Message }} }}; Write-Output '{}'\n",
+        command, TEXT_MARKER
+    )
 }
 
 impl PwshSession {
-    pub fn new() -> std::io::Result<Self> {
+    /// Spawn the pwsh process. Must be called within a tokio runtime.
+    pub fn new() -> crate::Result<Self> {
+        let stderr = Arc::new(Mutex::new(String::new()));
+        let (child, stdin, stdout) = Self::spawn(&stderr)
+            .map_err(|e| Error::Backend(io_context(e, "Failed to start pwsh")))?;
+        Ok(PwshSession {
+            child,
+            stdin,
+            stdout,
+            stderr,
+            timeout: Some(DEFAULT_COMMAND_TIMEOUT),
+            cancel: CancellationToken::new(),
+            bootstrap: Vec::new(),
+        })
+    }
+
+    fn spawn(
+        stderr: &Arc<Mutex<String>>,
+    ) -> std::io::Result<(Child, ChildStdin, BufReader<ChildStdout>)> {
         let mut child = Command::new("pwsh")
             .arg("-NoLogo")
             .arg("-NoProfile")
             .arg("-NonInteractive")
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
             .env("NO_COLOR", "1") // Prevent ANSI color codes
+            .kill_on_drop(true)
             .spawn()?;
 
         let stdin = child.stdin.take().unwrap();
         let stdout = BufReader::new(child.stdout.take().unwrap());
 
-        Ok(PwshSession { stdin, stdout })
+        let mut stderr_reader = BufReader::new(child.stderr.take().unwrap()).lines();
+        tokio::spawn({
+            let stderr = stderr.clone();
+            async move {
+                while let Ok(Some(line)) = stderr_reader.next_line().await {
+                    tracing::info!("pwsh stderr: {}", line);
+                    let mut buf = stderr.lock().unwrap();
+                    buf.push_str(&line);
+                    buf.push('\n');
+                }
+            }
+        });
+
+        Ok((child, stdin, stdout))
+    }
+
+    /// Kill the pwsh process and start a new one,
+    /// then replay the bootstrap commands to restore the session state.
+    /// The replay runs even if the cancellation token is cancelled, e.g. after a cancelled
+    /// command, and fails if a bootstrap command fails. The new process is kept either way.
+    pub async fn restart(&mut self) -> std::io::Result<()> {
+        tracing::info!("Restarting pwsh session");
+        if let Err(e) = self.child.kill().await {
+            tracing::info!("Failed to kill pwsh process: {e}");
+        }
+        let (child, stdin, stdout) = Self::spawn(&self.stderr)?;
+        self.child = child;
+        self.stdin = stdin;
+        self.stdout = stdout;
+
+        let cancel = std::mem::replace(&mut self.cancel, CancellationToken::new());
+        let res = self.replay_bootstrap().await;
+        self.cancel = cancel;
+        res
+    }
+
+    async fn replay_bootstrap(&mut self) -> std::io::Result<()> {
+        for command in self.bootstrap.clone() {
+            tracing::info!("Replaying bootstrap command: {}", command);
+            let res = async {
+                self.write_command(&wrap_text_command(&command)).await?;
+                self.read_output_with_limits(TEXT_MARKER).await?
+            }
+            .await;
+            match res {
+                Ok((output, true)) => tracing::info!("Bootstrap output: {}", output.trim()),
+                Ok((_, false)) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("pwsh exited while replaying: {command}"),
+                    ));
+                }
+                Err(e) => {
+                    return Err(std::io::Error::new(
+                        e.kind(),
+                        format!("Failed to replay bootstrap command {command}: {e}"),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Restart after a failed command. Returns the error of the command,
+    /// with the restart failure if the session could not be restored.
+    async fn restart_after(&mut self, error: std::io::Error) -> std::io::Error {
+        match self.restart().await {
+            Ok(()) => error,
+            Err(e) => {
+                tracing::error!("Failed to restart pwsh session: {e}");
+                std::io::Error::new(error.kind(), format!("{error}. {e}"))
+            }
+        }
+    }
+
+    /// Commands replayed after a restart.
+    pub fn bootstrap(&self) -> &[String] {
+        &self.bootstrap
+    }
+
+    /// Endpoint of the connected cluster, from the last Connect-ServiceFabricCluster.
+    pub fn endpoint(&self) -> Option<String> {
+        connection_endpoint(&self.bootstrap)
+    }
+
+    /// Add a command to replay after a restart.
+    /// Import-Module and Connect-ServiceFabricCluster commands are recorded automatically.
+    pub fn add_bootstrap_command(&mut self, command: &str) {
+        self.bootstrap.push(command.to_string());
+    }
+
+    /// Returns true if the pwsh process has exited.
+    pub fn has_exited(&mut self) -> bool {
+        !matches!(self.child.try_wait(), Ok(None))
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Set the max time a single command may run. None waits forever.
+    /// On timeout the pwsh process is restarted and the command returns a TimedOut error.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Commands are cancelled when the token is cancelled.
+    /// The pwsh process is restarted and the command returns an Interrupted error.
+    /// A cancelled token cancels every later command, so set a new one to continue.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = token;
     }
 
     /// Trim comments (lines starting with #) from the command
@@ -36,35 +381,126 @@ impl PwshSession {
         // Remove comments from command
         let command = Self::trim_command(command);
 
-        // Use Invoke-Command with a marker to simplify parsing
-        let marker = "___COMMAND_END___";
+        let output = self
+            .run_wrapped(&wrap_text_command(&command), TEXT_MARKER)
+            .await?;
+        update_bootstrap(&mut self.bootstrap, &command);
+        Ok(output)
+    }
+
+    /// Run the command and convert its output objects to json.
+    /// Returns Null if the command has no output.
+    /// Exceptions and error records are returned as an object with an Error property,
+    /// dropping the output.
+    pub async fn run_command_json(
+        &mut self,
+        command: &str,
+        depth: u32,
+    ) -> std::io::Result<serde_json::Value> {
+        let command = Self::trim_command(command);
+
+        // Use a distinct marker so json output is never confused with text output
+        let marker = "___JSON_END___";
         let wrapped_command = format!(
-            "Invoke-Command -ScriptBlock {{ try {{ {} }} catch {{ Write-Output $_.Exception.Message }} }}; Write-Output '{}'\n",
-            command, marker
+            "{}; Write-Output '{}'\n",
+            JSON_SCRIPT
+                .replace("__SFCTL_COMMAND__", &command)
+                .replace("__SFCTL_DEPTH__", &depth.to_string()),
+            marker
         );
 
-        self.stdin.write_all(wrapped_command.as_bytes()).await?;
-        self.stdin.flush().await?;
+        let output = self.run_wrapped(&wrapped_command, marker).await?;
+        if output.is_empty() {
+            update_bootstrap(&mut self.bootstrap, &command);
+            return Ok(serde_json::Value::Null);
+        }
+        let output = serde_json::from_str(&output).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to parse command output as json: {e}. Output was: {output}"),
+            )
+        })?;
+        if json_error(&output).is_none() {
+            update_bootstrap(&mut self.bootstrap, &command);
+        }
+        Ok(output)
+    }
 
-        let mut output = String::new();
-        let mut line = String::new();
-        let mut found_marker = false;
+    /// Run the command and report the output, error stream, warnings and status separately.
+    /// Unlike [`Self::run_command`] failures are not mixed into the output.
+    pub async fn run_command_outcome(&mut self, command: &str) -> std::io::Result<CommandOutcome> {
+        let command = Self::trim_command(command);
 
-        loop {
-            line.clear();
-            let n = self.stdout.read_line(&mut line).await?;
-            if n == 0 {
-                break; // EOF
-            }
-            if line.trim_end() == marker {
-                found_marker = true;
-                break;
-            }
-            output.push_str(&line);
+        let marker = "___OUTCOME_END___";
+        let wrapped_command = format!(
+            "{}; Write-Output '{}'\n",
+            OUTCOME_SCRIPT.replace("__SFCTL_COMMAND__", &command),
+            marker
+        );
+
+        let start = Instant::now();
+        let status_line = self.run_wrapped(&wrapped_command, marker).await?;
+        let duration = start.elapsed();
+        let stderr = std::mem::take(&mut *self.stderr.lock().unwrap())
+            .trim()
+            .to_string();
+
+        let outcome = CommandOutcome::parse(&status_line, stderr, duration)?;
+        if outcome.is_success() {
+            update_bootstrap(&mut self.bootstrap, &command);
+        }
+        Ok(outcome)
+    }
+
+    /// Write the wrapped command and read the output until the marker line.
+    /// Restarts pwsh if the process died, or if the command times out or is cancelled.
+    async fn run_wrapped(
+        &mut self,
+        wrapped_command: &str,
+        marker: &str,
+    ) -> std::io::Result<String> {
+        if self.cancel.is_cancelled() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Command cancelled",
+            ));
         }
 
+        // The process may have died since the last command.
+        // The command has not run yet, so it is safe to run it on a new process.
+        if self.has_exited() {
+            tracing::info!("pwsh process has exited");
+            self.restart().await?;
+        }
+        // Drop stderr from previous commands, whichever way they ran
+        self.stderr.lock().unwrap().clear();
+        if let Err(e) = self.write_command(wrapped_command).await {
+            tracing::info!("Failed to write to pwsh: {e}");
+            self.restart().await?;
+            self.write_command(wrapped_command).await?;
+        }
+
+        let (mut output, found_marker) = match self.read_output_with_limits(marker).await {
+            Ok(res) => res?,
+            Err(e) => {
+                // The command may still be running, so the process cannot be reused.
+                tracing::info!("{e}");
+                return Err(self.restart_after(e).await);
+            }
+        };
+
         if !found_marker {
-            return Ok(output.trim().to_string());
+            // pwsh exited while running the command. Do not rerun it, since it may have
+            // partially run, but restart so the next command works.
+            tracing::info!("pwsh exited while running command. Output: {}", output);
+            let e = std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "pwsh exited while running the command, the pwsh session was restarted. Output: {}",
+                    output.trim()
+                ),
+            );
+            return Err(self.restart_after(e).await);
         }
 
         // Remove the echoed command from the beginning of the output
@@ -80,6 +516,59 @@ impl PwshSession {
 
         Ok(output.trim().to_string())
     }
+
+    async fn write_command(&mut self, wrapped_command: &str) -> std::io::Result<()> {
+        self.stdin.write_all(wrapped_command.as_bytes()).await?;
+        self.stdin.flush().await
+    }
+
+    /// Read the output, limited by the timeout and the cancellation token.
+    /// The outer error is the timeout or cancellation, the inner error is from reading.
+    async fn read_output_with_limits(
+        &mut self,
+        marker: &str,
+    ) -> std::io::Result<std::io::Result<(String, bool)>> {
+        let timeout = self.timeout;
+        let cancel = self.cancel.clone();
+        tokio::select! {
+            res = self.read_output(marker) => Ok(res),
+            _ = async {
+                match timeout {
+                    Some(timeout) => tokio::time::sleep(timeout).await,
+                    None => std::future::pending().await,
+                }
+            } => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "Command timed out after {} seconds, the pwsh session was restarted",
+                    timeout.unwrap_or_default().as_secs()
+                ),
+            )),
+            _ = cancel.cancelled() => Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Command cancelled, the pwsh session was restarted",
+            )),
+        }
+    }
+
+    /// Read stdout until the marker line.
+    /// Returns the output and whether the marker was found before EOF.
+    async fn read_output(&mut self, marker: &str) -> std::io::Result<(String, bool)> {
+        let mut output = String::new();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let n = self.stdout.read_line(&mut line).await?;
+            if n == 0 {
+                return Ok((output, false)); // EOF
+            }
+            if line.trim_end() == marker {
+                return Ok((output, true));
+            }
+            output.push_str(&line);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -116,4 +605,266 @@ mod tests {
         );
         assert!(output.contains("Check the spelling of the name"));
     }
+
+    #[test]
+    fn test_parse_outcome() {
+        let line =
+            r#"{"Output":"Hello\n","Success":true,"ExitCode":null,"Warnings":[],"Errors":[]}"#;
+        let outcome = CommandOutcome::parse(line, String::new(), Duration::from_millis(5)).unwrap();
+        assert_eq!(outcome.stdout, "Hello");
+        assert!(outcome.is_success());
+        assert_eq!(
+            outcome.to_report(),
+            "Status: Succeeded\nDuration: 5 ms\nOutput:\nHello"
+        );
+
+        let line = r#"{"Output":"","Success":false,"ExitCode":null,"Warnings":["careful"],"Errors":[{"Message":"Node not found","Category":"ObjectNotFound","FullyQualifiedErrorId":"GetNodeErrorId","TargetObject":"_Node_9"}]}"#;
+        let outcome = CommandOutcome::parse(line, String::new(), Duration::ZERO).unwrap();
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.errors[0].category, "ObjectNotFound");
+        assert_eq!(outcome.errors[0].target_object.as_deref(), Some("_Node_9"));
+        let report = outcome.to_report();
+        assert!(report.starts_with("Status: Failed"));
+        assert!(report.contains("Error: Node not found (Category: ObjectNotFound, FullyQualifiedErrorId: GetNodeErrorId, Target: _Node_9)"));
+        assert!(report.contains("Warning: careful"));
+
+        // Native program failure
+        let line = r#"{"Output":null,"Success":true,"ExitCode":1,"Warnings":[],"Errors":[]}"#;
+        let outcome = CommandOutcome::parse(line, String::new(), Duration::ZERO).unwrap();
+        assert!(!outcome.is_success());
+
+        assert!(CommandOutcome::parse("not json", String::new(), Duration::ZERO).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pwsh_session_outcome() {
+        let mut session = PwshSession::new().unwrap();
+
+        let outcome = session
+            .run_command_outcome("Write-Output 'Hello'")
+            .await
+            .unwrap();
+        assert_eq!(outcome.stdout, "Hello");
+        assert!(outcome.is_success(), "{outcome:?}");
+
+        let outcome = session
+            .run_command_outcome("Bad-Command-That-Does-Not-Exist")
+            .await
+            .unwrap();
+        assert!(!outcome.is_success());
+        assert_eq!(
+            outcome.errors[0].fully_qualified_error_id,
+            "CommandNotFoundException"
+        );
+        assert_eq!(outcome.errors[0].category, "ObjectNotFound");
+
+        let outcome = session
+            .run_command_outcome("Write-Warning 'careful'; Write-Output 'done'")
+            .await
+            .unwrap();
+        assert!(outcome.is_success(), "{outcome:?}");
+        assert_eq!(outcome.warnings, vec!["careful".to_string()]);
+        assert_eq!(outcome.stdout, "done");
+    }
+
+    #[test]
+    fn test_update_bootstrap() {
+        let mut bootstrap = Vec::new();
+        update_bootstrap(&mut bootstrap, "Import-Module ServiceFabric");
+        update_bootstrap(&mut bootstrap, "import-module ServiceFabric");
+        update_bootstrap(&mut bootstrap, "Get-ServiceFabricNode");
+        update_bootstrap(
+            &mut bootstrap,
+            "Connect-ServiceFabricCluster -ConnectionEndpoint a:19000",
+        );
+        update_bootstrap(
+            &mut bootstrap,
+            "Connect-ServiceFabricCluster -ConnectionEndpoint b:19000",
+        );
+        update_bootstrap(&mut bootstrap, "Import-Module ServiceFabric; Remove-Item x");
+        assert_eq!(
+            bootstrap,
+            vec![
+                "Import-Module ServiceFabric".to_string(),
+                "Connect-ServiceFabricCluster -ConnectionEndpoint b:19000".to_string(),
+            ]
+        );
+        assert_eq!(connection_endpoint(&bootstrap), Some("b:19000".to_string()));
+        assert_eq!(
+            connection_endpoint(&["Connect-ServiceFabricCluster".to_string()]),
+            Some("localhost:19000".to_string())
+        );
+        assert_eq!(connection_endpoint(&bootstrap[..1]), None);
+    }
+
+    #[test]
+    fn test_endpoint() {
+        assert!(is_endpoint("localhost:19000"));
+        assert!(is_endpoint("[::1]:19000"));
+        assert!(is_endpoint("mycluster.westus.cloudapp.azure.com:19000"));
+        assert!(!is_endpoint("localhost"));
+        assert!(!is_endpoint(
+            "x:19000; Remove-ServiceFabricApplication fabric:/App"
+        ));
+        assert!(!is_endpoint(
+            "$(Remove-ServiceFabricApplication fabric:/App):19000"
+        ));
+        assert_eq!(quote("it's"), "'it''s'");
+        // a quoted endpoint is read back without its quotes
+        let connect = format!(
+            "Connect-ServiceFabricCluster -ConnectionEndpoint {}",
+            quote("a:19000")
+        );
+        assert_eq!(connection_endpoint(&[connect]), Some("a:19000".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_pwsh_session_crash_recovery() {
+        let mut session = PwshSession::new().unwrap();
+        session
+            .run_command("Import-Module Microsoft.PowerShell.Utility")
+            .await
+            .unwrap();
+        assert_eq!(
+            session.bootstrap(),
+            &["Import-Module Microsoft.PowerShell.Utility".to_string()]
+        );
+
+        // Crash while running a command
+        let err = session
+            .run_command("[Environment]::Exit(1)")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        let output = session.run_command("Write-Output 'Hello'").await.unwrap();
+        assert_eq!(output, "Hello");
+
+        // Crash between commands
+        session.child.kill().await.unwrap();
+        let output = session.run_command("Write-Output 'Hello'").await.unwrap();
+        assert_eq!(output, "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_pwsh_session_timeout() {
+        let mut session = PwshSession::new().unwrap();
+        session.set_timeout(Some(Duration::from_secs(1)));
+
+        let err = session
+            .run_command("Start-Sleep -Seconds 30")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        // The session is usable again after the restart
+        let output = session.run_command("Write-Output 'Hello'").await.unwrap();
+        assert_eq!(output, "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_pwsh_session_cancel() {
+        let mut session = PwshSession::new().unwrap();
+        let token = CancellationToken::new();
+        session.set_cancellation_token(token.clone());
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            token.cancel();
+        });
+        let err = session
+            .run_command("Start-Sleep -Seconds 30")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+
+        // Still cancelled until a new token is set
+        let err = session
+            .run_command("Write-Output 'Hello'")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+
+        session.set_cancellation_token(CancellationToken::new());
+        let output = session.run_command("Write-Output 'Hello'").await.unwrap();
+        assert_eq!(output, "Hello");
+
+        // The restart after a cancelled command restores the session state
+        let token = CancellationToken::new();
+        session.set_cancellation_token(token.clone());
+        session.add_bootstrap_command("$global:sfctl_restored = 'yes'");
+        session
+            .run_command("Connect-ServiceFabricCluster -ConnectionEndpoint localhost:19000")
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            token.cancel();
+        });
+        let err = session
+            .run_command("Start-Sleep -Seconds 30")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+        assert_eq!(session.endpoint().as_deref(), Some("localhost:19000"));
+        assert_eq!(session.bootstrap().len(), 2);
+        session.set_cancellation_token(CancellationToken::new());
+        let output = session.run_command("$global:sfctl_restored").await.unwrap();
+        assert_eq!(output, "yes");
+    }
+
+    #[tokio::test]
+    async fn test_pwsh_session_json() {
+        let mut session = PwshSession::new().unwrap();
+
+        let output = session
+            .run_command_json(
+                "[pscustomobject]@{ Name = 'n1'; Count = 2 }",
+                DEFAULT_JSON_DEPTH,
+            )
+            .await
+            .unwrap();
+        assert_eq!(output, serde_json::json!({ "Name": "n1", "Count": 2 }));
+
+        let output = session
+            .run_command_json("1, 2, 3", DEFAULT_JSON_DEPTH)
+            .await
+            .unwrap();
+        assert_eq!(output, serde_json::json!([1, 2, 3]));
+
+        // No output
+        let output = session
+            .run_command_json("$null", DEFAULT_JSON_DEPTH)
+            .await
+            .unwrap();
+        assert!(output.is_null());
+
+        // Exceptions are returned as an object
+        let output = session
+            .run_command_json("Bad-Command-That-Does-Not-Exist", DEFAULT_JSON_DEPTH)
+            .await
+            .unwrap();
+        assert!(
+            output["Error"]
+                .as_str()
+                .unwrap()
+                .contains("is not recognized")
+        );
+
+        // So are errors that do not stop the command
+        let output = session
+            .run_command_json(
+                "Write-Output 'partial'; Get-Item /sfctl-ai/does/not/exist",
+                DEFAULT_JSON_DEPTH,
+            )
+            .await
+            .unwrap();
+        assert!(
+            output["Error"].as_str().unwrap().contains("does not exist"),
+            "{output}"
+        );
+
+        // Text mode still works on the same session
+        let output = session.run_command("Write-Output 'Hello'").await.unwrap();
+        assert_eq!(output, "Hello");
+    }
 }
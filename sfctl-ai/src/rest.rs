@@ -0,0 +1,704 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use serde_json::Value;
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::CommandBackend;
+use crate::pwsh::{CommandOutcome, DEFAULT_COMMAND_TIMEOUT, ErrorRecord};
+
+/// Default Service Fabric HTTP gateway url.
+pub const DEFAULT_GATEWAY_URL: &str = "http://localhost:19080";
+const API_VERSION: &str = "6.0";
+
+/// Backend calling the Service Fabric REST api on the HTTP gateway,
+/// for machines without the ServiceFabric powershell module.
+/// Common read cmdlets are translated to REST calls, other commands fail as unsupported.
+pub struct RestBackend {
+    client: reqwest::Client,
+    base_url: String,
+    timeout: Option<Duration>,
+    cancel: CancellationToken,
+}
+
+/// A cmdlet with its named and positional parameters.
+#[derive(Debug, PartialEq, Eq)]
+struct Cmdlet {
+    /// Lower case cmdlet name.
+    name: String,
+    /// Lower case parameter names without the dash. Switches have the value "true".
+    params: HashMap<String, String>,
+    positional: Vec<String>,
+}
+
+impl Cmdlet {
+    /// Parse a single cmdlet invocation. Returns None for scripts with multiple statements or pipes.
+    fn parse(command: &str) -> Option<Self> {
+        let mut tokens = Vec::new();
+        let mut current = String::new();
+        let mut quote: Option<char> = None;
+        for c in command.trim().chars() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => current.push(c),
+                None => match c {
+                    '\'' | '"' => quote = Some(c),
+                    ';' | '|' | '\n' | '{' | '(' | '$' => return None,
+                    c if c.is_whitespace() => {
+                        if !current.is_empty() {
+                            tokens.push(std::mem::take(&mut current));
+                        }
+                    }
+                    c => current.push(c),
+                },
+            }
+        }
+        if quote.is_some() {
+            return None;
+        }
+        if !current.is_empty() {
+            tokens.push(current);
+        }
+
+        let mut tokens = tokens.into_iter().peekable();
+        let name = tokens.next()?.to_lowercase();
+        let mut params = HashMap::new();
+        let mut positional = Vec::new();
+        while let Some(token) = tokens.next() {
+            if let Some(param) = token.strip_prefix('-') {
+                let value = match tokens.peek() {
+                    Some(next) if !next.starts_with('-') => tokens.next().unwrap(),
+                    _ => "true".to_string(),
+                };
+                params.insert(param.to_lowercase(), value);
+            } else {
+                positional.push(token);
+            }
+        }
+        Some(Cmdlet {
+            name,
+            params,
+            positional,
+        })
+    }
+
+    /// Named parameter, or the first positional argument.
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .get(name)
+            .or(self.positional.first())
+            .map(|s| s.as_str())
+    }
+}
+
+/// REST path id of an application name, e.g. fabric:/App/Sub -> App~Sub
+fn application_id(name: &str) -> String {
+    name.trim_start_matches("fabric:/").replace('/', "~")
+}
+
+/// REST value of a health state filter, e.g. `Warning,Error` -> 12
+fn health_state_filter(value: &str) -> Result<u32, String> {
+    value.split(',').try_fold(0, |filter, name| {
+        Ok(filter
+            | match name.trim().to_lowercase().as_str() {
+                "default" => 0,
+                "none" => 1,
+                "ok" => 2,
+                "warning" => 4,
+                "error" => 8,
+                "all" => 65535,
+                _ => return Err(format!("invalid health state filter '{name}'")),
+            })
+    })
+}
+
+/// GET request of the path segments, with the query parameters that are set.
+fn get(path: &[&str], query: &[(&'static str, Option<String>)]) -> Request {
+    Request::Get {
+        path: path.iter().map(|s| s.to_string()).collect(),
+        query: query
+            .iter()
+            .filter_map(|(name, value)| Some((*name, value.clone()?)))
+            .collect(),
+    }
+}
+
+/// Host of a connection endpoint with an optional port, e.g. `[::1]:19000` -> `[::1]`.
+/// None if it is not a valid host.
+fn endpoint_host(endpoint: &str) -> Option<String> {
+    if let Ok(addr) = endpoint.parse::<SocketAddr>() {
+        return Some(match addr {
+            SocketAddr::V4(addr) => addr.ip().to_string(),
+            SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+        });
+    }
+    let url = reqwest::Url::parse(&format!("http://{endpoint}")).ok()?;
+    if url.path() != "/" || url.query().is_some() || url.fragment().is_some() {
+        return None;
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return None;
+    }
+    url.host_str().map(str::to_string)
+}
+
+/// Url of a REST call, with the path segments and query values percent encoded.
+fn rest_url(
+    base_url: &str,
+    path: &[String],
+    query: &[(&str, String)],
+) -> std::io::Result<reqwest::Url> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let mut url = reqwest::Url::parse(base_url).map_err(|e| invalid(e.to_string()))?;
+    url.path_segments_mut()
+        .map_err(|_| invalid(format!("{base_url} cannot be a gateway url")))?
+        .pop_if_empty()
+        .extend(path);
+    url.query_pairs_mut()
+        .append_pair("api-version", API_VERSION)
+        .extend_pairs(query);
+    Ok(url)
+}
+
+/// What to do for a cmdlet.
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    /// GET the path segments with the query parameters, following continuation tokens if paged.
+    Get {
+        path: Vec<String>,
+        query: Vec<(&'static str, String)>,
+    },
+    /// Point the backend to a new gateway host.
+    Connect(String),
+    /// Nothing to do, e.g. Import-Module ServiceFabric.
+    NoOp,
+}
+
+fn to_request(cmdlet: &Cmdlet) -> Result<Request, String> {
+    let app = || {
+        cmdlet
+            .param("applicationname")
+            .map(application_id)
+            .ok_or_else(|| "-ApplicationName is required".to_string())
+    };
+    let node = || {
+        cmdlet
+            .param("nodename")
+            .map(str::to_string)
+            .ok_or_else(|| "-NodeName is required".to_string())
+    };
+    let named = |name: &str| cmdlet.params.get(name).cloned();
+    let health_filter = |name: &str| {
+        cmdlet
+            .params
+            .get(name)
+            .map(|v| health_state_filter(v).map(|f| f.to_string()))
+            .transpose()
+    };
+    let request = match cmdlet.name.as_str() {
+        "import-module" => Request::NoOp,
+        "connect-servicefabriccluster" => Request::Connect(
+            cmdlet
+                .param("connectionendpoint")
+                .unwrap_or("localhost:19000")
+                .to_string(),
+        ),
+        "get-servicefabricclusterhealth" => get(
+            &["$", "GetClusterHealth"],
+            &[
+                ("NodesHealthStateFilter", health_filter("nodesfilter")?),
+                (
+                    "ApplicationsHealthStateFilter",
+                    health_filter("applicationsfilter")?,
+                ),
+                ("EventsHealthStateFilter", health_filter("eventsfilter")?),
+            ],
+        ),
+        "get-servicefabricclustermanifest" => get(&["$", "GetClusterManifest"], &[]),
+        "get-servicefabricnode" => match cmdlet.param("nodename") {
+            Some(node) => get(&["Nodes", node], &[]),
+            None => get(
+                &["Nodes"],
+                &[(
+                    "NodeStatusFilter",
+                    named("statusfilter").map(|v| v.to_lowercase()),
+                )],
+            ),
+        },
+        "get-servicefabricnodehealth" => get(&["Nodes", &node()?, "$", "GetHealth"], &[]),
+        "get-servicefabricapplicationtype" => get(&["ApplicationTypes"], &[]),
+        "get-servicefabricapplication" => match cmdlet.param("applicationname") {
+            Some(app) => get(&["Applications", &application_id(app)], &[]),
+            None => get(
+                &["Applications"],
+                &[("ApplicationTypeName", named("applicationtypename"))],
+            ),
+        },
+        "get-servicefabricapplicationhealth" => {
+            get(&["Applications", &app()?, "$", "GetHealth"], &[])
+        }
+        "get-servicefabricservice" => match named("servicename") {
+            Some(service) => get(
+                &[
+                    "Applications",
+                    &app()?,
+                    "$",
+                    "GetServices",
+                    &application_id(&service),
+                ],
+                &[],
+            ),
+            None => get(&["Applications", &app()?, "$", "GetServices"], &[]),
+        },
+        _ => {
+            return Err(format!(
+                "{} is not supported by the rest backend",
+                cmdlet.name
+            ));
+        }
+    };
+    Ok(request)
+}
+
+/// Error record for an unsupported command.
+fn unsupported(command: &str, message: String) -> ErrorRecord {
+    ErrorRecord {
+        message,
+        category: "NotImplemented".to_string(),
+        fully_qualified_error_id: "RestBackendUnsupportedCommand".to_string(),
+        target_object: Some(command.to_string()),
+    }
+}
+
+impl RestBackend {
+    pub fn new(base_url: &str) -> Self {
+        RestBackend {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            timeout: Some(DEFAULT_COMMAND_TIMEOUT),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Run the command. The outer error is a transport failure,
+    /// the inner error is the command failing.
+    async fn execute(&mut self, command: &str) -> std::io::Result<Result<Value, ErrorRecord>> {
+        if self.cancel.is_cancelled() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Command cancelled",
+            ));
+        }
+        let command = crate::pwsh::PwshSession::trim_command(command);
+        let Some(cmdlet) = Cmdlet::parse(&command) else {
+            return Ok(Err(unsupported(
+                &command,
+                "Only single cmdlets without pipes or script blocks are supported by the rest backend".to_string(),
+            )));
+        };
+        let request = match to_request(&cmdlet) {
+            Ok(request) => request,
+            Err(message) => return Ok(Err(unsupported(&command, message))),
+        };
+        tracing::info!("Rest request for {}: {:?}", command, request);
+        match request {
+            Request::NoOp => Ok(Ok(Value::Null)),
+            Request::Connect(endpoint) => {
+                // The connection endpoint uses the client port, keep the gateway port.
+                let base_url = endpoint_host(&endpoint).and_then(|host| {
+                    let mut url = reqwest::Url::parse(&self.base_url).ok()?;
+                    url.set_host(Some(&host)).ok()?;
+                    Some(url.as_str().trim_end_matches('/').to_string())
+                });
+                let Some(base_url) = base_url else {
+                    return Ok(Err(ErrorRecord {
+                        message: format!(
+                            "Invalid connection endpoint '{endpoint}', expected host:port"
+                        ),
+                        category: "InvalidArgument".to_string(),
+                        fully_qualified_error_id: "RestBackendInvalidEndpoint".to_string(),
+                        target_object: Some(endpoint),
+                    }));
+                };
+                // Switch to the gateway only if it is reachable
+                let path = ["$".to_string(), "GetClusterHealth".to_string()];
+                match self.get_paged(&base_url, &path, &[]).await? {
+                    Ok(_) => {
+                        self.base_url = base_url;
+                        Ok(Ok(Value::Bool(true)))
+                    }
+                    Err(e) => Ok(Err(e)),
+                }
+            }
+            Request::Get { path, query } => self.get_paged(&self.base_url, &path, &query).await,
+        }
+    }
+
+    /// GET the path and merge the items of all pages.
+    async fn get_paged(
+        &self,
+        base_url: &str,
+        path: &[String],
+        query: &[(&str, String)],
+    ) -> std::io::Result<Result<Value, ErrorRecord>> {
+        let url = rest_url(base_url, path, query)?;
+        let mut items: Option<Vec<Value>> = None;
+        let mut continuation = String::new();
+        loop {
+            let mut page_url = url.clone();
+            if !continuation.is_empty() {
+                page_url
+                    .query_pairs_mut()
+                    .append_pair("ContinuationToken", &continuation);
+            }
+            let mut value = match self.get(page_url).await? {
+                Ok(value) => value,
+                Err(e) => return Ok(Err(e)),
+            };
+            // Paged results have Items and ContinuationToken
+            let Some(page) = value.get_mut("Items").map(Value::take) else {
+                return Ok(Ok(value));
+            };
+            items
+                .get_or_insert_with(Vec::new)
+                .extend(page.as_array().cloned().unwrap_or_default());
+            continuation = value["ContinuationToken"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            if continuation.is_empty() {
+                return Ok(Ok(Value::Array(items.unwrap_or_default())));
+            }
+        }
+    }
+
+    async fn get(&self, url: reqwest::Url) -> std::io::Result<Result<Value, ErrorRecord>> {
+        let path = url.path().to_string();
+        let mut request = self.client.get(url);
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        let response = tokio::select! {
+            res = request.send() => res.map_err(to_io_error)?,
+            _ = self.cancel.cancelled() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "Command cancelled",
+                ));
+            }
+        };
+        let status = response.status();
+        let body = response.text().await.map_err(to_io_error)?;
+        let value = if body.trim().is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(&body).unwrap_or(Value::String(body))
+        };
+        if status.is_success() {
+            return Ok(Ok(value));
+        }
+        // Errors are returned as { "Error": { "Code": "...", "Message": "..." } }
+        let code = value["Error"]["Code"].as_str().unwrap_or(status.as_str());
+        let message = value["Error"]["Message"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("Request failed with status {status}"));
+        Ok(Err(ErrorRecord {
+            message,
+            category: if status == reqwest::StatusCode::NOT_FOUND {
+                "ObjectNotFound".to_string()
+            } else {
+                "InvalidOperation".to_string()
+            },
+            fully_qualified_error_id: code.to_string(),
+            target_object: Some(path),
+        }))
+    }
+}
+
+fn to_io_error(e: reqwest::Error) -> std::io::Error {
+    if e.is_timeout() {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, e)
+    } else {
+        std::io::Error::other(e)
+    }
+}
+
+impl CommandBackend for RestBackend {
+    fn run_command<'a>(&'a mut self, command: &'a str) -> BoxFuture<'a, std::io::Result<String>> {
+        Box::pin(async move {
+            Ok(match self.execute(command).await? {
+                Ok(Value::Null) => String::new(),
+                Ok(value) => serde_json::to_string_pretty(&value).unwrap_or_default(),
+                Err(e) => e.message,
+            })
+        })
+    }
+
+    fn run_command_json<'a>(
+        &'a mut self,
+        command: &'a str,
+        _depth: u32,
+    ) -> BoxFuture<'a, std::io::Result<Value>> {
+        Box::pin(async move {
+            Ok(match self.execute(command).await? {
+                Ok(value) => value,
+                Err(e) => serde_json::json!({ "Error": e.message }),
+            })
+        })
+    }
+
+    fn run_command_outcome<'a>(
+        &'a mut self,
+        command: &'a str,
+    ) -> BoxFuture<'a, std::io::Result<CommandOutcome>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let res = self.execute(command).await?;
+            let mut outcome = CommandOutcome {
+                stdout: String::new(),
+                stderr: String::new(),
+                errors: Vec::new(),
+                warnings: Vec::new(),
+                status: true,
+                exit_code: None,
+                duration: start.elapsed(),
+            };
+            match res {
+                Ok(Value::Null) => {}
+                Ok(value) => {
+                    outcome.stdout = serde_json::to_string_pretty(&value).unwrap_or_default()
+                }
+                Err(e) => {
+                    outcome.status = false;
+                    outcome.errors.push(e);
+                }
+            }
+            Ok(outcome)
+        })
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
+
+    fn endpoint(&self) -> Option<String> {
+        Some(self.base_url.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Minimal http server returning canned responses by path.
+    /// Returns the base url.
+    async fn mock_gateway(routes: Vec<(&'static str, u16, Value)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let target = request.split_whitespace().nth(1).unwrap_or_default();
+                let (status, body) = routes
+                    .iter()
+                    .find(|(path, _, _)| *path == target)
+                    .map(|(_, status, body)| (*status, body.to_string()))
+                    .unwrap_or((404, String::new()));
+                let response = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    /// Url of the REST call for the command.
+    fn url(command: &str) -> String {
+        match to_request(&Cmdlet::parse(command).unwrap()).unwrap() {
+            Request::Get { path, query } => rest_url("http://gw:19080", &path, &query)
+                .unwrap()
+                .to_string(),
+            request => panic!("not a GET: {request:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_cmdlet() {
+        let cmdlet =
+            Cmdlet::parse("Get-ServiceFabricService -ApplicationName 'fabric:/My App' -Force")
+                .unwrap();
+        assert_eq!(cmdlet.name, "get-servicefabricservice");
+        assert_eq!(cmdlet.param("applicationname"), Some("fabric:/My App"));
+        assert_eq!(cmdlet.param("force"), Some("true"));
+        assert_eq!(
+            url("Get-ServiceFabricService -ApplicationName 'fabric:/My App' -Force"),
+            "http://gw:19080/Applications/My%20App/$/GetServices?api-version=6.0"
+        );
+        assert_eq!(
+            url("Get-ServiceFabricNode _Node_0"),
+            "http://gw:19080/Nodes/_Node_0?api-version=6.0"
+        );
+
+        // names cannot change the path or add query parameters
+        assert_eq!(
+            url("Get-ServiceFabricNode '../Applications?x=1#'"),
+            "http://gw:19080/Nodes/..%2FApplications%3Fx=1%23?api-version=6.0"
+        );
+        assert_eq!(
+            url("Get-ServiceFabricApplication -ApplicationTypeName 'A&api-version=1'"),
+            "http://gw:19080/Applications?api-version=6.0&ApplicationTypeName=A%26api-version%3D1"
+        );
+
+        assert!(Cmdlet::parse("Get-ServiceFabricNode | Select-Object NodeName").is_none());
+        assert!(Cmdlet::parse("Get-ServiceFabricNode; Remove-Item x").is_none());
+        assert!(
+            to_request(&Cmdlet::parse("Remove-ServiceFabricApplication fabric:/App").unwrap())
+                .is_err()
+        );
+        assert_eq!(application_id("fabric:/App/Sub"), "App~Sub");
+
+        assert_eq!(
+            endpoint_host("mycluster:19000").as_deref(),
+            Some("mycluster")
+        );
+        assert_eq!(endpoint_host("10.0.0.4:19000").as_deref(), Some("10.0.0.4"));
+        assert_eq!(endpoint_host("[::1]:19000").as_deref(), Some("[::1]"));
+        assert_eq!(endpoint_host("mycluster").as_deref(), Some("mycluster"));
+        assert_eq!(endpoint_host("mycluster:19000/x"), None);
+        assert_eq!(endpoint_host("user@mycluster:19000"), None);
+
+        // filters become query parameters
+        assert_eq!(
+            url("Get-ServiceFabricClusterHealth -NodesFilter 'Warning,Error' -EventsFilter None"),
+            "http://gw:19080/$/GetClusterHealth?api-version=6.0&NodesHealthStateFilter=12&EventsHealthStateFilter=1"
+        );
+        assert_eq!(
+            url("Get-ServiceFabricNode -StatusFilter Down"),
+            "http://gw:19080/Nodes?api-version=6.0&NodeStatusFilter=down"
+        );
+        assert_eq!(
+            url(
+                "Get-ServiceFabricService -ApplicationName fabric:/App -ServiceName fabric:/App/Web"
+            ),
+            "http://gw:19080/Applications/App/$/GetServices/App~Web?api-version=6.0"
+        );
+        assert!(
+            to_request(&Cmdlet::parse("Get-ServiceFabricClusterHealth -NodesFilter Bad").unwrap())
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rest_backend() {
+        let base_url = mock_gateway(vec![
+            (
+                "/$/GetClusterHealth?api-version=6.0",
+                200,
+                serde_json::json!({ "AggregatedHealthState": "Ok" }),
+            ),
+            (
+                "/Nodes?api-version=6.0",
+                200,
+                serde_json::json!({ "ContinuationToken": "1", "Items": [{ "Name": "_Node_0" }] }),
+            ),
+            (
+                "/Nodes?api-version=6.0&ContinuationToken=1",
+                200,
+                serde_json::json!({ "ContinuationToken": "2+2&x", "Items": [{ "Name": "_Node_1" }] }),
+            ),
+            (
+                "/Nodes?api-version=6.0&ContinuationToken=2%2B2%26x",
+                200,
+                serde_json::json!({ "ContinuationToken": "", "Items": [{ "Name": "_Node_2" }] }),
+            ),
+            (
+                "/Applications/Missing?api-version=6.0",
+                404,
+                serde_json::json!({ "Error": { "Code": "FABRIC_E_APPLICATION_NOT_FOUND", "Message": "Application not found" } }),
+            ),
+        ])
+        .await;
+        let mut backend = RestBackend::new(&base_url);
+
+        let output = backend
+            .run_command_json("Get-ServiceFabricClusterHealth", 4)
+            .await
+            .unwrap();
+        assert_eq!(output["AggregatedHealthState"], "Ok");
+
+        // Pages are merged
+        let output = backend
+            .run_command_json("Get-ServiceFabricNode", 4)
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            serde_json::json!([{ "Name": "_Node_0" }, { "Name": "_Node_1" }, { "Name": "_Node_2" }])
+        );
+
+        let outcome = backend
+            .run_command_outcome("Get-ServiceFabricApplication -ApplicationName fabric:/Missing")
+            .await
+            .unwrap();
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.errors[0].category, "ObjectNotFound");
+        assert_eq!(
+            outcome.errors[0].fully_qualified_error_id,
+            "FABRIC_E_APPLICATION_NOT_FOUND"
+        );
+
+        let outcome = backend
+            .run_command_outcome("Remove-ServiceFabricApplication fabric:/App")
+            .await
+            .unwrap();
+        assert!(!outcome.is_success());
+        assert_eq!(
+            outcome.errors[0].fully_qualified_error_id,
+            "RestBackendUnsupportedCommand"
+        );
+
+        let outcome = backend
+            .run_command_outcome("Import-Module ServiceFabric")
+            .await
+            .unwrap();
+        assert!(outcome.is_success());
+
+        // Connect keeps the gateway port
+        let outcome = backend
+            .run_command_outcome("Connect-ServiceFabricCluster -ConnectionEndpoint 127.0.0.1:19000")
+            .await
+            .unwrap();
+        assert!(outcome.is_success(), "{outcome:?}");
+        assert_eq!(backend.base_url(), base_url);
+
+        // A gateway that cannot be reached or an invalid endpoint keeps the current one
+        for endpoint in ["[::1]:19000", "127.0.0.1:19000/x"] {
+            let connected = backend
+                .run_command_outcome(&format!(
+                    "Connect-ServiceFabricCluster -ConnectionEndpoint '{endpoint}'"
+                ))
+                .await
+                .is_ok_and(|outcome| outcome.is_success());
+            assert!(!connected, "{endpoint}");
+            assert_eq!(backend.base_url(), base_url);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use genai::webc;
+use reqwest::{StatusCode, header::HeaderMap};
+use reqwest_eventsource::Error as EventSourceError;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+pub const DEFAULT_MAX_RETRY_WAIT: u64 = 60;
+
+/// Wait before the first retry, doubled for each following one.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Retries of model requests that failed for a reason that may go away.
+#[derive(Debug, Clone, clap::Args)]
+pub struct RetryConfig {
+    /// Retries of a model request failing with a rate limit, server or network error
+    #[arg(long, env = "SFCTL_AI_MAX_RETRIES", default_value_t = DEFAULT_MAX_RETRIES, global = true)]
+    pub max_retries: u32,
+
+    /// Longest wait in seconds before retrying a model request, also caps the provider's Retry-After
+    #[arg(long, env = "SFCTL_AI_MAX_RETRY_WAIT", default_value_t = DEFAULT_MAX_RETRY_WAIT, global = true)]
+    pub max_retry_wait: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: DEFAULT_MAX_RETRIES,
+            max_retry_wait: DEFAULT_MAX_RETRY_WAIT,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Wait before the 1-based retry `attempt`. The provider's Retry-After if given,
+    /// otherwise exponential backoff with jitter, both capped at the ceiling.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let ceiling = Duration::from_secs(self.max_retry_wait);
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(ceiling);
+        }
+        let backoff = INITIAL_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(ceiling);
+        // spread the retries of clients that failed together over the second half
+        backoff / 2 + backoff.mul_f64(jitter() / 2.0)
+    }
+}
+
+/// Random fraction in [0, 1).
+fn jitter() -> f64 {
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A failed model request worth retrying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transient {
+    /// What failed, e.g. `429 Too Many Requests`.
+    pub reason: String,
+    /// Wait asked by the provider.
+    pub retry_after: Option<Duration>,
+}
+
+/// Whether the request may succeed when retried: rate limits, server errors and network errors.
+pub fn transient(error: &genai::Error) -> Option<Transient> {
+    match error {
+        genai::Error::ReqwestEventSource(e) => match e.as_ref() {
+            EventSourceError::InvalidStatusCode(status, response) => {
+                transient_status(*status, response.headers())
+            }
+            EventSourceError::Transport(e) => Some(network_error(e)),
+            EventSourceError::StreamEnded => Some(Transient {
+                reason: "the response stream ended early".to_string(),
+                retry_after: None,
+            }),
+            _ => None,
+        },
+        genai::Error::WebModelCall { webc_error, .. }
+        | genai::Error::WebAdapterCall { webc_error, .. } => match webc_error {
+            webc::Error::ResponseFailedStatus {
+                status, headers, ..
+            } => transient_status(*status, headers),
+            webc::Error::Reqwest(e) => Some(network_error(e)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn transient_status(status: StatusCode, headers: &HeaderMap) -> Option<Transient> {
+    if status != StatusCode::TOO_MANY_REQUESTS
+        && status != StatusCode::REQUEST_TIMEOUT
+        && !status.is_server_error()
+    {
+        return None;
+    }
+    let retry_after = headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_retry_after(v, chrono::Utc::now()));
+    Some(Transient {
+        reason: status.to_string(),
+        retry_after,
+    })
+}
+
+fn network_error(e: &reqwest::Error) -> Transient {
+    let reason = if e.is_timeout() {
+        "timeout"
+    } else if e.is_connect() {
+        "connection failed"
+    } else {
+        "network error"
+    };
+    Transient {
+        reason: reason.to_string(),
+        retry_after: None,
+    }
+}
+
+/// Parse a Retry-After value, either seconds or an http date.
+pub fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    // a date in the past means retry now
+    Some((date.to_utc() - now).to_std().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        let config = RetryConfig {
+            max_retries: 5,
+            max_retry_wait: 10,
+        };
+        for attempt in 1..=5 {
+            let full = Duration::from_secs(1 << (attempt - 1)).min(Duration::from_secs(10));
+            let delay = config.delay(attempt, None);
+            assert!(delay >= full / 2 && delay <= full, "{attempt}: {delay:?}");
+        }
+        assert!(config.delay(40, None) <= Duration::from_secs(10));
+        assert_eq!(
+            config.delay(1, Some(Duration::from_secs(3))),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            config.delay(1, Some(Duration::from_secs(300))),
+            Duration::from_secs(10)
+        );
+
+        let now = chrono::DateTime::parse_from_rfc3339("2025-01-31T14:25:00Z")
+            .unwrap()
+            .to_utc();
+        assert_eq!(parse_retry_after(" 7 ", now), Some(Duration::from_secs(7)));
+        assert_eq!(
+            parse_retry_after("Fri, 31 Jan 2025 14:25:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Fri, 31 Jan 2025 14:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    hash::{BuildHasher, Hasher},
+    path::{Path, PathBuf},
+};
+
+use genai::chat::{ChatMessage, MessageContent, ToolResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::ai::CommandRecord;
+
+pub const DEFAULT_SESSION_DIR: &str = "sessions";
+
+/// Saved conversation selection.
+#[derive(Debug, Clone, clap::Args)]
+pub struct SessionConfig {
+    /// Directory of the saved conversations
+    #[arg(long, env = "SFCTL_AI_SESSION_DIR", default_value = DEFAULT_SESSION_DIR, global = true)]
+    pub session_dir: PathBuf,
+
+    /// Resume the saved conversation with this id, see `session list`
+    #[arg(long, global = true)]
+    pub resume: Option<String>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            session_dir: PathBuf::from(DEFAULT_SESSION_DIR),
+            resume: None,
+        }
+    }
+}
+
+/// A conversation with the model, saved after each step so it survives Ctrl-C.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    /// RFC 3339 creation time.
+    pub created: String,
+    /// RFC 3339 time of the last save.
+    pub updated: String,
+    /// User who last worked on the session.
+    pub user: String,
+    /// Cluster endpoint at the last save, None if not connected.
+    pub cluster: Option<String>,
+    /// First question of the conversation, shown in the session list.
+    pub title: String,
+    /// Chat history without the system prompt and tools, which are rebuilt on resume.
+    pub messages: Vec<ChatMessage>,
+    /// Commands handled in the session with their approval and outcome.
+    pub commands: Vec<CommandRecord>,
+    /// Full command outputs that were truncated in the messages.
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+impl Session {
+    /// New empty session with a fresh id, e.g. `20250131-142501-3fa2`.
+    pub fn new() -> Self {
+        let now = chrono::Local::now();
+        let suffix = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish() as u16;
+        Session {
+            id: format!("{}-{suffix:04x}", now.format("%Y%m%d-%H%M%S")),
+            created: now.to_rfc3339(),
+            updated: now.to_rfc3339(),
+            user: crate::audit::current_user(),
+            cluster: None,
+            title: String::new(),
+            messages: Vec::new(),
+            commands: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Messages to continue the conversation with. Tool calls interrupted before their
+    /// results were sent get a response saying so, providers reject unanswered calls.
+    pub fn resumable_messages(&self) -> Vec<ChatMessage> {
+        let mut messages = self.messages.clone();
+        let answered: Vec<String> = messages
+            .iter()
+            .filter_map(|m| match &m.content {
+                MessageContent::ToolResponses(responses) => Some(responses),
+                _ => None,
+            })
+            .flatten()
+            .map(|r| r.call_id.clone())
+            .collect();
+        let unanswered: Vec<String> = messages
+            .iter()
+            .filter_map(|m| m.content.tool_calls())
+            .flatten()
+            .map(|c| c.call_id.clone())
+            .filter(|id| !answered.contains(id))
+            .collect();
+        for call_id in unanswered {
+            messages.push(ChatMessage::from(ToolResponse::new(
+                call_id,
+                "The command was not run, the session was interrupted.",
+            )));
+        }
+        messages
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Short description of a saved session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub id: String,
+    pub updated: String,
+    pub user: String,
+    pub cluster: Option<String>,
+    pub title: String,
+    pub messages: usize,
+    pub commands: usize,
+}
+
+/// Sessions saved as one json file per id in a directory.
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: &Path) -> Self {
+        SessionStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn path(&self, id: &str) -> std::io::Result<PathBuf> {
+        // the id names a file, keep it inside the directory
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid session id '{id}'"),
+            ));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    /// Write the session, replacing the previous save in one step.
+    pub fn save(&self, session: &Session) -> std::io::Result<()> {
+        let path = self.path(&session.id)?;
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(session)?)?;
+        std::fs::rename(&tmp, &path)
+    }
+
+    pub fn load(&self, id: &str) -> std::io::Result<Session> {
+        let path = self.path(id)?;
+        let content = std::fs::read(&path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("session '{id}' not found in {}", self.dir.display()),
+                )
+            } else {
+                e
+            }
+        })?;
+        serde_json::from_slice(&content).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid session file {}: {e}", path.display()),
+            )
+        })
+    }
+
+    /// Saved sessions, most recently updated first. Unreadable files are skipped.
+    pub fn list(&self) -> std::io::Result<Vec<SessionSummary>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut sessions = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let session = match std::fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|c| serde_json::from_slice::<Session>(&c).map_err(|e| e.to_string()))
+            {
+                Ok(session) => session,
+                Err(e) => {
+                    tracing::info!("Skipping session file {}: {e}", path.display());
+                    continue;
+                }
+            };
+            sessions.push(SessionSummary {
+                messages: session.messages.len(),
+                commands: session.commands.len(),
+                id: session.id,
+                updated: session.updated,
+                user: session.user,
+                cluster: session.cluster,
+                title: session.title,
+            });
+        }
+        sessions.sort_by(|a, b| {
+            let time = |s: &SessionSummary| chrono::DateTime::parse_from_rfc3339(&s.updated).ok();
+            time(b).cmp(&time(a))
+        });
+        Ok(sessions)
+    }
+}
+
+/// Saved session subcommands.
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum SessionCommand {
+    /// List the saved sessions, most recent first
+    List,
+    /// Print the conversation and commands of a session
+    Show {
+        /// Session id
+        id: String,
+    },
+}
+
+impl SessionCommand {
+    pub fn run(&self, store: &SessionStore, json: bool) -> std::io::Result<()> {
+        match self {
+            SessionCommand::List => {
+                let sessions = store.list()?;
+                if json {
+                    println!("{:#}", serde_json::to_value(&sessions)?);
+                    return Ok(());
+                }
+                for s in sessions {
+                    println!(
+                        "{}  {}  {}  {}  {}",
+                        s.id,
+                        s.updated,
+                        s.user,
+                        s.cluster.as_deref().unwrap_or("-"),
+                        s.title
+                    );
+                }
+            }
+            SessionCommand::Show { id } => {
+                let session = store.load(id)?;
+                if json {
+                    println!("{:#}", serde_json::to_value(&session)?);
+                    return Ok(());
+                }
+                println!(
+                    "Session {} by {}, updated {}",
+                    session.id, session.user, session.updated
+                );
+                if let Some(cluster) = &session.cluster {
+                    println!("Cluster: {cluster}");
+                }
+                for message in &session.messages {
+                    print_message(message);
+                }
+                println!("Commands:");
+                for c in &session.commands {
+                    println!(
+                        "  {} ({}, {})",
+                        c.command,
+                        serde_json::to_value(c.approval)?
+                            .as_str()
+                            .unwrap_or_default(),
+                        serde_json::to_value(c.outcome)?
+                            .as_str()
+                            .unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn print_message(message: &ChatMessage) {
+    match &message.content {
+        MessageContent::ToolCalls(calls) => {
+            for call in calls {
+                println!(
+                    "[{}] tool call {}: {}",
+                    message.role, call.fn_name, call.fn_arguments
+                );
+            }
+        }
+        MessageContent::ToolResponses(responses) => {
+            for response in responses {
+                println!("[tool] {}", response.content);
+            }
+        }
+        content => {
+            if let Some(text) = content.text() {
+                println!("[{}] {}", message.role, text);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{Approval, Outcome};
+    use genai::chat::ToolCall;
+
+    #[test]
+    fn test_session_store() {
+        let dir = std::env::temp_dir().join(format!("sfctl-ai-sessions-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = SessionStore::new(&dir);
+        assert!(store.list().unwrap().is_empty());
+
+        let mut session = Session::new();
+        session.title = "why is node _Node_2 unhealthy?".to_string();
+        session.cluster = Some("localhost:19000".to_string());
+        session.messages = vec![
+            ChatMessage::user("why is node _Node_2 unhealthy?"),
+            ChatMessage::from(vec![ToolCall {
+                call_id: "call_1".to_string(),
+                fn_name: "run_pwsh_command".to_string(),
+                fn_arguments: serde_json::json!({ "command": "Get-ServiceFabricNode" }),
+            }]),
+        ];
+        session.commands = vec![CommandRecord {
+            command: "Get-ServiceFabricNode".to_string(),
+            approval: Approval::Auto,
+            outcome: Outcome::Succeeded,
+        }];
+        store.save(&session).unwrap();
+        let loaded = store.load(&session.id).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&session).unwrap()
+        );
+
+        let list = store.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, session.id);
+        assert_eq!(list[0].messages, 2);
+        assert_eq!(list[0].commands, 1);
+
+        // The interrupted tool call gets a response so the chat can continue
+        let messages = loaded.resumable_messages();
+        assert_eq!(messages.len(), 3);
+        let MessageContent::ToolResponses(responses) = &messages[2].content else {
+            panic!("expected a tool response: {:?}", messages[2]);
+        };
+        assert_eq!(responses[0].call_id, "call_1");
+
+        assert_eq!(
+            store.load("missing").unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            store.load("../etc/passwd").unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}